@@ -0,0 +1,126 @@
+use crate::context::Context;
+use crate::errors::ApiError;
+use crate::fwd_err;
+use crate::influx_gateway::WorkerStatus;
+use crate::server::RequestHandler;
+use crate::status_store::StatusStore;
+use async_trait::async_trait;
+use mac_address::MacAddress;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct ResetBackoffRes {
+    reset: usize,
+}
+
+#[derive(Deserialize)]
+pub struct ResetBackoffReq {
+    // reset all macs if None
+    #[serde(default)]
+    mac: Option<MacAddress>,
+}
+
+pub struct ResetBackoffRequestHandler {}
+
+#[async_trait]
+impl RequestHandler<ResetBackoffReq, ResetBackoffRes> for ResetBackoffRequestHandler {
+    async fn handle(
+        &self,
+        req: ResetBackoffReq,
+        context: Context,
+    ) -> Result<ResetBackoffRes, ApiError> {
+        let reset = context.reset_backoff(req.mac.as_ref());
+        info!("reset wake backoff of {} macs", reset);
+        Ok(ResetBackoffRes { reset })
+    }
+}
+
+#[derive(Serialize)]
+pub struct AdminStatusRes {
+    mac: MacAddress,
+    status: i32,
+}
+
+#[derive(Deserialize)]
+pub struct AdminStatusReq {
+    mac: MacAddress,
+    status: WorkerStatus,
+    wake: bool,
+}
+
+pub struct AdminStatusRequestHandler {}
+
+async fn force_status(
+    req: AdminStatusReq,
+    store: &(impl StatusStore + ?Sized),
+) -> Result<AdminStatusRes, ApiError> {
+    // log status for the given mac (not resolved from the requester)
+    info!("[{}] forcing status {:?}", req.mac, req.status);
+    store
+        .log_workerstatus(&req.mac, req.status.clone(), req.wake)
+        .await
+        .map_err(|e| fwd_err!("Failed to log forced status! {}", e))?;
+    Ok(AdminStatusRes {
+        mac: req.mac,
+        status: req.status as i32,
+    })
+}
+
+#[async_trait]
+impl RequestHandler<AdminStatusReq, AdminStatusRes> for AdminStatusRequestHandler {
+    async fn handle(
+        &self,
+        req: AdminStatusReq,
+        context: Context,
+    ) -> Result<AdminStatusRes, ApiError> {
+        force_status(req, context.status_store.as_ref()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::influx_gateway::test::InfluxClientMock;
+    use std::collections::{HashMap, HashSet};
+
+    #[tokio::test]
+    async fn test_force_status() {
+        let req: AdminStatusReq = serde_json::from_str(
+            r#"{"mac": "11:22:33:44:55:66", "status": "Sleep", "wake": false}"#,
+        )
+        .unwrap();
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                "workerstatus,mac=11:22:33:44:55:66 status=0i,wake=false".to_string(),
+                "".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let res = force_status(req, &client).await.unwrap();
+        assert_eq!(res.status, WorkerStatus::Sleep as i32);
+        assert_eq!(
+            res.mac.to_string(),
+            "11:22:33:44:55:66",
+            "should keep given mac"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reset_backoff() {
+        let context = Context::load().unwrap();
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        context.record_wake_attempts(&HashSet::from([mac]));
+        assert!(!context.wake_eligible(&mac), "should back off");
+
+        let req: ResetBackoffReq = serde_json::from_str(r#"{"mac": "11:22:33:44:55:66"}"#).unwrap();
+        let res = ResetBackoffRequestHandler {}
+            .handle(req, context.clone())
+            .await
+            .unwrap();
+        assert_eq!(res.reset, 1);
+        assert!(
+            context.wake_eligible(&mac),
+            "should be eligible for waking after reset"
+        );
+    }
+}
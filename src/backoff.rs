@@ -0,0 +1,72 @@
+use chrono::{DateTime, Duration, Utc};
+use mac_address::MacAddress;
+use std::collections::HashMap;
+
+// cap of the exponential backoff (base * 2^MAX_BACKOFF_EXPONENT)
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+#[derive(Debug, Default)]
+pub struct WakeBackoff {
+    // consecutive wake attempts and time of the last attempt
+    attempts: HashMap<MacAddress, (u32, DateTime<Utc>)>,
+}
+
+impl WakeBackoff {
+    pub fn record_attempt(&mut self, mac: &MacAddress, now: DateTime<Utc>) {
+        let entry = self.attempts.entry(*mac).or_insert((0, now));
+        *entry = (entry.0 + 1, now);
+    }
+    pub fn eligible(&self, mac: &MacAddress, base: Duration, now: DateTime<Utc>) -> bool {
+        // wait base * 2^(attempts - 1) after the last attempt
+        match self.attempts.get(mac) {
+            Some((attempts, last)) => {
+                let exp = (attempts - 1).min(MAX_BACKOFF_EXPONENT);
+                now >= *last + base * 2i32.pow(exp)
+            }
+            None => true,
+        }
+    }
+    pub fn attempts(&self, mac: &MacAddress) -> u32 {
+        self.attempts.get(mac).map(|(a, _)| *a).unwrap_or(0)
+    }
+    pub fn all_attempts(&self) -> HashMap<MacAddress, u32> {
+        self.attempts.iter().map(|(m, (a, _))| (*m, *a)).collect()
+    }
+    pub fn reset(&mut self, mac: Option<&MacAddress>) -> usize {
+        // reset one or all macs
+        match mac {
+            Some(m) => self.attempts.remove(m).map(|_| 1).unwrap_or(0),
+            None => {
+                let n = self.attempts.len();
+                self.attempts.clear();
+                n
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let base = Duration::minutes(5);
+        let now = Utc::now();
+        let mut backoff = WakeBackoff::default();
+        assert!(backoff.eligible(&mac, base, now));
+        backoff.record_attempt(&mac, now);
+        assert!(!backoff.eligible(&mac, base, now));
+        assert!(backoff.eligible(&mac, base, now + base));
+        backoff.record_attempt(&mac, now);
+        assert!(
+            !backoff.eligible(&mac, base, now + base),
+            "should double the delay"
+        );
+        assert!(backoff.eligible(&mac, base, now + base * 2));
+        assert_eq!(backoff.attempts(&mac), 2);
+        assert_eq!(backoff.reset(Some(&mac)), 1);
+        assert!(backoff.eligible(&mac, base, now), "should reset attempts");
+    }
+}
@@ -0,0 +1,39 @@
+// pauses influxdb queries after consecutive failures (until healthy again)
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    consecutive_failures: u32,
+}
+
+impl CircuitBreaker {
+    pub fn record(&mut self, ok: bool) {
+        if ok {
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures += 1;
+        }
+    }
+    pub fn is_open(&self, threshold: u32) -> bool {
+        // never open if threshold is 0
+        threshold > 0 && self.consecutive_failures >= threshold
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut breaker = CircuitBreaker::default();
+        breaker.record(false);
+        breaker.record(false);
+        assert!(!breaker.is_open(3));
+        breaker.record(false);
+        assert!(breaker.is_open(3), "should open after 3 failures");
+        assert!(!breaker.is_open(0), "should never open if disabled");
+        breaker.record(true);
+        assert!(!breaker.is_open(3), "should close after success");
+        breaker.record(false);
+        assert!(!breaker.is_open(3), "should count consecutive failures");
+    }
+}
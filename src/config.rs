@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::env::{self, VarError};
+
+// config values of env vars (precedence) and an optional CONFIG_FILE
+#[derive(Debug, Default)]
+pub struct ConfigSource {
+    env: HashMap<String, String>,
+    file: HashMap<String, String>,
+}
+
+impl ConfigSource {
+    pub fn load() -> Result<Self, String> {
+        Self::load_with(env::vars().collect())
+    }
+    pub fn load_with(env: HashMap<String, String>) -> Result<Self, String> {
+        let file = match env.get("CONFIG_FILE") {
+            Some(path) => parse_config_file(
+                &std::fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read config file '{}'! {}", path, e))?,
+            )?,
+            None => HashMap::new(),
+        };
+        Ok(ConfigSource { env, file })
+    }
+    // like env::var (with values of the config file)
+    pub fn var(&self, key: &str) -> Result<String, VarError> {
+        self.env
+            .get(key)
+            .or_else(|| self.file.get(key))
+            .cloned()
+            .ok_or(VarError::NotPresent)
+    }
+    // set (env var or config file) unless 'false' or '0'
+    pub fn flag(&self, key: &str) -> bool {
+        self.flag_or(key, false)
+    }
+    // like flag (with a default for unset flags)
+    pub fn flag_or(&self, key: &str, default: bool) -> bool {
+        self.var(key).map(|v| parse_flag(&v)).unwrap_or(default)
+    }
+}
+
+fn parse_flag(value: &str) -> bool {
+    // any other value enables the flag (e.g. 'STARTUP_WARMUP=1' or 'STARTUP_WARMUP=')
+    !matches!(value.trim().to_lowercase().as_str(), "false" | "0")
+}
+
+fn toml_str(value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s),
+        // arrays are comma-separated env values
+        toml::Value::Array(a) => Ok(a
+            .into_iter()
+            .map(toml_str)
+            .collect::<Result<Vec<String>, String>>()?
+            .join(",")),
+        toml::Value::Table(_) => Err("Tables are not supported!".into()),
+        v => Ok(v.to_string()),
+    }
+}
+
+fn parse_config_file(s: &str) -> Result<HashMap<String, String>, String> {
+    // toml with the env var names as keys (case-insensitive)
+    let table: HashMap<String, toml::Value> =
+        toml::from_str(s).map_err(|e| format!("Invalid config file! {}", e))?;
+    table
+        .into_iter()
+        .map(|(k, v)| {
+            toml_str(v)
+                .map(|v| (k.to_uppercase(), v))
+                .map_err(|e| format!("Invalid config file value of '{}'! {}", k, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_file() {
+        let file = parse_config_file(
+            r#"
+            wake_interval_seconds = 600
+            VOLTAGE_SCALE = 0.001
+            TRUST_STATUS_FOR_WAKE = true
+            PARTIAL_HISTORY = false
+            SCHEDULED_WAKES = ["08:00", "12:30"]
+            HOST = "0.0.0.0:3000"
+            "#,
+        )
+        .unwrap();
+        let config = ConfigSource {
+            env: HashMap::from([("HOST".into(), "127.0.0.1:8080".into())]),
+            file,
+        };
+        assert_eq!(config.var("WAKE_INTERVAL_SECONDS"), Ok("600".into()));
+        assert_eq!(config.var("VOLTAGE_SCALE"), Ok("0.001".into()));
+        assert_eq!(config.var("SCHEDULED_WAKES"), Ok("08:00,12:30".into()));
+        assert_eq!(
+            config.var("HOST"),
+            Ok("127.0.0.1:8080".into()),
+            "env vars should take precedence"
+        );
+        assert_eq!(config.var("MIN_SAMPLES"), Err(VarError::NotPresent));
+        assert!(config.flag("TRUST_STATUS_FOR_WAKE"));
+        assert!(!config.flag("PARTIAL_HISTORY"));
+        assert_matches!(parse_config_file("[influx]\nbucket = \"pv\""), Err(_));
+
+        let config = ConfigSource {
+            env: HashMap::from([
+                ("TRUST_STATUS_FOR_WAKE".into(), "false".into()),
+                ("PARTIAL_HISTORY".into(), "1".into()),
+                ("STARTUP_WARMUP".into(), "0".into()),
+                ("EXCESS_DETAILS".into(), "".into()),
+            ]),
+            file: HashMap::from([("TRUST_STATUS_FOR_WAKE".into(), "true".into())]),
+        };
+        assert!(
+            !config.flag("TRUST_STATUS_FOR_WAKE"),
+            "env vars should override a 'true' of the file"
+        );
+        assert!(config.flag("PARTIAL_HISTORY"));
+        assert!(!config.flag("STARTUP_WARMUP"));
+        assert!(config.flag("EXCESS_DETAILS"), "should enable set flags");
+        assert!(!config.flag_or("STARTUP_WARMUP", true));
+        assert!(
+            config.flag_or("EXCESS_ENABLED", true),
+            "should default unset flags"
+        );
+        assert_matches!(parse_config_file("HOST = "), Err(_));
+    }
+}
@@ -1,9 +1,33 @@
+use crate::backoff::WakeBackoff;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::ConfigSource;
 use crate::errors::ApiError;
-use crate::neighbor::addr_to_mac;
+use crate::excess_smoother::ExcessSmoother;
+use crate::excess_source::parse_excess_source;
+use crate::influx_gateway::{
+    Aggregation, ExcessSignal, ExcessStatus, ExcessThresholds, QueryLanguage, TemperatureGate,
+    WorkerStatus, WEEKDAY_THRESHOLDS,
+};
+use crate::log_throttle::LogThrottle;
+use crate::mac_cache::{MacCache, MacCacheMode};
+use crate::neighbor::{
+    addr_to_mac, parse_mac_ips, parse_subnet_prefix, parse_wake_ip_override, ArpSelection,
+    LinuxNetworkGateway, MacIpMapping, NetworkGateway, PingLimits, WakeOptions,
+};
+use crate::report_handler::parse_worker_tokens;
+use crate::server::{parse_listen_addrs, ListenAddr};
 use crate::server_err;
+use crate::solar::{parse_location, Location};
+use crate::state_file::{load_last_known_ips, load_state, save_state, PersistedState};
+use crate::status_store::StatusStore;
+use crate::wake_heartbeat::{HeartbeatMode, HeartbeatSummary};
+use crate::wake_spread::{CandidateRotation, WakeSpread};
+use crate::wake_transport::{parse_transport_map, TransportKind};
+use chrono::{DateTime, NaiveTime, Utc};
 use mac_address::MacAddress;
-use std::collections::HashSet;
-use std::env;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone)]
@@ -11,81 +35,1460 @@ pub struct InfluxClient {
     pub client: influxdb::Client,
     pub workerstatus: String,
     pub pvstatus: String,
+    pub query_language: QueryLanguage,
+    // 'user:password' or token for flux queries
+    pub flux_token: Option<String>,
+    // minimum number of samples for a trusted mean
+    pub min_samples: u32,
+    // logical to physical field names
+    pub field_map: HashMap<String, String>,
+    // shard workerstatus measurements by mac (workerstatus_0, workerstatus_1, ...)
+    pub worker_shards: u32,
+    pub weekday_thresholds: ExcessThresholds,
+    // excess thresholds on weekends (weekday thresholds if None)
+    pub weekend_thresholds: Option<ExcessThresholds>,
+    // sun level signal (pvstatus if None)
+    pub sun_measurement: Option<String>,
+    pub sun_field: String,
+    // multiplier of the battery voltage to volts
+    pub voltage_scale: f32,
+    // battery_voltage (default) or soc
+    pub excess_signal: ExcessSignal,
+    // battery banks (default: battery_voltage) combined by aggregation
+    pub voltage_fields: Vec<String>,
+    pub voltage_aggregation: Aggregation,
+    // cap excess outside of the battery temperature range
+    pub temperature_gate: Option<TemperatureGate>,
+    // ignore negative pv_current (sensor glitches)
+    pub clamp_negative_current: bool,
+    // excess Yes only while charging (rising battery voltage)
+    pub require_charging: bool,
+    // statuses which are never stale (e.g. long-running Working jobs)
+    pub never_stale: Vec<i32>,
+    // retries of queries with connection errors
+    pub retries: u32,
+    // external excess source (EXCESS_SOURCE=http)
+    pub excess_url: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Context {
-    pub influx_client: InfluxClient,
+    pub status_store: Arc<dyn StatusStore>,
     pub wake_interval: std::time::Duration,
     pub wake_interval_enabled: bool,
-    pub local_addr: std::net::SocketAddr,
+    // heartbeat by timer or only on manual trigger
+    pub heartbeat_mode: HeartbeatMode,
+    // run the heartbeat on its own thread and runtime (isolated from the server)
+    pub isolate_heartbeat: bool,
+    // write a liveness point (informant tag, cycle field) after each heartbeat
+    pub write_liveness: bool,
+    pub liveness_measurement: String,
+    pub informant_id: String,
+    heartbeat_cycles: Arc<Mutex<u64>>,
+    // wake stale candidates by broadcast without confirming they sleep via ping
+    pub trust_status_for_wake: bool,
+    // only consider stale macs with wake = true as candidates if there is any excess
+    pub wake_intent_requires_excess: bool,
+    // query pv excess (otherwise wake stale sleeping macs regardless of excess)
+    pub excess_enabled: bool,
+    // skip the excess query while the sun is down at this location
+    pub location: Option<Location>,
+    // wake macs without arp entry (e.g. powered off) via their last known ip
+    pub wake_last_known_ip: bool,
+    last_known_ips: Arc<Mutex<HashMap<MacAddress, IpAddr>>>,
+    // limit concurrent pings of wake candidates
+    pub ping_limits: PingLimits,
+    // wake candidates accepting a tcp connection on this port are awake (even without ping)
+    pub awake_tcp_port: Option<u16>,
+    pub net: LinuxNetworkGateway,
+    // ip selection of macs with multiple arp entries
+    pub arp_selection: ArpSelection,
+    // log the inputs of the excess decision in the heartbeat
+    pub log_excess_inputs: bool,
+    // transport used to wake a mac (default: Wake-On-LAN)
+    pub wake_transports: HashMap<MacAddress, TransportKind>,
+    // POST woken macs to this url
+    pub wake_webhook_url: Option<String>,
+    // times (UTC) of the day to wake scheduled macs regardless of excess
+    pub scheduled_wakes: Vec<NaiveTime>,
+    pub scheduled_wake_macs: HashSet<MacAddress>,
+    // drop wake candidates which have not reported for longer than this
+    pub candidate_max_age: Option<chrono::Duration>,
+    // measurements which may be included in the /interval history
+    pub interval_measurements: Vec<String>,
+    // reject shorter /interval queries
+    pub min_query_duration: chrono::Duration,
+    // respond with pvstatus history if the workerstatus query fails
+    pub partial_history: bool,
+    // add X-Influx-Query-Ms to /interval responses
+    pub query_timing_header: bool,
+    // /interval only for the mac of the requester
+    pub restrict_interval_to_self: bool,
+    // include raw influxdb errors in 502 responses
+    pub debug_upstream_errors: bool,
+    // timezone of timestamps in history responses
+    pub response_timezone: chrono_tz::Tz,
+    // max-age of cached /excess responses
+    pub excess_cache_seconds: u64,
+    // respond to /excess with the inputs and thresholds of the decision
+    pub excess_details: bool,
+    // excess status of responses, webhook and report reason in lowercase (e.g. "maybe")
+    pub excess_json_lowercase: bool,
+    // effective excess is the most common of the last decisions (1: no smoothing)
+    pub excess_smooth_cycles: usize,
+    excess_smoother: Arc<Mutex<ExcessSmoother>>,
+    // window of the wake intent history of /workers?history=true
+    pub wake_history_window: chrono::Duration,
+    // accept request bodies without content-length (up to the max size)
+    pub accept_chunked: bool,
+    // bearer token for /admin endpoints (disabled if unset)
+    pub admin_token: Option<String>,
+    // cache macs of requester ips (or resolve on each request)
+    pub mac_cache_mode: MacCacheMode,
+    pub mac_cache_ttl: chrono::Duration,
+    mac_cache: Arc<Mutex<MacCache>>,
+    pub local_addrs: Vec<ListenAddr>,
     pub remote_addr: Option<std::net::SocketAddr>,
-    // issued last wake in last heartbeat
-    just_woke: Arc<Mutex<HashSet<MacAddress>>>,
+    // shared secrets of workers (required for /report if not empty)
+    pub worker_tokens: HashMap<MacAddress, String>,
+    // X-Worker-Token of the request
+    pub worker_token: Option<String>,
+    // resolve the mac of requesters before parsing the body (if the endpoint requires it)
+    pub resolve_mac_early: bool,
+    // mac of the request (if already resolved)
+    pub resolved_mac: Option<MacAddress>,
+    // history of the request as csv (Accept: text/csv)
+    pub accept_csv: bool,
+    // woken macs with the time of waking
+    just_woke: Arc<Mutex<HashMap<MacAddress, DateTime<Utc>>>>,
+    // report macs as woken for this long (default: two wake intervals)
+    pub woken_grace: chrono::Duration,
+    // respond to /report with the reason of the wake decision
+    pub report_reason: bool,
+    // do not wake woken macs again for this long (time to boot)
+    pub post_wake_cooldown: chrono::Duration,
+    last_heartbeat: Arc<Mutex<Option<HeartbeatSummary>>>,
+    // last tick of the heartbeat timer
+    last_tick: Arc<Mutex<Option<tokio::time::Instant>>>,
+    // reporting macs with the time of their last report
+    recently_active: Arc<Mutex<HashMap<MacAddress, DateTime<Utc>>>>,
+    // do not wake macs which reported within this window
+    pub active_window: chrono::Duration,
+    // wake attempts of macs which did not report since
+    wake_backoff: Arc<Mutex<WakeBackoff>>,
+    // spread waking of the candidates across this many heartbeats
+    pub wake_spread_cycles: usize,
+    wake_spread: Arc<Mutex<WakeSpread>>,
+    // process at most this many wake candidates per heartbeat (round-robin)
+    pub max_candidates: Option<usize>,
+    candidate_rotation: Arc<Mutex<CandidateRotation>>,
+    // pause the heartbeat after this many consecutive influxdb failures (0: never)
+    pub influx_circuit_threshold: u32,
+    // per-mac gauges on /metrics for at most this many macs (0: none)
+    pub metrics_max_macs: usize,
+    // maximum entries of a /report/bulk batch
+    pub bulk_max_entries: usize,
+    // persist the last heartbeat decision (replayed on startup unless older than max age)
+    pub state_file: Option<PathBuf>,
+    pub state_max_age: chrono::Duration,
+    influx_circuit: Arc<Mutex<CircuitBreaker>>,
+    // log identical heartbeat errors at most once per window (0: always)
+    pub log_throttle_window: chrono::Duration,
+    log_throttle: Arc<Mutex<LogThrottle>>,
+    // wait for the status store on startup (/excess and /report respond 503 until ready)
+    pub startup_warmup: bool,
+    ready: Arc<Mutex<bool>>,
 }
 
+// retry interval of the startup warmup (Retry-After while not ready)
+pub const WARMUP_RETRY_SECONDS: u64 = 5;
+
 impl Context {
     pub fn load() -> Result<Self, String> {
-        Ok(Self {
-            influx_client: InfluxClient {
-                client: parse_influx_client(
-                    env::var("INFLUXDB_CLIENT").unwrap_or("http://127.0.0.1:8086:test".into()),
+        Self::load_from(&ConfigSource::load()?)
+    }
+    pub fn load_from(config: &ConfigSource) -> Result<Self, String> {
+        let local_addrs =
+            parse_listen_addrs(&config.var("HOST").unwrap_or("127.0.0.1:3000".into()))
+                .map_err(|e| format!("Invalid host config! {}", e))?;
+        for local_addr in &local_addrs {
+            if let ListenAddr::Tcp(addr) = local_addr {
+                if let Some(w) = privileged_port_warning(
+                    addr,
+                    std::fs::read_to_string("/proc/self/status").ok().as_deref(),
+                ) {
+                    warn!("{}", w);
+                }
+            }
+        }
+        let influxdb_str = config
+            .var("INFLUXDB_CLIENT")
+            .unwrap_or("http://127.0.0.1:8086:test".into());
+        let wake_interval =
+            parse_wake_interval(&config.var("WAKE_INTERVAL_SECONDS").unwrap_or("300".into()))?;
+        let weekday_thresholds = ExcessThresholds {
+            sun_levels: match config.var("SUN_LEVELS") {
+                Ok(s) => parse_levels(&s)?,
+                Err(_) => WEEKDAY_THRESHOLDS.sun_levels,
+            },
+            ..WEEKDAY_THRESHOLDS
+        };
+        let influx_conn = parse_influx_conn(
+            &influxdb_str,
+            config.var("INFLUXDB_TOKEN").ok().filter(|t| !t.is_empty()),
+        )?;
+        let startup_warmup = config.flag("STARTUP_WARMUP");
+        let context = Self {
+            status_store: Arc::new(InfluxClient {
+                client: influx_conn.client(),
+                workerstatus: config
+                    .var("WORKER_MEASUREMENT")
+                    .unwrap_or("workerstatus".into()),
+                pvstatus: config.var("PV_MEASUREMENT").unwrap_or("pvstatus".into()),
+                query_language: config
+                    .var("QUERY_LANGUAGE")
+                    .unwrap_or("InfluxQL".into())
+                    .parse()?,
+                flux_token: influx_conn.flux_token(),
+                min_samples: config
+                    .var("MIN_SAMPLES")
+                    .unwrap_or("1".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid min samples config! {}", e))?,
+                field_map: parse_field_map(&config.var("FIELD_MAP").unwrap_or_default())?,
+                worker_shards: config
+                    .var("WORKER_SHARDS")
+                    .unwrap_or("1".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid worker shards config! {}", e))?,
+                weekend_thresholds: parse_weekend_thresholds(
+                    config.var("SUN_LEVELS_WEEKEND").ok(),
+                    config.var("MAYBE_VOLTAGE_THRESHOLDS_WEEKEND").ok(),
+                    config.var("YES_VOLTAGE_THRESHOLDS_WEEKEND").ok(),
+                    &weekday_thresholds,
+                )?,
+                weekday_thresholds,
+                sun_measurement: config.var("SUN_MEASUREMENT").ok(),
+                sun_field: config.var("SUN_FIELD").unwrap_or("pv_current".into()),
+                voltage_scale: config
+                    .var("VOLTAGE_SCALE")
+                    .unwrap_or("1.0".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid voltage scale config! {}", e))?,
+                excess_signal: parse_excess_signal(
+                    config.var("EXCESS_SIGNAL").ok(),
+                    config.var("MAYBE_SOC").ok(),
+                    config.var("YES_SOC").ok(),
+                )?,
+                voltage_fields: match config.var("VOLTAGE_FIELDS") {
+                    Ok(s) => s
+                        .split(',')
+                        .map(|f| f.trim().to_string())
+                        .filter(|f| !f.is_empty())
+                        .collect(),
+                    Err(_) => vec!["battery_voltage".into()],
+                },
+                voltage_aggregation: config
+                    .var("VOLTAGE_AGGREGATION")
+                    .unwrap_or("mean".into())
+                    .parse()?,
+                temperature_gate: parse_temperature_gate(
+                    config.var("TEMPERATURE_RANGE").ok(),
+                    config.var("TEMPERATURE_CAP").ok(),
+                )?,
+                clamp_negative_current: config.flag("CLAMP_NEGATIVE_CURRENT"),
+                require_charging: config.flag("EXCESS_REQUIRE_CHARGING"),
+                never_stale: config
+                    .var("NEVER_STALE_STATUS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse::<WorkerStatus>().map(|s| s as i32))
+                    .collect::<Result<_, _>>()?,
+                retries: config
+                    .var("INFLUX_RETRIES")
+                    .unwrap_or("3".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid influx retries config! {}", e))?,
+                excess_url: parse_excess_source(
+                    config.var("EXCESS_SOURCE").ok(),
+                    config.var("EXCESS_SOURCE_URL").ok(),
                 )?,
-                workerstatus: env::var("WORKER_MEASUREMENT").unwrap_or("workerstatus".into()),
-                pvstatus: env::var("PV_MEASUREMENT").unwrap_or("pvstatus".into()),
+            }),
+            wake_interval,
+            wake_interval_enabled: !config.flag("DISABLE_WAKE_INTERVAL"),
+            heartbeat_mode: config
+                .var("HEARTBEAT_MODE")
+                .unwrap_or("timer".into())
+                .parse()?,
+            isolate_heartbeat: config.flag("ISOLATE_HEARTBEAT"),
+            write_liveness: config.flag("WRITE_LIVENESS"),
+            liveness_measurement: config
+                .var("LIVENESS_MEASUREMENT")
+                .unwrap_or("informantliveness".into()),
+            informant_id: config.var("INFORMANT_ID").unwrap_or("pv_informant".into()),
+            heartbeat_cycles: Arc::new(Mutex::new(0)),
+            trust_status_for_wake: config.flag("TRUST_STATUS_FOR_WAKE"),
+            wake_intent_requires_excess: config.flag("WAKE_INTENT_REQUIRES_EXCESS"),
+            excess_enabled: config.flag_or("EXCESS_ENABLED", true),
+            location: parse_location(config.var("LATITUDE").ok(), config.var("LONGITUDE").ok())?,
+            wake_last_known_ip: config.flag("WAKE_LAST_KNOWN_IP"),
+            last_known_ips: Arc::new(Mutex::new(parse_mac_ips(
+                &config.var("LAST_KNOWN_IPS").unwrap_or_default(),
+                "last known ip",
+            )?)),
+            ping_limits: PingLimits {
+                concurrency: config
+                    .var("PING_CONCURRENCY")
+                    .map(|s| s.parse())
+                    .unwrap_or(Ok(PingLimits::default().concurrency))
+                    .map_err(|e| format!("Invalid ping concurrency config! {}", e))?,
+                jitter: std::time::Duration::from_millis(
+                    config
+                        .var("PING_JITTER_MS")
+                        .unwrap_or("0".into())
+                        .parse()
+                        .map_err(|e| format!("Invalid ping jitter config! {}", e))?,
+                ),
             },
-            wake_interval: std::time::Duration::from_secs(
-                env::var("WAKE_INTERVAL_SECONDS")
-                    .unwrap_or("300".into())
+            awake_tcp_port: config
+                .var("AWAKE_TCP_PORT")
+                .ok()
+                .map(|s| {
+                    s.parse()
+                        .map_err(|e| format!("Invalid awake tcp port config! {}", e))
+                })
+                .transpose()?,
+            net: LinuxNetworkGateway {
+                neigh_timeout: std::time::Duration::from_secs(
+                    config
+                        .var("NEIGH_TIMEOUT_SECONDS")
+                        .unwrap_or("5".into())
+                        .parse()
+                        .map_err(|e| format!("Invalid neigh timeout seconds config! {}", e))?,
+                ),
+                wake_ip_override: parse_wake_ip_override(
+                    &config.var("WAKE_IP_OVERRIDE").unwrap_or_default(),
+                )?,
+                unicast_probes: config
+                    .var("WAKE_UNICAST_PROBE")
+                    .unwrap_or("0".into())
                     .parse()
-                    .map_err(|e| format!("Invalid wake interval seconds config! {}", e))?,
+                    .map_err(|e| format!("Invalid wake unicast probe config! {}", e))?,
+                ping_bin: config.var("PING_BIN").unwrap_or("ping".into()),
+                ip_bin: config.var("IP_BIN").unwrap_or("ip".into()),
+                wake_options: WakeOptions {
+                    group_spacing: config
+                        .var("WAKE_GROUP_SPACING_MS")
+                        .ok()
+                        .map(|s| {
+                            s.parse()
+                                .map(std::time::Duration::from_millis)
+                                .map_err(|e| format!("Invalid wake group spacing config! {}", e))
+                        })
+                        .transpose()?,
+                    subnet_prefix: parse_subnet_prefix(
+                        &config.var("SUBNET_PREFIX").unwrap_or("24".into()),
+                    )?,
+                    unicast: config.flag("WOL_UNICAST"),
+                },
+            },
+            arp_selection: config
+                .var("ARP_SELECTION")
+                .unwrap_or("first".into())
+                .parse()?,
+            log_excess_inputs: config.flag("LOG_EXCESS_INPUTS"),
+            wake_transports: parse_transport_map(
+                &config.var("WAKE_TRANSPORTS").unwrap_or_default(),
+            )?,
+            wake_webhook_url: config.var("WAKE_WEBHOOK_URL").ok(),
+            scheduled_wakes: parse_schedule(&config.var("SCHEDULED_WAKES").unwrap_or_default())?,
+            scheduled_wake_macs: config
+                .var("SCHEDULED_WAKE_MACS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|m| m.trim())
+                .filter(|m| !m.is_empty())
+                .map(|m| {
+                    m.parse()
+                        .map_err(|e| format!("Invalid scheduled wake mac '{}'! {}", m, e))
+                })
+                .collect::<Result<_, _>>()?,
+            candidate_max_age: config
+                .var("CANDIDATE_MAX_AGE")
+                .ok()
+                .map(|s| {
+                    s.parse()
+                        .map(chrono::Duration::seconds)
+                        .map_err(|e| format!("Invalid candidate max age seconds config! {}", e))
+                })
+                .transpose()?,
+            interval_measurements: config
+                .var("INTERVAL_MEASUREMENTS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|m| m.trim())
+                .filter(|m| !m.is_empty())
+                .map(|m| m.to_string())
+                .collect(),
+            min_query_duration: chrono::Duration::seconds(
+                config
+                    .var("MIN_QUERY_DURATION")
+                    .unwrap_or("0".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid min query duration config! {}", e))?,
             ),
-            wake_interval_enabled: env::var("DISABLE_WAKE_INTERVAL")
-                .map(|_| false)
-                .unwrap_or(true),
-            local_addr: env::var("HOST")
-                .unwrap_or("127.0.0.1:3000".into())
+            partial_history: config.flag("PARTIAL_HISTORY"),
+            query_timing_header: config.flag("QUERY_TIMING_HEADER"),
+            restrict_interval_to_self: config.flag("RESTRICT_INTERVAL_TO_SELF"),
+            debug_upstream_errors: config.flag("DEBUG_UPSTREAM_ERRORS"),
+            response_timezone: config
+                .var("RESPONSE_TIMEZONE")
+                .unwrap_or("UTC".into())
+                .parse()
+                .map_err(|e| format!("Invalid response timezone config! {}", e))?,
+            excess_cache_seconds: config
+                .var("EXCESS_CACHE_SECONDS")
+                .unwrap_or("5".into())
                 .parse()
-                .map_err(|e| format!("Invalid host config! {}", e))?,
-            just_woke: Arc::new(Mutex::new(HashSet::new())),
+                .map_err(|e| format!("Invalid excess cache seconds config! {}", e))?,
+            excess_details: config.flag("EXCESS_DETAILS"),
+            excess_json_lowercase: config.flag("EXCESS_JSON_LOWERCASE"),
+            excess_smooth_cycles: config
+                .var("EXCESS_SMOOTH_CYCLES")
+                .unwrap_or("1".into())
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid excess smooth cycles config! {}", e))?
+                .max(1),
+            excess_smoother: Arc::new(Mutex::new(ExcessSmoother::default())),
+            wake_history_window: chrono::Duration::seconds(
+                config
+                    .var("WAKE_HISTORY_WINDOW")
+                    .unwrap_or("604800".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid wake history window config! {}", e))?,
+            ),
+            accept_chunked: config.flag("ACCEPT_CHUNKED_REQUESTS"),
+            admin_token: config.var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            mac_cache_mode: config
+                .var("MAC_CACHE_MODE")
+                .unwrap_or("ttl".into())
+                .parse()?,
+            mac_cache_ttl: chrono::Duration::seconds(
+                config
+                    .var("MAC_CACHE_TTL_SECONDS")
+                    .unwrap_or("60".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid mac cache ttl seconds config! {}", e))?,
+            ),
+            mac_cache: Arc::new(Mutex::new(MacCache::default())),
+            local_addrs,
+            just_woke: Arc::new(Mutex::new(HashMap::new())),
+            woken_grace: chrono::Duration::seconds(
+                config
+                    .var("WOKEN_GRACE_SECONDS")
+                    .map(|s| s.parse())
+                    .unwrap_or(Ok(2 * wake_interval.as_secs() as i64))
+                    .map_err(|e| format!("Invalid woken grace seconds config! {}", e))?,
+            ),
+            report_reason: config.flag("REPORT_REASON"),
+            post_wake_cooldown: chrono::Duration::seconds(
+                config
+                    .var("POST_WAKE_COOLDOWN")
+                    .unwrap_or("0".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid post wake cooldown seconds config! {}", e))?,
+            ),
+            last_heartbeat: Arc::new(Mutex::new(None)),
+            last_tick: Arc::new(Mutex::new(None)),
+            recently_active: Arc::new(Mutex::new(HashMap::new())),
+            wake_backoff: Arc::new(Mutex::new(WakeBackoff::default())),
+            wake_spread_cycles: config
+                .var("WAKE_SPREAD_CYCLES")
+                .unwrap_or("1".into())
+                .parse()
+                .map_err(|e| format!("Invalid wake spread cycles config! {}", e))?,
+            wake_spread: Arc::new(Mutex::new(WakeSpread::default())),
+            max_candidates: config
+                .var("MAX_CANDIDATES_PER_HEARTBEAT")
+                .ok()
+                .map(|s| {
+                    s.parse()
+                        .map_err(|e| format!("Invalid max candidates config! {}", e))
+                })
+                .transpose()?,
+            candidate_rotation: Arc::new(Mutex::new(CandidateRotation::default())),
+            influx_circuit_threshold: config
+                .var("INFLUX_CIRCUIT_THRESHOLD")
+                .unwrap_or("0".into())
+                .parse()
+                .map_err(|e| format!("Invalid influx circuit threshold config! {}", e))?,
+            influx_circuit: Arc::new(Mutex::new(CircuitBreaker::default())),
+            log_throttle_window: chrono::Duration::seconds(
+                config
+                    .var("LOG_THROTTLE_SECONDS")
+                    .unwrap_or("0".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid log throttle seconds config! {}", e))?,
+            ),
+            log_throttle: Arc::new(Mutex::new(LogThrottle::default())),
+            startup_warmup,
+            ready: Arc::new(Mutex::new(!startup_warmup)),
+            metrics_max_macs: config
+                .var("METRICS_MAX_MACS")
+                .unwrap_or("0".into())
+                .parse()
+                .map_err(|e| format!("Invalid metrics max macs config! {}", e))?,
+            bulk_max_entries: config
+                .var("BULK_MAX_ENTRIES")
+                .unwrap_or("1000".into())
+                .parse()
+                .map_err(|e| format!("Invalid bulk max entries config! {}", e))?,
+            state_file: config.var("STATE_FILE").ok().map(PathBuf::from),
+            state_max_age: chrono::Duration::seconds(
+                config
+                    .var("STATE_MAX_AGE")
+                    .map(|s| s.parse())
+                    .unwrap_or(Ok(2 * wake_interval.as_secs() as i64))
+                    .map_err(|e| format!("Invalid state max age config! {}", e))?,
+            ),
+            active_window: chrono::Duration::seconds(
+                config
+                    .var("ACTIVE_WINDOW_SECONDS")
+                    .unwrap_or("60".into())
+                    .parse()
+                    .map_err(|e| format!("Invalid active window seconds config! {}", e))?,
+            ),
             remote_addr: None,
-        })
+            worker_tokens: parse_worker_tokens(&config.var("WORKER_TOKENS").unwrap_or_default())?,
+            worker_token: None,
+            resolve_mac_early: config.flag("RESOLVE_MAC_EARLY"),
+            resolved_mac: None,
+            accept_csv: false,
+        };
+        context.restore_state(Utc::now());
+        Ok(context)
+    }
+    fn restore_state(&self, now: DateTime<Utc>) {
+        if let (Some(path), true) = (&self.state_file, self.wake_last_known_ip) {
+            // learned ips take precedence over LAST_KNOWN_IPS (like at runtime)
+            self.last_known_ips
+                .lock()
+                .unwrap()
+                .extend(load_last_known_ips(path));
+        }
+        let state = match &self.state_file {
+            Some(path) => load_state(path, self.state_max_age, now),
+            None => None,
+        };
+        if let Some(state) = state {
+            info!("replaying heartbeat decision of {}", state.saved_at);
+            self.just_woke.lock().unwrap().extend(state.woken);
+            self.set_last_heartbeat(HeartbeatSummary {
+                excess: state.excess,
+                candidates: 0,
+                awake: 0,
+                woken: 0,
+                scheduled: 0,
+            });
+        }
+    }
+    pub fn persist_state(&self) {
+        let (path, summary) = match (&self.state_file, self.last_heartbeat()) {
+            (Some(path), Some(summary)) => (path, summary),
+            _ => return,
+        };
+        let state = PersistedState {
+            excess: summary.excess,
+            woken: self.woken_macs().into_iter().collect(),
+            saved_at: Utc::now(),
+            last_known_ips: self
+                .last_known_ips
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(m, ip)| (*m, *ip))
+                .collect(),
+        };
+        if let Err(e) = save_state(path, &state) {
+            warn!("Failed to persist state to {:?}! {}", path, e);
+        }
     }
     pub fn woken_in_previous_heartbeat(&self, mac: &MacAddress) -> bool {
         let woken_macs = self.just_woke.lock().unwrap();
-        woken_macs.contains(mac)
+        woken_macs
+            .get(mac)
+            .map(|t| *t > Utc::now() - self.woken_grace)
+            .unwrap_or(false)
     }
     pub fn just_woke(&self, macs: HashSet<MacAddress>) -> () {
+        let now = Utc::now();
         let mut guard = self.just_woke.lock().unwrap();
-        *guard = macs;
+        guard.extend(macs.into_iter().map(|m| (m, now)));
+    }
+    pub fn in_wake_cooldown(&self, mac: &MacAddress) -> bool {
+        let woken_macs = self.just_woke.lock().unwrap();
+        woken_macs
+            .get(mac)
+            .map(|t| *t > Utc::now() - self.post_wake_cooldown)
+            .unwrap_or(false)
+    }
+    pub fn woken_macs(&self) -> HashMap<MacAddress, DateTime<Utc>> {
+        self.just_woke.lock().unwrap().clone()
+    }
+    pub fn prune_woken(&self) -> usize {
+        // forget macs which were woken before the grace period (and cooldown)
+        let oldest = Utc::now() - self.woken_grace.max(self.post_wake_cooldown);
+        let mut guard = self.just_woke.lock().unwrap();
+        let before = guard.len();
+        guard.retain(|_, t| *t > oldest);
+        before - guard.len()
+    }
+    pub fn mark_active(&self, mac: &MacAddress) {
+        let now = Utc::now();
+        let mut guard = self.recently_active.lock().unwrap();
+        // forget macs outside of the window
+        guard.retain(|_, t| *t > now - self.active_window);
+        guard.insert(mac.clone(), now);
+    }
+    pub fn recently_active(&self, mac: &MacAddress) -> bool {
+        self.recently_active
+            .lock()
+            .unwrap()
+            .get(mac)
+            .map(|t| *t > Utc::now() - self.active_window)
+            .unwrap_or(false)
+    }
+    pub fn wake_eligible(&self, mac: &MacAddress) -> bool {
+        // back off exponentially (in multiples of the wake interval)
+        let base = chrono::Duration::from_std(self.wake_interval)
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        self.wake_backoff
+            .lock()
+            .unwrap()
+            .eligible(mac, base, Utc::now())
+    }
+    pub fn record_wake_attempts(&self, macs: &HashSet<MacAddress>) {
+        let now = Utc::now();
+        let mut backoff = self.wake_backoff.lock().unwrap();
+        for m in macs {
+            backoff.record_attempt(m, now);
+        }
+    }
+    pub fn wake_attempts(&self) -> HashMap<MacAddress, u32> {
+        self.wake_backoff.lock().unwrap().all_attempts()
+    }
+    pub fn reset_backoff(&self, mac: Option<&MacAddress>) -> usize {
+        self.wake_backoff.lock().unwrap().reset(mac)
+    }
+    pub fn wake_slice(&self, macs: &HashSet<MacAddress>) -> HashSet<MacAddress> {
+        self.wake_spread
+            .lock()
+            .unwrap()
+            .next_slice(macs, self.wake_spread_cycles)
+    }
+    pub fn candidate_batch(&self, macs: HashSet<MacAddress>) -> HashSet<MacAddress> {
+        match self.max_candidates {
+            Some(max) if macs.len() > max => self
+                .candidate_rotation
+                .lock()
+                .unwrap()
+                .next_batch(&macs, max),
+            _ => macs,
+        }
+    }
+    pub fn record_influx_health(&self, ok: bool) {
+        self.influx_circuit.lock().unwrap().record(ok)
+    }
+    pub fn influx_circuit_open(&self) -> bool {
+        self.influx_circuit
+            .lock()
+            .unwrap()
+            .is_open(self.influx_circuit_threshold)
+    }
+    pub fn last_heartbeat(&self) -> Option<HeartbeatSummary> {
+        self.last_heartbeat.lock().unwrap().clone()
+    }
+    pub fn set_last_heartbeat(&self, summary: HeartbeatSummary) {
+        *self.last_heartbeat.lock().unwrap() = Some(summary);
+    }
+    pub fn next_heartbeat_cycle(&self) -> u64 {
+        let mut cycles = self.heartbeat_cycles.lock().unwrap();
+        *cycles += 1;
+        *cycles
+    }
+    pub fn smooth_excess(&self, excess: ExcessStatus) -> ExcessStatus {
+        self.excess_smoother
+            .lock()
+            .unwrap()
+            .push(excess, self.excess_smooth_cycles)
+    }
+    pub fn peek_smoothed_excess(&self, excess: &ExcessStatus) -> ExcessStatus {
+        self.excess_smoother
+            .lock()
+            .unwrap()
+            .peek(excess, self.excess_smooth_cycles)
+    }
+    pub fn throttle_log(&self, msg: String) -> Option<String> {
+        self.log_throttle
+            .lock()
+            .unwrap()
+            .check(&msg, self.log_throttle_window, Utc::now())
+    }
+    pub fn remember_ip(&self, mac: MacAddress, ip: IpAddr) {
+        if self.wake_last_known_ip {
+            self.last_known_ips.lock().unwrap().insert(mac, ip);
+        }
+    }
+    pub fn remember_ips(&self, mac_mapping: &MacIpMapping) {
+        for (mac, ip) in mac_mapping {
+            if let Some(ip) = ip {
+                self.remember_ip(*mac, *ip);
+            }
+        }
+    }
+    pub fn with_last_known_ips(&self, mac_mapping: MacIpMapping) -> MacIpMapping {
+        // fill in the ips of macs missing in the arp table
+        if !self.wake_last_known_ip {
+            return mac_mapping;
+        }
+        let known = self.last_known_ips.lock().unwrap();
+        mac_mapping
+            .into_iter()
+            .map(|(m, ip)| (m, ip.or_else(|| known.get(&m).copied())))
+            .collect()
+    }
+    pub fn is_ready(&self) -> bool {
+        *self.ready.lock().unwrap()
+    }
+    pub fn set_ready(&self, ready: bool) {
+        *self.ready.lock().unwrap() = ready;
+    }
+    pub async fn warmup(&self) {
+        // retry until the status store is reachable
+        while let Err(e) = self.status_store.health_check().await {
+            info!(
+                "status store not ready, retrying in {}s! {}",
+                WARMUP_RETRY_SECONDS, e
+            );
+            tokio::time::sleep(std::time::Duration::from_secs(WARMUP_RETRY_SECONDS)).await;
+        }
+        info!("status store is ready");
+        self.set_ready(true);
+    }
+    pub fn record_tick(&self, at: tokio::time::Instant) {
+        *self.last_tick.lock().unwrap() = Some(at);
+    }
+    pub fn next_heartbeat(&self) -> Option<DateTime<Utc>> {
+        // one wake interval after the last tick (None before the first tick)
+        let last_tick = (*self.last_tick.lock().unwrap())?;
+        let now = tokio::time::Instant::now();
+        let next = last_tick + self.wake_interval;
+        let until_next = chrono::Duration::from_std(next.saturating_duration_since(now)).ok()?;
+        Some(Utc::now() + until_next)
     }
     pub async fn remote_mac(&self) -> Result<Option<MacAddress>, ApiError> {
-        let ip = self.remote_addr.unwrap().ip();
-        addr_to_mac(ip)
+        if self.resolved_mac.is_some() {
+            return Ok(self.resolved_mac);
+        }
+        match self.remote_addr {
+            Some(addr) => self.resolve_mac(addr.ip(), &self.net).await,
+            // no peer ip (unix domain socket)
+            None => Ok(None),
+        }
+    }
+    async fn resolve_mac(
+        &self,
+        ip: std::net::IpAddr,
+        net: &(impl NetworkGateway + Sync),
+    ) -> Result<Option<MacAddress>, ApiError> {
+        let now = Utc::now();
+        let cached = match self.mac_cache_mode {
+            MacCacheMode::Ttl => self
+                .mac_cache
+                .lock()
+                .unwrap()
+                .get(&ip, self.mac_cache_ttl, now),
+            MacCacheMode::Off => None,
+        };
+        if cached.is_some() {
+            return Ok(cached);
+        }
+        let mac = addr_to_mac(ip, net)
             .await
-            .map_err(|e| server_err!("Failed to find mac for {}! {}", ip, e))
+            .map_err(|e| server_err!("Failed to find mac for {}! {}", ip, e))?;
+        if let (MacCacheMode::Ttl, Some(m)) = (self.mac_cache_mode, mac) {
+            self.mac_cache.lock().unwrap().insert(ip, m, now);
+        }
+        if let Some(m) = mac {
+            self.remember_ip(m, ip);
+        }
+        Ok(mac)
+    }
+}
+
+fn parse_levels(s: &str) -> Result<[f32; 3], String> {
+    // three comma-separated thresholds (one per sun level)
+    let levels: Vec<f32> = s
+        .split(',')
+        .map(|v| {
+            v.trim()
+                .parse()
+                .map_err(|e| format!("Invalid threshold '{}'! {}", v, e))
+        })
+        .collect::<Result<_, _>>()?;
+    levels
+        .try_into()
+        .map_err(|_| format!("Expected 3 thresholds in '{}'!", s))
+}
+
+fn parse_weekend_thresholds(
+    sun_levels: Option<String>,
+    maybe_voltage: Option<String>,
+    yes_voltage: Option<String>,
+    weekday: &ExcessThresholds,
+) -> Result<Option<ExcessThresholds>, String> {
+    if sun_levels.is_none() && maybe_voltage.is_none() && yes_voltage.is_none() {
+        return Ok(None);
+    }
+    // unset thresholds default to the weekday thresholds
+    let parse =
+        |s: Option<String>, default: [f32; 3]| s.map(|s| parse_levels(&s)).unwrap_or(Ok(default));
+    Ok(Some(ExcessThresholds {
+        sun_levels: parse(sun_levels, weekday.sun_levels)?,
+        maybe_voltage: parse(maybe_voltage, weekday.maybe_voltage)?,
+        yes_voltage: parse(yes_voltage, weekday.yes_voltage)?,
+    }))
+}
+
+fn parse_excess_signal(
+    signal: Option<String>,
+    maybe_soc: Option<String>,
+    yes_soc: Option<String>,
+) -> Result<ExcessSignal, String> {
+    let signal = signal.unwrap_or("voltage".into()).parse()?;
+    Ok(match signal {
+        ExcessSignal::Soc { maybe, yes } => ExcessSignal::Soc {
+            maybe: maybe_soc.map(|s| parse_levels(&s)).unwrap_or(Ok(maybe))?,
+            yes: yes_soc.map(|s| parse_levels(&s)).unwrap_or(Ok(yes))?,
+        },
+        signal => signal,
+    })
+}
+
+fn parse_temperature_gate(
+    range: Option<String>,
+    cap: Option<String>,
+) -> Result<Option<TemperatureGate>, String> {
+    // 'min,max' (degrees) with cap 'maybe' (default) or 'no'
+    let range = match range {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<f32>()
+            .map_err(|e| format!("Invalid temperature '{}'! {}", v, e))
+    };
+    let (min, max) = range
+        .split_once(',')
+        .ok_or_else(|| format!("Invalid temperature range '{}'! (min,max)", range))?;
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!(
+            "Invalid temperature range '{}'! (min > max)",
+            range
+        ));
+    }
+    let cap = match cap.unwrap_or("maybe".into()).to_lowercase().as_str() {
+        "maybe" => ExcessStatus::Maybe,
+        "no" => ExcessStatus::No,
+        c => return Err(format!("Invalid temperature cap '{}'! (maybe|no)", c)),
+    };
+    Ok(Some(TemperatureGate { min, max, cap }))
+}
+
+fn parse_field_map(field_map: &str) -> Result<HashMap<String, String>, String> {
+    // comma-separated 'logical=physical' pairs
+    field_map
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            p.split_once('=')
+                .map(|(l, f)| (l.trim().to_string(), f.trim().to_string()))
+                .ok_or_else(|| {
+                    format!("Invalid field map entry '{}'! Expected logical=physical", p)
+                })
+        })
+        .collect()
+}
+
+// bounds of the heartbeat interval (0 would busy-loop)
+const MIN_WAKE_INTERVAL_SECONDS: u64 = 10;
+const MAX_WAKE_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
+
+fn parse_wake_interval(seconds: &str) -> Result<std::time::Duration, String> {
+    let secs: u64 = seconds
+        .parse()
+        .map_err(|e| format!("Invalid wake interval seconds config! {}", e))?;
+    if !(MIN_WAKE_INTERVAL_SECONDS..=MAX_WAKE_INTERVAL_SECONDS).contains(&secs) {
+        return Err(format!(
+            "Wake interval of {}s is out of range [{}s, {}s]!",
+            secs, MIN_WAKE_INTERVAL_SECONDS, MAX_WAKE_INTERVAL_SECONDS
+        ));
     }
+    Ok(std::time::Duration::from_secs(secs))
 }
 
-fn parse_influx_client(influxdb_str: String) -> Result<influxdb::Client, String> {
+fn parse_schedule(schedule: &str) -> Result<Vec<NaiveTime>, String> {
+    // comma-separated 'HH:MM' list
+    schedule
+        .split(',')
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            NaiveTime::parse_from_str(t, "%H:%M")
+                .map_err(|e| format!("Invalid scheduled wake time '{}'! {}", t, e))
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum InfluxAuth {
+    // 1.x user and password
+    Basic(String, String),
+    // 2.x api token
+    Token(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct InfluxConn {
+    url: String,
+    dbname: String,
+    auth: Option<InfluxAuth>,
+}
+
+impl InfluxConn {
+    fn client(&self) -> influxdb::Client {
+        let client = influxdb::Client::new(&self.url, &self.dbname);
+        match &self.auth {
+            Some(InfluxAuth::Basic(username, password)) => client.with_auth(username, password),
+            Some(InfluxAuth::Token(token)) => client.with_token(token),
+            None => client,
+        }
+    }
+    fn flux_token(&self) -> Option<String> {
+        // 'Token user:password' is accepted by 1.x
+        self.auth.as_ref().map(|auth| match auth {
+            InfluxAuth::Basic(username, password) => format!("{}:{}", username, password),
+            InfluxAuth::Token(token) => token.clone(),
+        })
+    }
+}
+
+fn parse_influx_conn(influxdb_str: &str, token: Option<String>) -> Result<InfluxConn, String> {
     let error_str = "Invalid influxdb client config!";
-    // user:password@http[s]://host:port:dbname
-    // user:password and port is optional
-    let mut auth_n_conn: Vec<&str> = influxdb_str.split('@').collect();
-    let conn = auth_n_conn.pop().ok_or(error_str)?;
-    let mut url_dbname: Vec<&str> = conn.split(':').collect();
-    let dbname: &str = url_dbname.pop().ok_or(error_str)?;
-    let url = url_dbname.join(":");
-    let client = influxdb::Client::new(url, dbname);
-    Ok(if auth_n_conn.len() > 0 {
-        let auth = auth_n_conn[0];
-        let mut name_pwd = auth.split(':');
-        let username = name_pwd.next().ok_or(error_str)?;
-        let password = name_pwd.next().unwrap_or(username);
-        client.with_auth(username, password)
-    } else {
-        client
+    // user:password@http[s]://host:port:dbname or token:<token>@http[s]://host:port:bucket
+    // auth and port are optional
+    let (auth_str, conn) = match influxdb_str.rsplit_once('@') {
+        Some((auth, conn)) => (Some(auth), conn),
+        None => (None, influxdb_str),
+    };
+    let (url, dbname) = conn.rsplit_once(':').ok_or(error_str)?;
+    // INFLUXDB_TOKEN takes precedence over the auth of the connection string
+    let auth = match (token, auth_str) {
+        (Some(token), _) => Some(InfluxAuth::Token(token)),
+        (None, Some(auth)) => Some(match auth.strip_prefix("token:") {
+            Some(token) => InfluxAuth::Token(token.to_string()),
+            None => {
+                let (username, password) = auth.split_once(':').unwrap_or((auth, auth));
+                InfluxAuth::Basic(username.to_string(), password.to_string())
+            }
+        }),
+        (None, None) => None,
+    };
+    Ok(InfluxConn {
+        url: url.to_string(),
+        dbname: dbname.to_string(),
+        auth,
     })
 }
+
+const CAP_NET_BIND_SERVICE: u32 = 10;
+
+fn may_bind_privileged(proc_status: &str) -> bool {
+    // effective uid 0 or CAP_NET_BIND_SERVICE in effective capabilities
+    let field = |name: &str| {
+        proc_status
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .map(|v| v.trim())
+    };
+    let root = field("Uid:").and_then(|uids| uids.split_whitespace().nth(1)) == Some("0");
+    let capable = field("CapEff:")
+        .and_then(|caps| u64::from_str_radix(caps, 16).ok())
+        .map(|caps| caps & (1 << CAP_NET_BIND_SERVICE) != 0)
+        .unwrap_or(false);
+    root || capable
+}
+
+fn privileged_port_warning(
+    addr: &std::net::SocketAddr,
+    proc_status: Option<&str>,
+) -> Option<String> {
+    match proc_status {
+        Some(status) if addr.port() != 0 && addr.port() < 1024 && !may_bind_privileged(status) => {
+            Some(format!(
+                "HOST port {} is privileged (<1024) but the process is neither root nor has \
+                CAP_NET_BIND_SERVICE! Use a port >= 1024 or grant the capability \
+                (e.g. setcap 'cap_net_bind_service=+ep').",
+                addr.port()
+            ))
+        }
+        _ => None,
+    }
+}
+
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+
+    const USER_STATUS: &str =
+        "Name:\tpv_informant\nUid:\t1000\t1000\t1000\t1000\nCapEff:\t0000000000000000\n";
+    const ROOT_STATUS: &str = "Name:\tpv_informant\nUid:\t0\t0\t0\t0\nCapEff:\t000001ffffffffff\n";
+    const CAP_STATUS: &str =
+        "Name:\tpv_informant\nUid:\t1000\t1000\t1000\t1000\nCapEff:\t0000000000000400\n";
+
+    #[tokio::test]
+    async fn test_mac_cache_mode() {
+        use crate::neighbor::test::NetworkGatewayMock;
+        use std::sync::atomic::Ordering;
+        let net = NetworkGatewayMock {
+            neigh_resp: "192.168.178.2 dev eth0 lladdr 11:22:33:44:55:66 REACHABLE".into(),
+            ..Default::default()
+        };
+        let ip = "192.168.178.2".parse().unwrap();
+        let mut context = Context::load().unwrap();
+        context.mac_cache_mode = MacCacheMode::Ttl;
+        for _ in 0..3 {
+            assert_matches!(context.resolve_mac(ip, &net).await, Ok(Some(_)));
+        }
+        assert_eq!(
+            net.neigh_calls.load(Ordering::SeqCst),
+            1,
+            "should resolve once within the ttl"
+        );
+        context.mac_cache_mode = MacCacheMode::Off;
+        for _ in 0..3 {
+            assert_matches!(context.resolve_mac(ip, &net).await, Ok(Some(_)));
+        }
+        assert_eq!(
+            net.neigh_calls.load(Ordering::SeqCst),
+            4,
+            "should resolve on each report without cache"
+        );
+    }
+
+    #[test]
+    fn test_parse_influx_conn() {
+        let conn = |url: &str, dbname: &str, auth: Option<InfluxAuth>| InfluxConn {
+            url: url.into(),
+            dbname: dbname.into(),
+            auth,
+        };
+        assert_eq!(
+            parse_influx_conn("http://127.0.0.1:8086:test", None),
+            Ok(conn("http://127.0.0.1:8086", "test", None))
+        );
+        assert_eq!(
+            parse_influx_conn("user:pass@https://influx:8086:test", None),
+            Ok(conn(
+                "https://influx:8086",
+                "test",
+                Some(InfluxAuth::Basic("user".into(), "pass".into()))
+            )),
+            "should use basic auth (1.x)"
+        );
+        assert_eq!(
+            parse_influx_conn("token:s3cr3t==@http://influx:8086:bucket", None),
+            Ok(conn(
+                "http://influx:8086",
+                "bucket",
+                Some(InfluxAuth::Token("s3cr3t==".into()))
+            )),
+            "should use token auth (2.x)"
+        );
+        assert_eq!(
+            parse_influx_conn("user:pass@http://influx:8086:bucket", Some("s3cr3t".into())),
+            Ok(conn(
+                "http://influx:8086",
+                "bucket",
+                Some(InfluxAuth::Token("s3cr3t".into()))
+            )),
+            "should prefer INFLUXDB_TOKEN"
+        );
+        assert_eq!(
+            parse_influx_conn("user:pass@http://influx:8086:test", None)
+                .unwrap()
+                .flux_token(),
+            Some("user:pass".into())
+        );
+    }
+
+    #[test]
+    fn test_parse_weekend_thresholds() {
+        assert_eq!(
+            parse_weekend_thresholds(None, None, None, &WEEKDAY_THRESHOLDS),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_weekend_thresholds(Some("10, 30, 50".into()), None, None, &WEEKDAY_THRESHOLDS),
+            Ok(Some(ExcessThresholds {
+                sun_levels: [10.0, 30.0, 50.0],
+                ..WEEKDAY_THRESHOLDS
+            })),
+            "should default unset thresholds to weekday thresholds"
+        );
+        assert_matches!(
+            parse_weekend_thresholds(None, Some("12.7,12.5".into()), None, &WEEKDAY_THRESHOLDS),
+            Err(_)
+        );
+        assert_matches!(
+            parse_weekend_thresholds(None, None, Some("13,x,12".into()), &WEEKDAY_THRESHOLDS),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn test_replay_state() {
+        let path =
+            std::env::temp_dir().join(format!("pv_informant_replay_{}.json", std::process::id()));
+        let config = ConfigSource::load_with(HashMap::from([
+            ("STATE_FILE".into(), path.to_string_lossy().to_string()),
+            ("STATE_MAX_AGE".into(), "600".into()),
+        ]))
+        .unwrap();
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        for (age, replayed) in [(60, true), (900, false)] {
+            let saved_at = Utc::now() - chrono::Duration::seconds(age);
+            save_state(
+                &path,
+                &PersistedState {
+                    excess: ExcessStatus::Maybe,
+                    woken: vec![(mac, Utc::now())],
+                    saved_at,
+                    last_known_ips: vec![],
+                },
+            )
+            .unwrap();
+            let context = Context::load_from(&config).unwrap();
+            assert_eq!(
+                context.woken_in_previous_heartbeat(&mac),
+                replayed,
+                "state saved {}s ago should be replayed: {}",
+                age,
+                replayed
+            );
+            assert_eq!(
+                matches!(
+                    context.last_heartbeat(),
+                    Some(HeartbeatSummary {
+                        excess: ExcessStatus::Maybe,
+                        ..
+                    })
+                ),
+                replayed
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_last_known_ips() {
+        let path =
+            std::env::temp_dir().join(format!("pv_informant_ips_{}.json", std::process::id()));
+        let config = ConfigSource::load_with(HashMap::from([
+            ("STATE_FILE".into(), path.to_string_lossy().to_string()),
+            ("STATE_MAX_AGE".into(), "600".into()),
+            ("WAKE_LAST_KNOWN_IP".into(), "1".into()),
+            ("LAST_KNOWN_IPS".into(), "11:22:33:44:55:77=10.0.0.7".into()),
+        ]))
+        .unwrap();
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let ip: IpAddr = "192.168.5.23".parse().unwrap();
+        let context = Context::load_from(&config).unwrap();
+        context.remember_ip(mac, ip);
+        context.set_last_heartbeat(HeartbeatSummary {
+            excess: ExcessStatus::No,
+            candidates: 0,
+            awake: 0,
+            woken: 0,
+            scheduled: 0,
+        });
+        context.persist_state();
+
+        // powered off for longer than the state max age
+        let mut state = load_state(&path, chrono::Duration::days(1), Utc::now()).unwrap();
+        state.saved_at = Utc::now() - chrono::Duration::days(3);
+        save_state(&path, &state).unwrap();
+        let restarted = Context::load_from(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(restarted.last_heartbeat().is_none());
+        let seeded: MacAddress = "11:22:33:44:55:77".parse().unwrap();
+        let mapping =
+            restarted.with_last_known_ips([(mac, None), (seeded, None)].into_iter().collect());
+        assert_eq!(
+            mapping[&mac],
+            Some(ip),
+            "should replay the last known ip after a restart"
+        );
+        assert_eq!(mapping[&seeded], "10.0.0.7".parse().ok());
+        assert_matches!(
+            Context::load_from(
+                &ConfigSource::load_with(HashMap::from([(
+                    "LAST_KNOWN_IPS".into(),
+                    "11:22:33:44:55:77".into()
+                )]))
+                .unwrap()
+            ),
+            Err(e) if e.contains("last known ip"),
+            "should report the LAST_KNOWN_IPS config"
+        );
+    }
+
+    #[test]
+    fn test_load_config_file() {
+        let path = std::env::temp_dir().join(format!("pv_informant_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "WAKE_INTERVAL_SECONDS = 600\nTRUST_STATUS_FOR_WAKE = true\nPING_CONCURRENCY = 2\n\
+            SCHEDULED_WAKES = [\"08:00\", \"12:30\"]\nEXCESS_ENABLED = 0\n\
+            INTERVAL_MEASUREMENTS = [\"excessstatus\", \"\"]\n",
+        )
+        .unwrap();
+        let config = ConfigSource::load_with(HashMap::from([
+            ("CONFIG_FILE".into(), path.to_string_lossy().to_string()),
+            ("PING_CONCURRENCY".into(), "4".into()),
+        ]))
+        .unwrap();
+        let context = Context::load_from(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(context.wake_interval.as_secs(), 600);
+        assert!(context.trust_status_for_wake);
+        assert!(!context.excess_enabled, "should disable with '0'");
+        assert_eq!(context.scheduled_wakes.len(), 2);
+        assert_eq!(
+            context.interval_measurements,
+            vec!["excessstatus".to_string()],
+            "should skip empty measurements"
+        );
+        assert_eq!(
+            context.ping_limits.concurrency, 4,
+            "env vars should override file values"
+        );
+        assert_matches!(
+            ConfigSource::load_with(HashMap::from([(
+                "CONFIG_FILE".into(),
+                "/nonexistent.toml".into()
+            )])),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn test_parse_excess_signal() {
+        assert_eq!(
+            parse_excess_signal(None, None, None),
+            Ok(ExcessSignal::Voltage)
+        );
+        assert_eq!(
+            parse_excess_signal(Some("SOC".into()), None, Some("98,92,85".into())),
+            Ok(ExcessSignal::Soc {
+                maybe: [80.0, 70.0, 60.0],
+                yes: [98.0, 92.0, 85.0],
+            })
+        );
+        assert_matches!(
+            parse_excess_signal(Some("soc".into()), Some("80,70".into()), None),
+            Err(_)
+        );
+        assert_matches!(
+            parse_excess_signal(Some("power".into()), None, None),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn test_parse_temperature_gate() {
+        assert_matches!(parse_temperature_gate(None, Some("no".into())), Ok(None));
+        assert_matches!(
+            parse_temperature_gate(Some("5, 40".into()), None),
+            Ok(Some(TemperatureGate { min, max, cap: ExcessStatus::Maybe })) if min == 5.0 && max == 40.0
+        );
+        assert_matches!(
+            parse_temperature_gate(Some("-10,35".into()), Some("No".into())),
+            Ok(Some(TemperatureGate {
+                cap: ExcessStatus::No,
+                ..
+            }))
+        );
+        assert_matches!(parse_temperature_gate(Some("40,5".into()), None), Err(_));
+        assert_matches!(parse_temperature_gate(Some("5".into()), None), Err(_));
+        assert_matches!(
+            parse_temperature_gate(Some("5,40".into()), Some("yes".into())),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn test_woken_grace() {
+        let context = Context::load().unwrap();
+        let interval = chrono::Duration::from_std(context.wake_interval).unwrap();
+        assert!(context.woken_grace >= interval * 2);
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        for (heartbeats_ago, woken) in [(0, true), (1, true), (2, true), (3, false)] {
+            // woken shortly after the heartbeat
+            let woken_at = Utc::now() - interval * heartbeats_ago + chrono::Duration::seconds(1);
+            context.just_woke.lock().unwrap().insert(mac, woken_at);
+            assert_eq!(
+                context.woken_in_previous_heartbeat(&mac),
+                woken,
+                "woken {} heartbeats ago should be reported as woken: {}",
+                heartbeats_ago,
+                woken
+            );
+        }
+    }
+
+    #[test]
+    fn test_post_wake_cooldown() {
+        let mut context = Context::load().unwrap();
+        context.post_wake_cooldown = chrono::Duration::seconds(120);
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        assert!(!context.in_wake_cooldown(&mac));
+        context.just_woke(HashSet::from([mac]));
+        assert!(
+            context.in_wake_cooldown(&mac),
+            "should cool down after waking"
+        );
+        context
+            .just_woke
+            .lock()
+            .unwrap()
+            .insert(mac, Utc::now() - chrono::Duration::seconds(121));
+        assert!(
+            !context.in_wake_cooldown(&mac),
+            "should be eligible after the cooldown"
+        );
+    }
+
+    #[test]
+    fn test_next_heartbeat() {
+        let context = Context::load().unwrap();
+        assert!(context.next_heartbeat().is_none(), "should not tick yet");
+        let start = tokio::time::Instant::now();
+        context.record_tick(start);
+        let next = context.next_heartbeat().unwrap();
+        let interval = chrono::Duration::from_std(context.wake_interval).unwrap();
+        assert!(next <= Utc::now() + interval);
+        assert!(next > Utc::now() + interval - chrono::Duration::seconds(1));
+        context.record_tick(start + context.wake_interval);
+        assert!(
+            context.next_heartbeat().unwrap() >= next + interval - chrono::Duration::seconds(1),
+            "should advance by a wake interval with the next tick"
+        );
+    }
+
+    #[test]
+    fn test_prune_woken() {
+        let context = Context::load().unwrap();
+        let stale: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let recent: MacAddress = "11:22:33:44:55:77".parse().unwrap();
+        context.just_woke(HashSet::from([recent]));
+        context.just_woke.lock().unwrap().insert(
+            stale,
+            Utc::now() - context.woken_grace - chrono::Duration::seconds(1),
+        );
+        assert!(!context.woken_in_previous_heartbeat(&stale));
+        assert_eq!(context.prune_woken(), 1, "should prune stale entry");
+        assert!(!context.just_woke.lock().unwrap().contains_key(&stale));
+        assert!(
+            context.woken_in_previous_heartbeat(&recent),
+            "should keep recent entry"
+        );
+    }
+
+    #[test]
+    fn test_parse_wake_interval() {
+        assert_matches!(
+            parse_wake_interval("0"),
+            Err(e) if e.contains("out of range"),
+            "should reject busy-loop interval"
+        );
+        assert_matches!(
+            parse_wake_interval(&(MAX_WAKE_INTERVAL_SECONDS + 1).to_string()),
+            Err(e) if e.contains("out of range")
+        );
+        assert_matches!(parse_wake_interval("abc"), Err(_));
+        assert_eq!(
+            parse_wake_interval("300").unwrap(),
+            std::time::Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn test_parse_field_map() {
+        assert_eq!(
+            parse_field_map("pv_current=solar_current, battery_voltage=batt_v").unwrap(),
+            HashMap::from([
+                ("pv_current".to_string(), "solar_current".to_string()),
+                ("battery_voltage".to_string(), "batt_v".to_string())
+            ])
+        );
+        assert!(parse_field_map("").unwrap().is_empty());
+        assert_matches!(parse_field_map("pv_current"), Err(_));
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(
+            parse_schedule("02:00, 23:45").unwrap(),
+            vec![
+                NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(23, 45, 0).unwrap()
+            ]
+        );
+        assert_eq!(parse_schedule("").unwrap(), vec![]);
+        assert_matches!(parse_schedule("25:00"), Err(_));
+    }
+
+    #[test]
+    fn test_privileged_port_warning() {
+        let privileged = "0.0.0.0:80".parse().unwrap();
+        let unprivileged = "0.0.0.0:3000".parse().unwrap();
+        assert!(may_bind_privileged(ROOT_STATUS));
+        assert!(may_bind_privileged(CAP_STATUS));
+        assert!(!may_bind_privileged(USER_STATUS));
+        assert_matches!(
+            privileged_port_warning(&privileged, Some(USER_STATUS)),
+            Some(w) if w.contains("CAP_NET_BIND_SERVICE"),
+            "should warn about privileged port without capability"
+        );
+        assert_matches!(
+            privileged_port_warning(&privileged, Some(ROOT_STATUS)),
+            None
+        );
+        assert_matches!(privileged_port_warning(&privileged, Some(CAP_STATUS)), None);
+        assert_matches!(
+            privileged_port_warning(&unprivileged, Some(USER_STATUS)),
+            None
+        );
+        assert_matches!(
+            privileged_port_warning(&privileged, None),
+            None,
+            "should not warn if capabilities are unknown"
+        );
+    }
+}
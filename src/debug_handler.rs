@@ -0,0 +1,153 @@
+use crate::context::Context;
+use crate::errors::ApiError;
+use crate::server::RequestHandler;
+use crate::wake_heartbeat::HeartbeatRes;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    config: serde_json::Value,
+    // woken macs with the time of waking
+    just_woke: HashMap<String, DateTime<Utc>>,
+    // consecutive wake attempts per mac
+    wake_backoff: HashMap<String, u32>,
+    // excess of the last heartbeat
+    excess: Option<&'static str>,
+    last_heartbeat: Option<HeartbeatRes>,
+    // next tick of the heartbeat timer
+    next_heartbeat: Option<DateTime<Utc>>,
+}
+
+fn config_snapshot(context: &Context) -> serde_json::Value {
+    // effective config (without the status store credentials)
+    json!({
+        "wake_interval_seconds": context.wake_interval.as_secs(),
+        "wake_interval_enabled": context.wake_interval_enabled,
+        "heartbeat_mode": format!("{:?}", context.heartbeat_mode),
+        "excess_enabled": context.excess_enabled,
+        "location": context.location.map(|l| [l.latitude, l.longitude]),
+        "trust_status_for_wake": context.trust_status_for_wake,
+        "ping_concurrency": context.ping_limits.concurrency,
+        "ping_jitter_ms": context.ping_limits.jitter.as_millis() as u64,
+        "awake_tcp_port": context.awake_tcp_port,
+        "arp_selection": format!("{:?}", context.arp_selection),
+        "log_excess_inputs": context.log_excess_inputs,
+        "write_liveness": context.write_liveness,
+        "scheduled_wakes": context.scheduled_wakes,
+        "scheduled_wake_macs": context.scheduled_wake_macs,
+        "candidate_max_age_seconds": context.candidate_max_age.map(|d| d.num_seconds()),
+        "interval_measurements": context.interval_measurements,
+        "partial_history": context.partial_history,
+        "debug_upstream_errors": context.debug_upstream_errors,
+        "response_timezone": context.response_timezone.name(),
+        "excess_cache_seconds": context.excess_cache_seconds,
+        "excess_smooth_cycles": context.excess_smooth_cycles,
+        "wake_history_window_seconds": context.wake_history_window.num_seconds(),
+        "accept_chunked": context.accept_chunked,
+        "admin_token": context.admin_token.as_ref().map(|_| REDACTED),
+        "mac_cache_mode": format!("{:?}", context.mac_cache_mode),
+        "mac_cache_ttl_seconds": context.mac_cache_ttl.num_seconds(),
+        "woken_grace_seconds": context.woken_grace.num_seconds(),
+        "post_wake_cooldown_seconds": context.post_wake_cooldown.num_seconds(),
+        "active_window_seconds": context.active_window.num_seconds(),
+        "wake_spread_cycles": context.wake_spread_cycles,
+        "local_addrs": context
+            .local_addrs
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn snapshot(context: &Context) -> Snapshot {
+    let last_heartbeat = context.last_heartbeat();
+    Snapshot {
+        config: config_snapshot(context),
+        just_woke: context
+            .woken_macs()
+            .into_iter()
+            .map(|(m, t)| (m.to_string(), t))
+            .collect(),
+        wake_backoff: context
+            .wake_attempts()
+            .into_iter()
+            .map(|(m, a)| (m.to_string(), a))
+            .collect(),
+        excess: last_heartbeat
+            .as_ref()
+            .map(|h| h.excess.name(context.excess_json_lowercase)),
+        last_heartbeat: last_heartbeat
+            .as_ref()
+            .map(|h| HeartbeatRes::new(h, context.excess_json_lowercase)),
+        next_heartbeat: context.next_heartbeat(),
+    }
+}
+
+// public subset of the snapshot (no admin token required)
+#[derive(Serialize)]
+pub struct StatusRes {
+    next_heartbeat: Option<DateTime<Utc>>,
+    wake_interval_seconds: u64,
+}
+
+pub struct StatusRequestHandler {}
+
+#[async_trait]
+impl RequestHandler<(), StatusRes> for StatusRequestHandler {
+    async fn handle(&self, _req: (), context: Context) -> Result<StatusRes, ApiError> {
+        Ok(StatusRes {
+            next_heartbeat: context.next_heartbeat(),
+            wake_interval_seconds: context.wake_interval.as_secs(),
+        })
+    }
+}
+
+pub struct SnapshotRequestHandler {}
+
+#[async_trait]
+impl RequestHandler<(), Snapshot> for SnapshotRequestHandler {
+    async fn handle(&self, _req: (), context: Context) -> Result<Snapshot, ApiError> {
+        Ok(snapshot(&context))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mac_address::MacAddress;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_snapshot() {
+        let mut context = Context::load().unwrap();
+        context.admin_token = Some("s3cr3t".into());
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        context.just_woke(HashSet::from([mac]));
+        context.record_wake_attempts(&HashSet::from([mac]));
+
+        let json = serde_json::to_value(snapshot(&context)).unwrap();
+        for key in [
+            "config",
+            "just_woke",
+            "wake_backoff",
+            "excess",
+            "last_heartbeat",
+            "next_heartbeat",
+        ] {
+            assert!(json.get(key).is_some(), "should include '{}'", key);
+        }
+        assert_eq!(json["wake_backoff"]["11:22:33:44:55:66"], 1);
+        assert!(json["just_woke"].get("11:22:33:44:55:66").is_some());
+        assert_eq!(json["config"]["admin_token"], REDACTED);
+        assert!(
+            !json.to_string().contains("s3cr3t"),
+            "should redact secrets"
+        );
+    }
+}
@@ -1,22 +1,279 @@
 use crate::context::Context;
 use crate::errors::ApiError;
-use crate::influx_gateway::{query_pv_excess, ExcessStatus};
-use crate::server::RequestHandler;
-use crate::fwd_err;
+use crate::influx_gateway::{ExcessDetail, ExcessReport, ExcessStatus};
+use crate::server::{query_param, RequestHandler};
+use crate::{api_baderr, api_err, fwd_err};
 use async_trait::async_trait;
+use serde::Serialize;
+
+// status strings are lowercase with EXCESS_JSON_LOWERCASE
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum ExcessRes {
+    Status(&'static str),
+    // excess is at least the requested minimum
+    AtLeast(bool),
+    // excess with the inputs and thresholds of the decision
+    Report(ExcessReportRes),
+    // excess with the means of the decision ('?detail=1')
+    Detail(ExcessDetailRes),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExcessReportRes {
+    excess: &'static str,
+    mean_current: Option<f32>,
+    sun_level: usize,
+    mean_voltage: Option<f32>,
+    mean_soc: Option<f32>,
+    maybe_threshold: Option<f32>,
+    yes_threshold: Option<f32>,
+    voltage_trend: Option<f32>,
+    mean_temperature: Option<f32>,
+}
+
+impl ExcessReportRes {
+    fn new(report: ExcessReport, lowercase: bool) -> Self {
+        ExcessReportRes {
+            excess: report.excess.name(lowercase),
+            mean_current: report.mean_current,
+            sun_level: report.sun_level,
+            mean_voltage: report.mean_voltage,
+            mean_soc: report.mean_soc,
+            maybe_threshold: report.maybe_threshold,
+            yes_threshold: report.yes_threshold,
+            voltage_trend: report.voltage_trend,
+            mean_temperature: report.mean_temperature,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExcessDetailRes {
+    status: &'static str,
+    mean_current: Option<f32>,
+    mean_voltage: Option<f32>,
+    sun_level: usize,
+}
+
+impl ExcessDetailRes {
+    fn new(detail: ExcessDetail, lowercase: bool) -> Self {
+        ExcessDetailRes {
+            status: detail.status.name(lowercase),
+            mean_current: detail.mean_current,
+            mean_voltage: detail.mean_voltage,
+            sun_level: detail.sun_level,
+        }
+    }
+}
+
+fn parse_min(query_str: &str) -> Result<Option<ExcessStatus>, ApiError> {
+    // '?min=maybe|yes'
+    match query_param(query_str, "min") {
+        Some(v) => match v.to_lowercase().as_str() {
+            "no" => Ok(Some(ExcessStatus::No)),
+            "maybe" => Ok(Some(ExcessStatus::Maybe)),
+            "yes" => Ok(Some(ExcessStatus::Yes)),
+            _ => Err(api_baderr!("Invalid min excess '{}'! (maybe|yes)", v)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn excess_response(excess: ExcessStatus, min: Option<ExcessStatus>, lowercase: bool) -> ExcessRes {
+    match min {
+        Some(m) => ExcessRes::AtLeast(excess as u8 >= m as u8),
+        None => ExcessRes::Status(excess.name(lowercase)),
+    }
+}
 
 pub struct ExcessRequestHandler {}
 
 #[async_trait]
-impl RequestHandler<String, ExcessStatus> for ExcessRequestHandler {
-    async fn handle(&self, _query_str: String, context: Context) -> Result<ExcessStatus, ApiError> {
-        Ok(query_pv_excess(&context.influx_client)
+impl RequestHandler<String, ExcessRes> for ExcessRequestHandler {
+    async fn handle(&self, query_str: String, context: Context) -> Result<ExcessRes, ApiError> {
+        if !context.excess_enabled {
+            return Err(api_err!(
+                hyper::StatusCode::NOT_FOUND,
+                "Excess query is disabled!"
+            ));
+        }
+        let min = parse_min(&query_str)?;
+        if min.is_none() && query_param(&query_str, "detail").as_deref() == Some("1") {
+            let detail = context
+                .status_store
+                .excess_detail()
+                .await
+                .map_err(|e| fwd_err!("Failed to query pv excess! {}", e))?;
+            return Ok(ExcessRes::Detail(ExcessDetailRes::new(
+                detail,
+                context.excess_json_lowercase,
+            )));
+        }
+        if context.excess_details && min.is_none() {
+            let report = context
+                .status_store
+                .excess_report()
+                .await
+                .map_err(|e| fwd_err!("Failed to query pv excess! {}", e))?;
+            return Ok(ExcessRes::Report(ExcessReportRes::new(
+                report,
+                context.excess_json_lowercase,
+            )));
+        }
+        let excess = context
+            .status_store
+            .pv_excess()
             .await
-            .map_err(|e| fwd_err!("Failed to query pv excess! {}", e))?)
+            .map_err(|e| fwd_err!("Failed to query pv excess! {}", e))?;
+        // combined with the recent heartbeat decisions (EXCESS_SMOOTH_CYCLES)
+        let excess = context.peek_smoothed_excess(&excess);
+        Ok(excess_response(excess, min, context.excess_json_lowercase))
     }
 }
 
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use super::*;
+    use crate::influx_gateway::test::InfluxClientMock;
+    use crate::status_store::test::MemoryStore;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_min_excess() {
+        assert_matches!(parse_min(""), Ok(None));
+        assert_matches!(parse_min("min=Maybe"), Ok(Some(ExcessStatus::Maybe)));
+        assert_matches!(parse_min("x=1&min=yes"), Ok(Some(ExcessStatus::Yes)));
+        assert_matches!(parse_min("min=lots"), Err(_));
+        for (min, expected) in [
+            (ExcessStatus::Maybe, [false, true, true]),
+            (ExcessStatus::Yes, [false, false, true]),
+        ] {
+            for (excess, at_least) in [ExcessStatus::No, ExcessStatus::Maybe, ExcessStatus::Yes]
+                .into_iter()
+                .zip(expected)
+            {
+                assert_matches!(
+                    excess_response(excess.clone(), Some(min.clone()), false),
+                    ExcessRes::AtLeast(b) if b == at_least,
+                    "{:?} should be at least {:?}: {}", excess, min, at_least
+                );
+            }
+        }
+        assert_eq!(
+            serde_json::to_string(&excess_response(ExcessStatus::Yes, None, false)).unwrap(),
+            r#""Yes""#,
+            "should return the full status by default"
+        );
+        assert_eq!(
+            serde_json::to_string(&excess_response(ExcessStatus::Yes, None, true)).unwrap(),
+            r#""yes""#,
+            "should return the lowercase status with EXCESS_JSON_LOWERCASE"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_details() {
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{:.2}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m"
+                        .into(),
+                    mean_resp(30.0),
+                ),
+                (
+                    "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m"
+                        .into(),
+                    mean_resp(12.8),
+                ),
+            ]),
+            ..Default::default()
+        });
+        assert_matches!(
+            ExcessRequestHandler {}
+                .handle("".into(), context.clone())
+                .await,
+            Ok(ExcessRes::Status("Maybe")),
+            "should respond with the status by default"
+        );
+        context.excess_details = true;
+        let res = ExcessRequestHandler {}
+            .handle("".into(), context.clone())
+            .await
+            .unwrap();
+        let json = serde_json::to_value(&res).unwrap();
+        assert_eq!(json["excess"], "Maybe");
+        assert_eq!(json["sun_level"], 2);
+        assert_eq!(
+            (
+                json["maybe_threshold"].as_f64(),
+                json["yes_threshold"].as_f64()
+            ),
+            (Some(12.5f32 as f64), Some(13.0f32 as f64)),
+            "should include the thresholds of the sun level"
+        );
+        assert_matches!(
+            ExcessRequestHandler {}
+                .handle("min=maybe".into(), context.clone())
+                .await,
+            Ok(ExcessRes::AtLeast(true)),
+            "should not change min responses"
+        );
+        context.excess_details = false;
+        let res = ExcessRequestHandler {}
+            .handle("detail=1".into(), context)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            serde_json::json!({
+                "status": "Maybe",
+                "mean_current": 30.0,
+                "mean_voltage": 12.8f32,
+                "sun_level": 2
+            }),
+            "should respond with the means of the decision"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_smoothing() {
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::Yes));
+        context.excess_smooth_cycles = 3;
+        for excess in [ExcessStatus::Yes, ExcessStatus::No, ExcessStatus::No] {
+            context.smooth_excess(excess);
+        }
+        assert_matches!(
+            ExcessRequestHandler {}
+                .handle("".into(), context.clone())
+                .await,
+            Ok(ExcessRes::Status("No")),
+            "should respond with the most common of the recent decisions"
+        );
+        context.smooth_excess(ExcessStatus::Yes);
+        assert_matches!(
+            ExcessRequestHandler {}.handle("".into(), context).await,
+            Ok(ExcessRes::Status("Yes")),
+            "should follow once the excess is common"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_disabled() {
+        let mut context = Context::load().unwrap();
+        context.excess_enabled = false;
+        assert_matches!(
+            ExcessRequestHandler {}.handle("".into(), context).await,
+            Err(e) if e.code == hyper::StatusCode::NOT_FOUND,
+            "should respond with not found if excess is disabled"
+        );
+    }
 }
@@ -0,0 +1,80 @@
+use crate::influx_gateway::ExcessStatus;
+use std::collections::VecDeque;
+
+// effective excess as the mode of the recent heartbeat decisions
+#[derive(Debug, Default)]
+pub struct ExcessSmoother {
+    recent: VecDeque<ExcessStatus>,
+}
+
+impl ExcessSmoother {
+    // record the decision of a heartbeat and return the smoothed excess
+    pub fn push(&mut self, excess: ExcessStatus, cycles: usize) -> ExcessStatus {
+        self.recent.push_back(excess);
+        while self.recent.len() > cycles.max(1) {
+            self.recent.pop_front();
+        }
+        mode(self.recent.iter())
+    }
+
+    // smoothed excess if the decision was recorded now (without recording it)
+    pub fn peek(&self, excess: &ExcessStatus, cycles: usize) -> ExcessStatus {
+        let skip = (self.recent.len() + 1).saturating_sub(cycles.max(1));
+        mode(self.recent.iter().skip(skip).chain(std::iter::once(excess)))
+    }
+}
+
+fn mode<'a>(decisions: impl Iterator<Item = &'a ExcessStatus>) -> ExcessStatus {
+    let mut counts = [0usize; 3];
+    for d in decisions {
+        counts[d.clone() as usize] += 1;
+    }
+    // ties prefer the lower excess
+    [ExcessStatus::No, ExcessStatus::Maybe, ExcessStatus::Yes]
+        .into_iter()
+        .zip(counts)
+        .rev()
+        .max_by_key(|(_, n)| *n)
+        .map(|(s, _)| s)
+        .unwrap_or(ExcessStatus::No)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_excess_smoother() {
+        use ExcessStatus::*;
+        let mut smoother = ExcessSmoother::default();
+        let noisy = [Yes, No, Yes, Yes, Maybe, Yes, No, No, Maybe, No];
+        let smoothed: Vec<u8> = noisy
+            .into_iter()
+            .map(|e| smoother.push(e, 3) as u8)
+            .collect();
+        assert_eq!(
+            smoothed,
+            vec![2, 0, 2, 2, 2, 2, 0, 0, 0, 0],
+            "should respond with the most common of the last 3 decisions"
+        );
+        assert_matches!(
+            smoother.peek(&Yes, 3),
+            No,
+            "should combine the last 2 decisions with the given one"
+        );
+        assert_eq!(
+            smoother.recent.len(),
+            3,
+            "should not record peeked decisions"
+        );
+
+        let mut unsmoothed = ExcessSmoother::default();
+        for e in [Yes, No, Maybe] {
+            assert_eq!(
+                unsmoothed.push(e.clone(), 1) as u8,
+                e as u8,
+                "should keep the decision with a single cycle"
+            );
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::influx_gateway::ExcessStatus;
+use anyhow::{anyhow, bail, Result};
+use std::time::Duration;
+
+// do not delay the heartbeat by a slow inverter api
+pub const EXCESS_SOURCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn parse_excess_source(
+    source: Option<String>,
+    url: Option<String>,
+) -> std::result::Result<Option<String>, String> {
+    // url of the external excess (None for influxdb)
+    match source.as_deref().map(|s| s.to_lowercase()).as_deref() {
+        None | Some("influx") => Ok(None),
+        Some("http") => url
+            .filter(|u| !u.is_empty())
+            .map(Some)
+            .ok_or_else(|| "Invalid excess source config! (EXCESS_SOURCE_URL required)".into()),
+        Some(s) => Err(format!("Invalid excess source '{}'! (influx|http)", s)),
+    }
+}
+
+fn parse_excess(body: &str) -> Option<ExcessStatus> {
+    // 0/1/2, No/Maybe/Yes, true/false or a json object with 'excess'
+    let value: serde_json::Value = serde_json::from_str(body.trim())
+        .unwrap_or_else(|_| serde_json::Value::String(body.trim().to_string()));
+    let value = value.get("excess").cloned().unwrap_or(value);
+    let level = match value {
+        serde_json::Value::Bool(b) => Some(if b { 2 } else { 0 }),
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => match s.to_lowercase().as_str() {
+            "0" | "no" => Some(0),
+            "1" | "maybe" => Some(1),
+            "2" | "yes" => Some(2),
+            _ => None,
+        },
+        _ => None,
+    };
+    match level? {
+        0 => Some(ExcessStatus::No),
+        1 => Some(ExcessStatus::Maybe),
+        2 => Some(ExcessStatus::Yes),
+        _ => None,
+    }
+}
+
+async fn request_excess(url: &str, timeout: Duration) -> Result<ExcessStatus> {
+    let uri: hyper::Uri = url.parse()?;
+    let resp = tokio::time::timeout(timeout, hyper::Client::new().get(uri))
+        .await
+        .map_err(|_| anyhow!("'{}' timed out after {:?}", url, timeout))??;
+    if !resp.status().is_success() {
+        bail!("'{}' responded with {}", url, resp.status());
+    }
+    let body = tokio::time::timeout(timeout, hyper::body::to_bytes(resp.into_body()))
+        .await
+        .map_err(|_| anyhow!("'{}' timed out after {:?}", url, timeout))??;
+    let body = String::from_utf8_lossy(&body);
+    parse_excess(&body).ok_or_else(|| anyhow!("'{}' responded with unknown excess '{}'", url, body))
+}
+
+pub async fn fetch_excess(url: &str, timeout: Duration) -> ExcessStatus {
+    // no excess if the source fails (never wake by mistake)
+    match request_excess(url, timeout).await {
+        Ok(excess) => excess,
+        Err(e) => {
+            warn!("Failed to fetch the excess! {}", e);
+            ExcessStatus::No
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::influx_gateway::query_pv_excess;
+    use crate::influx_gateway::test::InfluxClientMock;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+
+    // local http server which responds with the path (e.g. '/2')
+    fn excess_server() -> String {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let (status, body) = match req.uri().path() {
+                    "/json" => (StatusCode::OK, r#"{"excess": 2}"#.to_string()),
+                    "/error" => (StatusCode::INTERNAL_SERVER_ERROR, "".to_string()),
+                    p => (StatusCode::OK, p.trim_start_matches('/').to_string()),
+                };
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn test_fetch_excess() {
+        let url = excess_server();
+        for (path, expected) in [
+            ("0", ExcessStatus::No),
+            ("1", ExcessStatus::Maybe),
+            ("2", ExcessStatus::Yes),
+            ("Maybe", ExcessStatus::Maybe),
+            ("json", ExcessStatus::Yes),
+            ("true", ExcessStatus::Yes),
+        ] {
+            assert_eq!(
+                fetch_excess(&format!("{}/{}", url, path), EXCESS_SOURCE_TIMEOUT).await as u8,
+                expected as u8,
+                "should map '{}'",
+                path
+            );
+        }
+        for path in ["3", "error"] {
+            assert_matches!(
+                fetch_excess(&format!("{}/{}", url, path), EXCESS_SOURCE_TIMEOUT).await,
+                ExcessStatus::No,
+                "should fall back to no excess for '{}'",
+                path
+            );
+        }
+        assert_matches!(
+            fetch_excess("http://127.0.0.1:1/excess", EXCESS_SOURCE_TIMEOUT).await,
+            ExcessStatus::No,
+            "should fall back to no excess if unreachable"
+        );
+
+        let client = InfluxClientMock {
+            excess_url: Some(format!("{}/2", url)),
+            ..Default::default()
+        };
+        assert_matches!(
+            query_pv_excess(&client).await,
+            Ok(ExcessStatus::Yes),
+            "should use the external source instead of influxdb"
+        );
+    }
+
+    #[test]
+    fn test_parse_excess_source() {
+        assert_eq!(parse_excess_source(None, None), Ok(None));
+        assert_eq!(
+            parse_excess_source(Some("HTTP".into()), Some("http://inverter/surplus".into())),
+            Ok(Some("http://inverter/surplus".into()))
+        );
+        assert_matches!(parse_excess_source(Some("http".into()), None), Err(_));
+        assert_matches!(parse_excess_source(Some("mqtt".into()), None), Err(_));
+    }
+}
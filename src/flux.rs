@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+// Flux scripts for InfluxDB 2.x (or 1.8+ with flux-enabled)
+
+pub fn mean_script(bucket: &str, measurement: &str, field: &str, duration: &str) -> String {
+    mean_script_min(bucket, measurement, field, duration, None)
+}
+
+// mean of the values which are at least min_value
+pub fn mean_script_min(
+    bucket: &str,
+    measurement: &str,
+    field: &str,
+    duration: &str,
+    min_value: Option<f32>,
+) -> String {
+    mean_script_window(bucket, measurement, field, duration, None, min_value)
+}
+
+// mean from now - duration until now - until (or now)
+pub fn mean_script_window(
+    bucket: &str,
+    measurement: &str,
+    field: &str,
+    duration: &str,
+    until: Option<&str>,
+    min_value: Option<f32>,
+) -> String {
+    format!(
+        r#"from(bucket: "{}")
+  |> range(start: -{}{})
+  |> filter(fn: (r) => r._measurement == "{}" and r._field == "{}"{})
+  |> group()
+  |> reduce(
+    fn: (r, accumulator) => ({count: accumulator.count + 1, sum: accumulator.sum + float(v: r._value)}),
+    identity: {count: 0, sum: 0.0},
+  )
+  |> map(fn: (r) => ({count: r.count, _value: r.sum / float(v: r.count)}))"#,
+        bucket,
+        duration,
+        until.map(|u| format!(", stop: -{}", u)).unwrap_or_default(),
+        measurement,
+        field,
+        min_value
+            .map(|v| format!(" and r._value >= {:?}", v))
+            .unwrap_or_default()
+    )
+}
+
+pub fn last_status_script(bucket: &str, measurement: &str) -> String {
+    format!(
+        r#"from(bucket: "{}")
+  |> range(start: 0)
+  |> filter(fn: (r) => r._measurement == "{}" and (r._field == "status" or r._field == "wake"))
+  |> last()
+  |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
+  |> keep(columns: ["_time", "mac", "status", "wake"])"#,
+        bucket, measurement
+    )
+}
+
+pub fn last_seen_script(bucket: &str, measurement: &str, min_status: i32) -> String {
+    format!(
+        r#"from(bucket: "{}")
+  |> range(start: 0)
+  |> filter(fn: (r) => r._measurement == "{}" and r._field == "status" and r._value >= {})
+  |> last()
+  |> keep(columns: ["_time", "mac"])"#,
+        bucket, measurement, min_status
+    )
+}
+
+pub fn history_script(
+    bucket: &str,
+    range: (&str, &str),
+    measurement: &str,
+    fields: &[&str],
+    mac: Option<String>,
+) -> String {
+    let field_filter = if fields.is_empty() {
+        String::new()
+    } else {
+        format!(
+            " and ({})",
+            fields
+                .iter()
+                .map(|f| format!("r._field == \"{}\"", f))
+                .collect::<Vec<String>>()
+                .join(" or ")
+        )
+    };
+    let mac_filter = mac
+        .map(|m| format!(" and r.mac == \"{}\"", m))
+        .unwrap_or_default();
+    format!(
+        r#"from(bucket: "{}")
+  |> range(start: {}, stop: {})
+  |> filter(fn: (r) => r._measurement == "{}"{}{})
+  |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
+  |> yield(name: "{}")"#,
+        bucket, range.0, range.1, measurement, field_filter, mac_filter, measurement
+    )
+}
+
+pub fn parse_csv(csv: &str) -> Vec<HashMap<String, String>> {
+    // rows of (annotated) CSV tables separated by empty lines
+    let mut rows = vec![];
+    let mut header: Option<Vec<String>> = None;
+    for line in csv.lines().map(|l| l.trim_end_matches('\r')) {
+        if line.is_empty() {
+            header = None;
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(columns) = &header {
+            rows.push(
+                columns
+                    .iter()
+                    .cloned()
+                    .zip(line.split(',').map(|v| v.to_string()))
+                    .collect(),
+            );
+        } else {
+            header = Some(line.split(',').map(|c| c.to_string()).collect());
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mean_script() {
+        assert_eq!(
+            mean_script("pv", "pvstatus", "pv_current", "30m"),
+            r#"from(bucket: "pv")
+  |> range(start: -30m)
+  |> filter(fn: (r) => r._measurement == "pvstatus" and r._field == "pv_current")
+  |> group()
+  |> reduce(
+    fn: (r, accumulator) => ({count: accumulator.count + 1, sum: accumulator.sum + float(v: r._value)}),
+    identity: {count: 0, sum: 0.0},
+  )
+  |> map(fn: (r) => ({count: r.count, _value: r.sum / float(v: r.count)}))"#
+        );
+    }
+
+    #[test]
+    fn test_mean_script_min() {
+        assert!(mean_script_min("pv", "pvstatus", "pv_current", "30m", Some(0.0)).contains(
+            r#"filter(fn: (r) => r._measurement == "pvstatus" and r._field == "pv_current" and r._value >= 0.0)"#
+        ));
+    }
+
+    #[test]
+    fn test_mean_script_window() {
+        assert!(
+            mean_script_window("pv", "pvstatus", "battery_voltage", "15m", Some("5m"), None)
+                .contains("range(start: -15m, stop: -5m)")
+        );
+    }
+
+    #[test]
+    fn test_last_status_script() {
+        assert_eq!(
+            last_status_script("pv", "workerstatus"),
+            r#"from(bucket: "pv")
+  |> range(start: 0)
+  |> filter(fn: (r) => r._measurement == "workerstatus" and (r._field == "status" or r._field == "wake"))
+  |> last()
+  |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
+  |> keep(columns: ["_time", "mac", "status", "wake"])"#
+        );
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = "#datatype,string,long,dateTime:RFC3339,string,long,boolean\r\n\
+            ,result,table,_time,mac,status,wake\r\n\
+            ,_result,0,2022-01-10T12:00:00Z,11:22:33:44:55:66,0,true\r\n\
+            \r\n\
+            ,result,table,_time,mac,status,wake\r\n\
+            ,_result,1,2022-01-10T12:05:00Z,11:22:33:44:55:77,3,false\r\n";
+        let rows = parse_csv(csv);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["mac"], "11:22:33:44:55:66");
+        assert_eq!(rows[0]["wake"], "true");
+        assert_eq!(rows[1]["_time"], "2022-01-10T12:05:00Z");
+        assert_eq!(rows[1]["status"], "3");
+    }
+}
@@ -1,13 +1,21 @@
 use crate::context::InfluxClient;
+use crate::excess_source::{fetch_excess, EXCESS_SOURCE_TIMEOUT};
+use crate::flux;
 use crate::interval_handler::IntervalReq;
+use crate::metrics;
 use async_trait::async_trait;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Datelike, Duration, Local, Utc, Weekday};
+use chrono_tz::Tz;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
 use influxdb::{
     integrations::serde_integration::DatabaseQueryResult, InfluxDbWriteable, Query, ReadQuery,
+    WriteQuery,
 };
 use mac_address::MacAddress;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
 
 #[derive(Debug, InfluxDbWriteable)]
 pub struct WorkerStatusEntry {
@@ -18,26 +26,152 @@ pub struct WorkerStatusEntry {
     wake: bool,
 }
 
+// informant is alive (written every heartbeat with WRITE_LIVENESS)
+#[derive(Debug, InfluxDbWriteable)]
+struct LivenessEntry {
+    #[influxdb(tag)]
+    informant: String,
+    time: DateTime<Utc>,
+    cycle: i64,
+}
+
+// excess decision of a heartbeat (joined into histories by annotate_excess)
+#[derive(Debug, InfluxDbWriteable)]
+struct ExcessEntry {
+    #[influxdb(tag)]
+    informant: String,
+    time: DateTime<Utc>,
+    excess: i64,
+}
+
 #[async_trait]
 pub trait QueryClient {
     async fn json_query(&self, query: ReadQuery) -> Result<DatabaseQueryResult, influxdb::Error>;
     async fn query<Q>(&self, query: Q) -> Result<String, influxdb::Error>
     where
-        Q: Query + Send;
+        Q: Query + Clone + Send;
+    async fn flux_query(&self, script: String) -> Result<String, influxdb::Error>;
+    async fn ping(&self) -> Result<(), influxdb::Error>;
+    fn query_language(&self) -> QueryLanguage;
+    fn bucket(&self) -> &str;
     fn workerstatus(&self) -> &str;
     fn pvstatus(&self) -> &str;
+    fn min_samples(&self) -> u32;
+    // number of workerstatus measurements (sharded by mac)
+    fn worker_shards(&self) -> u32;
+    // physical name of a logical field
+    fn field<'a>(&'a self, logical: &'a str) -> &'a str;
+    fn weekday_thresholds(&self) -> &ExcessThresholds;
+    // thresholds on saturday and sunday (weekday thresholds if None)
+    fn weekend_thresholds(&self) -> Option<&ExcessThresholds>;
+    // measurement and (logical) field of the sun level signal
+    fn sun_measurement(&self) -> &str;
+    fn sun_field(&self) -> &str;
+    // multiplier of battery_voltage to volts (e.g. 0.001 for millivolts)
+    fn voltage_scale(&self) -> f32;
+    fn excess_signal(&self) -> &ExcessSignal;
+    // (logical) fields of the battery voltage and their aggregation
+    fn voltage_fields(&self) -> Vec<&str>;
+    fn voltage_aggregation(&self) -> Aggregation;
+    fn temperature_gate(&self) -> Option<&TemperatureGate>;
+    // statuses of long-running workers which are never stale
+    fn never_stale(&self) -> &[i32];
+    // ignore negative pv_current in the excess query
+    fn clamp_negative_current(&self) -> bool;
+    // excess Yes only with rising battery_voltage
+    fn require_charging(&self) -> bool;
+    // fetch the excess from this url instead (EXCESS_SOURCE=http)
+    fn excess_url(&self) -> Option<&str>;
+}
+
+// first delay between retries (doubled after each retry)
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+fn is_transient(e: &influxdb::Error) -> bool {
+    // no retry of invalid queries, database or deserialization errors
+    matches!(e, influxdb::Error::ConnectionError { .. })
+}
+
+pub async fn with_retry<T, F, Fut>(
+    retries: u32,
+    base_delay: std::time::Duration,
+    mut op: F,
+) -> Result<T, influxdb::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, influxdb::Error>>,
+{
+    // retry connection errors (e.g. a flaky link) with exponential backoff
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = base_delay.saturating_mul(2u32.saturating_pow(attempt));
+                debug!("retrying influxdb query in {:?}! {}", delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
 }
 
 #[async_trait]
 impl QueryClient for InfluxClient {
     async fn json_query(&self, query: ReadQuery) -> Result<DatabaseQueryResult, influxdb::Error> {
-        self.client.json_query(query).await
+        with_retry(self.retries, RETRY_BASE_DELAY, move || {
+            self.client.json_query(query.clone())
+        })
+        .await
     }
     async fn query<Q>(&self, q: Q) -> Result<String, influxdb::Error>
     where
-        Q: Query + Send,
+        Q: Query + Clone + Send,
     {
-        self.client.query(q).await
+        // the query is moved into the closure (Q is not Sync)
+        with_retry(self.retries, RETRY_BASE_DELAY, move || {
+            self.client.query(q.clone())
+        })
+        .await
+    }
+    async fn flux_query(&self, script: String) -> Result<String, influxdb::Error> {
+        let mut req = hyper::Request::post(format!("{}/api/v2/query", self.client.database_url()))
+            .header(CONTENT_TYPE, "application/vnd.flux")
+            .header(ACCEPT, "application/csv");
+        if let Some(token) = &self.flux_token {
+            req = req.header(AUTHORIZATION, format!("Token {}", token));
+        }
+        let req = req.body(hyper::Body::from(script)).map_err(|e| {
+            influxdb::Error::InvalidQueryError {
+                error: e.to_string(),
+            }
+        })?;
+        let connection_error = |e: hyper::Error| influxdb::Error::ConnectionError {
+            error: e.to_string(),
+        };
+        let resp = hyper::Client::new()
+            .request(req)
+            .await
+            .map_err(connection_error)?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(connection_error)?;
+        let text = String::from_utf8_lossy(&body).to_string();
+        if status.is_success() {
+            Ok(text)
+        } else {
+            Err(influxdb::Error::DatabaseError { error: text })
+        }
+    }
+    async fn ping(&self) -> Result<(), influxdb::Error> {
+        self.client.ping().await.map(|_| ())
+    }
+    fn query_language(&self) -> QueryLanguage {
+        self.query_language
+    }
+    fn bucket(&self) -> &str {
+        self.client.database_name()
     }
     fn workerstatus(&self) -> &str {
         &self.workerstatus
@@ -45,6 +179,74 @@ impl QueryClient for InfluxClient {
     fn pvstatus(&self) -> &str {
         &self.pvstatus
     }
+    fn min_samples(&self) -> u32 {
+        self.min_samples
+    }
+    fn worker_shards(&self) -> u32 {
+        self.worker_shards
+    }
+    fn field<'a>(&'a self, logical: &'a str) -> &'a str {
+        self.field_map
+            .get(logical)
+            .map(|f| f.as_str())
+            .unwrap_or(logical)
+    }
+    fn weekday_thresholds(&self) -> &ExcessThresholds {
+        &self.weekday_thresholds
+    }
+    fn weekend_thresholds(&self) -> Option<&ExcessThresholds> {
+        self.weekend_thresholds.as_ref()
+    }
+    fn sun_measurement(&self) -> &str {
+        self.sun_measurement.as_deref().unwrap_or(&self.pvstatus)
+    }
+    fn sun_field(&self) -> &str {
+        &self.sun_field
+    }
+    fn voltage_scale(&self) -> f32 {
+        self.voltage_scale
+    }
+    fn excess_signal(&self) -> &ExcessSignal {
+        &self.excess_signal
+    }
+    fn voltage_fields(&self) -> Vec<&str> {
+        self.voltage_fields.iter().map(|f| f.as_str()).collect()
+    }
+    fn voltage_aggregation(&self) -> Aggregation {
+        self.voltage_aggregation
+    }
+    fn temperature_gate(&self) -> Option<&TemperatureGate> {
+        self.temperature_gate.as_ref()
+    }
+    fn never_stale(&self) -> &[i32] {
+        &self.never_stale
+    }
+    fn clamp_negative_current(&self) -> bool {
+        self.clamp_negative_current
+    }
+    fn require_charging(&self) -> bool {
+        self.require_charging
+    }
+    fn excess_url(&self) -> Option<&str> {
+        self.excess_url.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryLanguage {
+    InfluxQL,
+    Flux,
+}
+
+impl FromStr for QueryLanguage {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "influxql" => Ok(QueryLanguage::InfluxQL),
+            "flux" => Ok(QueryLanguage::Flux),
+            _ => Err(format!("Unknown query language '{}'!", s)),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -55,61 +257,361 @@ pub enum WorkerStatus {
     Working = 3,
 }
 
-#[derive(Debug, Serialize, Clone)]
+impl FromStr for WorkerStatus {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sleep" => Ok(WorkerStatus::Sleep),
+            "awake" => Ok(WorkerStatus::Awake),
+            "inquisitive" => Ok(WorkerStatus::Inquisitive),
+            "working" => Ok(WorkerStatus::Working),
+            _ => Err(format!("Unknown worker status '{}'!", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ExcessStatus {
+    #[serde(alias = "no")]
     No = 0,
+    #[serde(alias = "maybe")]
     Maybe = 1,
+    #[serde(alias = "yes")]
     Yes = 2,
 }
 
-// thresholds for battery_voltage depend on SUN_LEVEL based on pv_current
+impl ExcessStatus {
+    // status string of responses ('Maybe' or 'maybe' with EXCESS_JSON_LOWERCASE)
+    pub fn name(&self, lowercase: bool) -> &'static str {
+        match (self, lowercase) {
+            (ExcessStatus::No, false) => "No",
+            (ExcessStatus::Maybe, false) => "Maybe",
+            (ExcessStatus::Yes, false) => "Yes",
+            (ExcessStatus::No, true) => "no",
+            (ExcessStatus::Maybe, true) => "maybe",
+            (ExcessStatus::Yes, true) => "yes",
+        }
+    }
+}
+
+// thresholds for battery_voltage depend on SUN_LEVEL based on pv_current (or SUN_FIELD)
 // 30m pv_current
 const SUN_LEVELS: [f32; 3] = [7.0, 25.0, 40.0];
 // 15m battery_voltage
 const MAYBE_VOLTAGE_THRESHOLDS: [f32; 3] = [12.7, 12.5, 12.2];
 const YES_VOLTAGE_THRESHOLDS: [f32; 3] = [13.2, 13.0, 12.7];
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExcessThresholds {
+    pub sun_levels: [f32; 3],
+    pub maybe_voltage: [f32; 3],
+    pub yes_voltage: [f32; 3],
+}
+
+pub const WEEKDAY_THRESHOLDS: ExcessThresholds = ExcessThresholds {
+    sun_levels: SUN_LEVELS,
+    maybe_voltage: MAYBE_VOLTAGE_THRESHOLDS,
+    yes_voltage: YES_VOLTAGE_THRESHOLDS,
+};
+
+// recent (5m) mean of the excess signal compared with the mean of 15m to 5m ago
+const CHARGING_WINDOW: &str = "5m";
+const CHARGING_BASELINE: &str = "15m";
+
+// 15m soc (percent)
+pub const MAYBE_SOC_THRESHOLDS: [f32; 3] = [80.0, 70.0, 60.0];
+pub const YES_SOC_THRESHOLDS: [f32; 3] = [95.0, 90.0, 80.0];
+
+// policy combining the voltages of several battery banks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Min,
+    Mean,
+    Max,
+}
+
+impl Aggregation {
+    pub fn apply(&self, values: &[f32]) -> Option<f32> {
+        if values.is_empty() {
+            return None;
+        }
+        Some(match self {
+            Aggregation::Min => values.iter().cloned().fold(f32::INFINITY, f32::min),
+            Aggregation::Mean => values.iter().sum::<f32>() / values.len() as f32,
+            Aggregation::Max => values.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+        })
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "min" => Ok(Aggregation::Min),
+            "mean" => Ok(Aggregation::Mean),
+            "max" => Ok(Aggregation::Max),
+            _ => Err(format!("Unknown aggregation '{}'! (min|mean|max)", s)),
+        }
+    }
+}
+
+// signal compared with the thresholds of the sun level
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExcessSignal {
+    Voltage,
+    Soc { maybe: [f32; 3], yes: [f32; 3] },
+}
+
+impl FromStr for ExcessSignal {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "voltage" => Ok(ExcessSignal::Voltage),
+            "soc" => Ok(ExcessSignal::Soc {
+                maybe: MAYBE_SOC_THRESHOLDS,
+                yes: YES_SOC_THRESHOLDS,
+            }),
+            _ => Err(format!("Unknown excess signal '{}'!", s)),
+        }
+    }
+}
+
+fn thresholds_on(c: &impl QueryClient, weekday: Weekday) -> &ExcessThresholds {
+    match weekday {
+        Weekday::Sat | Weekday::Sun => c
+            .weekend_thresholds()
+            .unwrap_or_else(|| c.weekday_thresholds()),
+        _ => c.weekday_thresholds(),
+    }
+}
+
+// battery temperature range for safely discharging
+#[derive(Debug, Clone)]
+pub struct TemperatureGate {
+    pub min: f32,
+    pub max: f32,
+    // excess outside of the range is capped at this
+    pub cap: ExcessStatus,
+}
+
+// inputs of the excess decision
+#[derive(Debug, Clone)]
+pub struct ExcessReport {
+    pub excess: ExcessStatus,
+    pub mean_current: Option<f32>,
+    pub sun_level: usize,
+    pub mean_voltage: Option<f32>,
+    pub mean_soc: Option<f32>,
+    // thresholds of the sun level (voltage or soc)
+    pub maybe_threshold: Option<f32>,
+    pub yes_threshold: Option<f32>,
+    // recent minus older mean of the excess signal (voltage or soc)
+    pub voltage_trend: Option<f32>,
+    pub mean_temperature: Option<f32>,
+}
+
+impl From<ExcessStatus> for ExcessReport {
+    fn from(excess: ExcessStatus) -> Self {
+        ExcessReport {
+            excess,
+            mean_current: None,
+            sun_level: 0,
+            mean_voltage: None,
+            mean_soc: None,
+            maybe_threshold: None,
+            yes_threshold: None,
+            voltage_trend: None,
+            mean_temperature: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ExcessReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mean = |m: Option<f32>| m.map(|v| format!("{:.2}", v)).unwrap_or("n/a".into());
+        write!(
+            f,
+            "{} (pv_current: {}, sun level: {}, battery_voltage: {}",
+            self.excess.clone() as u8,
+            mean(self.mean_current),
+            self.sun_level,
+            mean(self.mean_voltage)
+        )?;
+        if self.mean_soc.is_some() {
+            write!(f, ", soc: {}", mean(self.mean_soc))?;
+        }
+        if self.voltage_trend.is_some() {
+            write!(f, ", voltage trend: {}", mean(self.voltage_trend))?;
+        }
+        if self.mean_temperature.is_some() {
+            write!(f, ", temperature: {}", mean(self.mean_temperature))?;
+        }
+        write!(f, ")")
+    }
+}
+
+// excess with the means which drove the decision (e.g. for dashboards)
+#[derive(Debug, Clone)]
+pub struct ExcessDetail {
+    pub status: ExcessStatus,
+    pub mean_current: Option<f32>,
+    pub mean_voltage: Option<f32>,
+    pub sun_level: usize,
+}
+
+impl From<ExcessReport> for ExcessDetail {
+    fn from(report: ExcessReport) -> Self {
+        ExcessDetail {
+            status: report.excess,
+            mean_current: report.mean_current,
+            mean_voltage: report.mean_voltage,
+            sun_level: report.sun_level,
+        }
+    }
+}
+
 pub async fn query_pv_excess(c: &impl QueryClient) -> Result<ExcessStatus, influxdb::Error> {
+    query_excess_report(c).await.map(|r| r.excess)
+}
+
+pub async fn query_pv_excess_detail(c: &impl QueryClient) -> Result<ExcessDetail, influxdb::Error> {
+    query_excess_report(c).await.map(ExcessDetail::from)
+}
+
+pub async fn query_excess_report(c: &impl QueryClient) -> Result<ExcessReport, influxdb::Error> {
+    if let Some(url) = c.excess_url() {
+        // trust the excess of an external source (e.g. the inverter)
+        return Ok(ExcessReport::from(
+            fetch_excess(url, EXCESS_SOURCE_TIMEOUT).await,
+        ));
+    }
+    query_excess_report_on(c, Local::now().weekday()).await
+}
+
+async fn query_excess_report_on(
+    c: &impl QueryClient,
+    weekday: Weekday,
+) -> Result<ExcessReport, influxdb::Error> {
     // query influxdb for excess pv power
-    match mean_query(c, c.pvstatus(), "pv_current", "30m").await {
-        Err(e) => Err(e),
-        Ok(None) => {
-            warn!("Could not determine mean of pv_current because of missing data!");
-            Ok(ExcessStatus::No)
-        }
-        Ok(Some(mean_current)) => {
-            let mut sun_level = 0;
-            for (i, t) in IntoIterator::into_iter(SUN_LEVELS).enumerate() {
-                if mean_current < t {
+    let thresholds = thresholds_on(c, weekday);
+    let mut report = ExcessReport::from(ExcessStatus::No);
+    // negative current of glitching sensors drags the mean down
+    let min_current = c.clamp_negative_current().then_some(0.0);
+    match mean_query_min(c, c.sun_measurement(), c.sun_field(), "30m", min_current).await? {
+        None => {
+            warn!(
+                "Could not determine mean of {} because of missing data!",
+                c.sun_field()
+            );
+        }
+        Some(mean_current) => {
+            report.mean_current = Some(mean_current);
+            for (i, t) in thresholds.sun_levels.iter().enumerate() {
+                if mean_current < *t {
                     break;
                 }
-                sun_level = i + 1;
+                report.sun_level = i + 1;
             }
-            if sun_level < 1 {
-                Ok(ExcessStatus::No)
-            } else {
-                match mean_query(c, c.pvstatus(), "battery_voltage", "15m").await {
-                    Err(e) => Err(e),
-                    Ok(None) => {
-                        warn!(
-                            "Could not determine mean of battery_voltage because of missing data!"
-                        );
-                        Ok(ExcessStatus::No)
-                    }
-
-                    Ok(Some(mean_voltage)) => {
-                        Ok(if mean_voltage > YES_VOLTAGE_THRESHOLDS[sun_level - 1] {
+            if report.sun_level > 0 {
+                let sun_level = report.sun_level;
+                let (fields, scale) = signal_fields(c);
+                let (maybe, yes) = match c.excess_signal() {
+                    ExcessSignal::Voltage => (&thresholds.maybe_voltage, &thresholds.yes_voltage),
+                    ExcessSignal::Soc { maybe, yes } => (maybe, yes),
+                };
+                report.maybe_threshold = Some(maybe[sun_level - 1]);
+                report.yes_threshold = Some(yes[sun_level - 1]);
+                match aggregate_mean_query(c, &fields, c.voltage_aggregation(), "15m", None).await?
+                {
+                    None => {}
+                    Some(mean) => {
+                        let mean = mean * scale;
+                        if let ExcessSignal::Voltage = c.excess_signal() {
+                            report.mean_voltage = Some(mean);
+                        } else {
+                            report.mean_soc = Some(mean);
+                        }
+                        report.excess = if mean > yes[sun_level - 1] {
                             ExcessStatus::Yes
-                        } else if mean_voltage > MAYBE_VOLTAGE_THRESHOLDS[sun_level - 1] {
+                        } else if mean > maybe[sun_level - 1] {
                             ExcessStatus::Maybe
                         } else {
                             ExcessStatus::No
-                        })
+                        };
+                    }
+                }
+            }
+        }
+    }
+    if c.require_charging() && matches!(report.excess, ExcessStatus::Yes) {
+        // resting full (not charging) is at most Maybe
+        let (fields, scale) = signal_fields(c);
+        let aggregation = c.voltage_aggregation();
+        let recent = aggregate_mean_query(c, &fields, aggregation, CHARGING_WINDOW, None).await?;
+        let older = aggregate_mean_query(
+            c,
+            &fields,
+            aggregation,
+            CHARGING_BASELINE,
+            Some(CHARGING_WINDOW),
+        )
+        .await?;
+        report.voltage_trend = recent.zip(older).map(|(r, o)| (r - o) * scale);
+        if !matches!(report.voltage_trend, Some(trend) if trend > 0.0) {
+            report.excess = ExcessStatus::Maybe;
+        }
+    }
+    if let Some(gate) = c.temperature_gate() {
+        if report.excess.clone() as u8 > gate.cap.clone() as u8 {
+            match mean_query(c, c.pvstatus(), "temperature", "15m").await? {
+                None => {
+                    warn!("Could not determine mean of temperature because of missing data!");
+                }
+                Some(mean_temperature) => {
+                    report.mean_temperature = Some(mean_temperature);
+                    if mean_temperature < gate.min || mean_temperature > gate.max {
+                        report.excess = gate.cap.clone();
                     }
                 }
             }
         }
     }
+    Ok(report)
+}
+
+// means of several fields (e.g. battery banks) aggregated by policy
+// fields and scale of the excess signal
+fn signal_fields(c: &impl QueryClient) -> (Vec<&str>, f32) {
+    match c.excess_signal() {
+        ExcessSignal::Voltage => (c.voltage_fields(), c.voltage_scale()),
+        ExcessSignal::Soc { .. } => (vec!["soc"], 1.0),
+    }
+}
+
+async fn aggregate_mean_query<Q>(
+    c: &Q,
+    fields: &[&str],
+    aggregation: Aggregation,
+    duration: &str,
+    until: Option<&str>,
+) -> Result<Option<f32>, influxdb::Error>
+where
+    Q: QueryClient,
+{
+    let mut means = vec![];
+    for field in fields {
+        match mean_query_window(c, c.pvstatus(), field, duration, until, None).await? {
+            None => {
+                warn!(
+                    "Could not determine mean of {} because of missing data!",
+                    field
+                );
+                return Ok(None);
+            }
+            Some(mean) => means.push(mean),
+        }
+    }
+    Ok(aggregation.apply(&means))
 }
 
 pub async fn mean_query<Q>(
@@ -121,20 +623,95 @@ pub async fn mean_query<Q>(
 where
     Q: QueryClient,
 {
+    mean_query_min(c, measurement, field, duration, None).await
+}
+
+// mean of the values which are at least min_value
+pub async fn mean_query_min<Q>(
+    c: &Q,
+    measurement: &str,
+    field: &str,
+    duration: &str,
+    min_value: Option<f32>,
+) -> Result<Option<f32>, influxdb::Error>
+where
+    Q: QueryClient,
+{
+    mean_query_window(c, measurement, field, duration, None, min_value).await
+}
+
+// mean from now - duration until now - until (or now)
+pub async fn mean_query_window<Q>(
+    c: &Q,
+    measurement: &str,
+    field: &str,
+    duration: &str,
+    until: Option<&str>,
+    min_value: Option<f32>,
+) -> Result<Option<f32>, influxdb::Error>
+where
+    Q: QueryClient,
+{
+    let field = c.field(field);
     #[derive(Debug, Deserialize)]
     struct MeanMeasurement {
         mean: f32,
+        count: u32,
     }
-    query_values::<MeanMeasurement, Q>(
-        c,
-        &format!(
-            "SELECT mean(\"{}\") AS mean FROM {} WHERE time > now() - {}",
-            field, measurement, duration
-        ),
-    )
-    .await
-    .map(|values| values.into_iter().next())
-    .map(|v| v.map(|m| m.mean))
+    let mean_count = if let QueryLanguage::Flux = c.query_language() {
+        c.flux_query(flux::mean_script_window(
+            c.bucket(),
+            measurement,
+            field,
+            duration,
+            until,
+            min_value,
+        ))
+        .await
+        .map(|csv| {
+            flux::parse_csv(&csv).into_iter().next().and_then(|r| {
+                Some((
+                    r.get("_value")?.parse().ok()?,
+                    r.get("count")?.parse().ok()?,
+                ))
+            })
+        })?
+    } else {
+        query_values::<MeanMeasurement, Q>(
+            c,
+            &format!(
+                "SELECT mean(\"{}\") AS mean, count(\"{}\") AS count FROM {} WHERE time > now() - {}{}{}",
+                field,
+                field,
+                measurement,
+                duration,
+                until
+                    .map(|u| format!(" AND time <= now() - {}", u))
+                    .unwrap_or_default(),
+                min_value
+                    .map(|v| format!(" AND \"{}\" >= {}", field, v))
+                    .unwrap_or_default()
+            ),
+        )
+        .await?
+        .into_iter()
+        .next()
+        .map(|m| (m.mean, m.count))
+    };
+    // a mean of too few samples is not trusted
+    Ok(mean_count.and_then(|(mean, count)| {
+        if count < c.min_samples() {
+            warn!(
+                "Mean of {} from {} samples (less than {})!",
+                field,
+                count,
+                c.min_samples()
+            );
+            None
+        } else {
+            Some(mean)
+        }
+    }))
 }
 
 pub async fn query_values<D, Q>(c: &Q, query: &str) -> Result<Vec<D>, influxdb::Error>
@@ -144,13 +721,81 @@ where
 {
     c.json_query(ReadQuery::new(query))
         .await
-        .and_then(|mut db_result| db_result.deserialize_next::<D>())
+        .map_err(inspect_error(query, ""))
+        .and_then(|db_result| deserialize_with(query, db_result, |r| r.deserialize_next::<D>()))
         .map(|m| m.series.into_iter().next())
         .map(|s| match s {
             Some(s) => s.values,
             None => Vec::new(),
         })
 }
+// max length of payload snippets in logs
+const PAYLOAD_SNIPPET_LEN: usize = 200;
+
+fn deserialization_error_msg(query: &str, payload: &str, error: &str) -> String {
+    let mut snippet: String = payload.chars().take(PAYLOAD_SNIPPET_LEN).collect();
+    if snippet.len() < payload.len() {
+        snippet.push_str("...");
+    }
+    format!(
+        "[Schema-Mismatch] Unexpected response for '{}'! {} (payload: '{}')",
+        query, error, snippet
+    )
+}
+
+fn inspect_error<'a>(
+    query: &'a str,
+    payload: &'a str,
+) -> impl Fn(influxdb::Error) -> influxdb::Error + 'a {
+    // distinguish deserialization errors (schema drift) from connectivity errors
+    move |e| {
+        if let influxdb::Error::DeserializationError { error } = &e {
+            metrics::INFLUX_DESERIALIZATION_ERRORS.inc();
+            error!("{}", deserialization_error_msg(query, payload, error));
+        }
+        e
+    }
+}
+
+fn deserialize_with<T>(
+    query: &str,
+    mut db_result: DatabaseQueryResult,
+    deserialize: impl FnOnce(&mut DatabaseQueryResult) -> Result<T, influxdb::Error>,
+) -> Result<T, influxdb::Error> {
+    let payload = db_result
+        .results
+        .first()
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+    deserialize(&mut db_result).map_err(inspect_error(query, &payload))
+}
+
+pub fn shard_of(mac: &MacAddress, shards: u32) -> u32 {
+    // FNV-1a of the mac bytes (stable across builds)
+    let hash = mac.bytes().iter().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % shards.max(1) as u64) as u32
+}
+
+pub fn worker_measurement(c: &impl QueryClient, mac: &MacAddress) -> String {
+    if c.worker_shards() > 1 {
+        format!("{}_{}", c.workerstatus(), shard_of(mac, c.worker_shards()))
+    } else {
+        c.workerstatus().to_string()
+    }
+}
+
+fn worker_measurements(c: &impl QueryClient) -> Vec<String> {
+    if c.worker_shards() > 1 {
+        (0..c.worker_shards())
+            .map(|i| format!("{}_{}", c.workerstatus(), i))
+            .collect()
+    } else {
+        vec![c.workerstatus().to_string()]
+    }
+}
+
 pub async fn log_workerstatus(
     mac: &MacAddress,
     status: WorkerStatus,
@@ -165,73 +810,574 @@ pub async fn log_workerstatus(
         wake,
     };
     info!("[{}] status: {}", mac, entry.status);
-    c.query(entry.into_query(c.workerstatus())).await?;
+    c.query(entry.into_query(worker_measurement(c, mac)))
+        .await?;
+    Ok(())
+}
+
+pub async fn log_liveness(
+    measurement: &str,
+    informant: &str,
+    cycle: u64,
+    c: &impl QueryClient,
+) -> Result<(), influxdb::Error> {
+    let entry = LivenessEntry {
+        informant: informant.to_string(),
+        time: Utc::now(),
+        cycle: cycle as i64,
+    };
+    debug!("[{}] alive (cycle: {})", informant, cycle);
+    c.query(entry.into_query(measurement)).await?;
+    Ok(())
+}
+
+pub async fn log_excess(
+    excess: &ExcessStatus,
+    informant: &str,
+    c: &impl QueryClient,
+) -> Result<(), influxdb::Error> {
+    let entry = ExcessEntry {
+        informant: informant.to_string(),
+        time: Utc::now(),
+        excess: excess.clone() as i64,
+    };
+    debug!("[{}] excess: {}", informant, entry.excess);
+    c.query(entry.into_query(EXCESS_MEASUREMENT)).await?;
+    Ok(())
+}
+
+fn batch_entries(
+    entries: &[(MacAddress, WorkerStatus, bool)],
+    now: DateTime<Utc>,
+) -> Vec<(MacAddress, WorkerStatusEntry)> {
+    // distinct (ordered) timestamps for statuses of the same mac (would overwrite otherwise)
+    let mut seen: HashMap<MacAddress, i64> = HashMap::new();
+    entries
+        .iter()
+        .map(|(mac, status, wake)| {
+            let n = seen.entry(*mac).or_insert(0);
+            let time = now + Duration::milliseconds(*n);
+            *n += 1;
+            let entry = WorkerStatusEntry {
+                mac: mac.to_string(),
+                time,
+                status: status.clone() as i32,
+                wake: *wake,
+            };
+            (*mac, entry)
+        })
+        .collect()
+}
+
+pub async fn log_workerstatus_batch(
+    entries: &[(MacAddress, WorkerStatus, bool)],
+    c: &impl QueryClient,
+) -> Result<(), influxdb::Error> {
+    // log workerstatus of many macs with a single write
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let queries: Vec<WriteQuery> = batch_entries(entries, Utc::now())
+        .into_iter()
+        .map(|(mac, entry)| {
+            info!("[{}] status: {}", mac, entry.status);
+            entry.into_query(worker_measurement(c, &mac))
+        })
+        .collect();
+    c.query(queries).await?;
     Ok(())
 }
 
+// logical fields of the pvstatus measurement
+const PV_FIELDS: [&str; 4] = ["battery_voltage", "pv_voltage", "pv_current", "temperature"];
+
 pub async fn query_history_interval(
     req: &IntervalReq,
     c: &impl QueryClient,
 ) -> Result<String, influxdb::Error> {
+    if let QueryLanguage::Flux = c.query_language() {
+        let (start, stop) = req.range();
+        let range = (start.as_str(), stop.as_str());
+        let pv_fields: Vec<&str> = PV_FIELDS.iter().map(|f| c.field(f)).collect();
+        let mut scripts = vec![flux::history_script(
+            c.bucket(),
+            range,
+            c.pvstatus(),
+            &pv_fields,
+            None,
+        )];
+        if let Some(mac) = req.mac() {
+            scripts.push(flux::history_script(
+                c.bucket(),
+                range,
+                &worker_measurement(c, &mac),
+                &["status", "wake"],
+                Some(mac.to_string()),
+            ));
+        }
+        for m in req.measurements() {
+            scripts.push(flux::history_script(c.bucket(), range, m, &[], None));
+        }
+        return c.flux_query(scripts.join("\n\n")).await;
+    }
     let interval_query = req.query_condition();
+    // select physical fields with their logical names
+    let pv_fields = PV_FIELDS
+        .iter()
+        .map(|f| match c.field(f) {
+            p if p == *f => f.to_string(),
+            p => format!("{} AS {}", p, f),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
     let query = ReadQuery::new(format!(
-        "SELECT battery_voltage, pv_voltage, pv_current, temperature FROM {} WHERE {} ORDER BY time ASC",
+        "SELECT {} FROM {} WHERE {} ORDER BY time ASC",
+        pv_fields,
         c.pvstatus(),
         interval_query
     ));
-    c.query(if let Some(mac) = req.mac() {
+    let query = if let Some(mac) = req.mac() {
         query.add_query(format!(
             "SELECT status, wake FROM {} WHERE {} AND mac = '{}' ORDER BY time ASC",
-            c.workerstatus(),
+            worker_measurement(c, &mac),
             interval_query,
             mac
         ))
     } else {
         query
-    })
-    .await
+    };
+    let query = req.measurements().iter().fold(query, |query, m| {
+        query.add_query(format!(
+            "SELECT * FROM {} WHERE {} ORDER BY time ASC",
+            m, interval_query
+        ))
+    });
+    let query = if req.annotate_excess() {
+        // last statement (joined by annotate_excess)
+        query.add_query(format!(
+            "SELECT excess FROM {} WHERE {} ORDER BY time ASC",
+            EXCESS_MEASUREMENT, interval_query
+        ))
+    } else {
+        query
+    };
+    c.query(query).await
 }
 
-const WORKER_STALE_MINS: i64 = 10;
+pub fn convert_timezone(history: &str, tz: &Tz) -> Result<String, serde_json::Error> {
+    // convert the 'time' column of all series in an influxdb response
+    let mut value: serde_json::Value = serde_json::from_str(history)?;
+    let series = value
+        .get_mut("results")
+        .and_then(|r| r.as_array_mut())
+        .into_iter()
+        .flatten()
+        .filter_map(|r| r.get_mut("series").and_then(|s| s.as_array_mut()))
+        .flatten();
+    for s in series {
+        let time_idx = s["columns"]
+            .as_array()
+            .and_then(|cols| cols.iter().position(|c| c == "time"));
+        let rows = s.get_mut("values").and_then(|v| v.as_array_mut());
+        if let (Some(idx), Some(rows)) = (time_idx, rows) {
+            for row in rows {
+                let converted = row[idx]
+                    .as_str()
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .map(|t| t.with_timezone(tz).to_rfc3339());
+                if let Some(t) = converted {
+                    row[idx] = serde_json::Value::String(t);
+                }
+            }
+        }
+    }
+    serde_json::to_string(&value)
+}
 
-pub async fn query_stale_macs<Q: QueryClient>(
-    c: &Q,
-) -> Result<Vec<(MacAddress, bool)>, influxdb::Error> {
-    #[derive(Deserialize)]
-    struct EntryTag {
-        mac: String,
+fn csv_field(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::Null => "".into(),
+        serde_json::Value::String(s) if s.contains([',', '"', '\n']) => {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        }
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
     }
+}
 
-    #[derive(Deserialize)]
-    struct Entry {
-        time: DateTime<Utc>,
-        status: i32,
-        wake: bool,
-    }
-
-    let now_m_10m = Utc::now() - Duration::minutes(WORKER_STALE_MINS);
-    c.json_query(ReadQuery::new(&format!(
-        "SELECT last(\"status\") AS status,wake,time FROM {} GROUP BY mac",
-        c.workerstatus()
-    )))
-    .await
-    .and_then(|mut db_result| db_result.deserialize_next_tagged::<EntryTag, Entry>())
-    .map(|r| {
-        r.series
+pub fn history_to_csv(history: &str) -> Result<String, serde_json::Error> {
+    // a header row (name and columns) followed by the rows of each series
+    let value: serde_json::Value = serde_json::from_str(history)?;
+    let series = value["results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|r| r["series"].as_array())
+        .flatten();
+    let mut csv = String::new();
+    for s in series {
+        let name = csv_field(&s["name"]);
+        let columns = s["columns"].as_array().into_iter().flatten();
+        let header: Vec<String> = std::iter::once("name".into())
+            .chain(columns.map(csv_field))
+            .collect();
+        csv.push_str(&header.join(","));
+        csv.push('\n');
+        for row in s["values"].as_array().into_iter().flatten() {
+            let row: Vec<String> = std::iter::once(name.clone())
+                .chain(row.as_array().into_iter().flatten().map(csv_field))
+                .collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+    Ok(csv)
+}
+
+// excess status written by each heartbeat (field 'excess')
+pub const EXCESS_MEASUREMENT: &str = "excessstatus";
+
+pub fn annotate_excess(history: &str) -> Result<String, serde_json::Error> {
+    // join the excess series (last statement) into the pvstatus series (first statement)
+    let mut value: serde_json::Value = serde_json::from_str(history)?;
+    let results = match value.get_mut("results").and_then(|r| r.as_array_mut()) {
+        Some(results) if results.len() > 1 => results,
+        _ => return serde_json::to_string(&value),
+    };
+    let parse_time = |v: &serde_json::Value| {
+        v.as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+    };
+    let excess_series = results.pop().unwrap();
+    let excess: Vec<(DateTime<chrono::FixedOffset>, serde_json::Value)> = excess_series["series"]
+        .as_array()
+        .and_then(|s| s.first())
+        .and_then(|s| s["values"].as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|row| Some((parse_time(&row[0])?, row[1].clone())))
+        .collect();
+    let series = results[0]
+        .get_mut("series")
+        .and_then(|s| s.as_array_mut())
+        .into_iter()
+        .flatten();
+    for s in series {
+        let time_idx = s["columns"]
+            .as_array()
+            .and_then(|cols| cols.iter().position(|c| c == "time"));
+        if let Some(cols) = s.get_mut("columns").and_then(|c| c.as_array_mut()) {
+            cols.push(serde_json::Value::String("excess".into()));
+        }
+        let rows = s.get_mut("values").and_then(|v| v.as_array_mut());
+        if let (Some(idx), Some(rows)) = (time_idx, rows) {
+            for row in rows {
+                // excess active at the row time (null before the first excess)
+                let active = parse_time(&row[idx])
+                    .and_then(|t| excess.iter().take_while(|(at, _)| *at <= t).last())
+                    .map(|(_, e)| e.clone())
+                    .unwrap_or(serde_json::Value::Null);
+                if let Some(row) = row.as_array_mut() {
+                    row.push(active);
+                }
+            }
+        }
+    }
+    serde_json::to_string(&value)
+}
+
+pub const WORKER_STALE_MINS: i64 = 10;
+
+#[derive(Deserialize)]
+struct EntryTag {
+    mac: String,
+}
+
+#[derive(Deserialize)]
+struct LastStatus {
+    time: DateTime<Utc>,
+    status: i32,
+    wake: bool,
+}
+
+pub async fn query_stale_macs<Q: QueryClient>(
+    c: &Q,
+    max_age: Option<Duration>,
+) -> Result<Vec<(MacAddress, bool)>, influxdb::Error> {
+    // last report of the worker itself (heartbeat only logs Sleep/Awake)
+    let last_seen = match max_age {
+        Some(_) => query_last_seen(c).await?,
+        None => HashMap::new(),
+    };
+    let now = Utc::now();
+    let now_m_10m = now - Duration::minutes(WORKER_STALE_MINS);
+    query_last_status(c).await.map(|entries| {
+        entries
             .into_iter()
-            .filter_map(|s| {
-                s.values
-                    .first()
-                    .filter(|e| {
-                        let active = e.status >= { WorkerStatus::Inquisitive as i32 };
-                        (active && e.time < now_m_10m) || (!active && e.wake)
-                    })
-                    .and_then(|e| s.tags.mac.parse().ok().map(|m| (m, e.wake)))
+            .filter(|(mac, e)| {
+                if let Some(age) = max_age {
+                    // never seen if only logged by the heartbeat (e.g. reports out of retention)
+                    let seen = last_seen.get(mac);
+                    if seen.map(|t| *t < now - age).unwrap_or(true) {
+                        info!(
+                            "[{}] abandoned (last seen: {})",
+                            mac,
+                            seen.map(|t| t.to_string())
+                                .unwrap_or_else(|| "never".into())
+                        );
+                        return false;
+                    }
+                }
+                if c.never_stale().contains(&e.status) {
+                    debug!("[{}] never stale (status: {})", mac, e.status);
+                    return false;
+                }
+                let active = e.status >= { WorkerStatus::Inquisitive as i32 };
+                (active && e.time < now_m_10m) || (!active && e.wake)
             })
+            .filter_map(|(mac, e)| mac.parse().ok().map(|m| (m, e.wake)))
             .collect()
     })
 }
 
+// last report of a worker
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WorkerLastStatus {
+    // from the mac tag
+    #[serde(default)]
+    pub mac: String,
+    pub status: i32,
+    pub wake: bool,
+    #[serde(rename(deserialize = "time"))]
+    pub last_seen: DateTime<Utc>,
+    // reports by wake intent within the history window ('?history=true')
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wake_history: Option<WakeCounts>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct WakeCounts {
+    pub wake_true: u64,
+    pub wake_false: u64,
+}
+
+pub async fn query_all_worker_status<Q: QueryClient>(
+    c: &Q,
+) -> Result<Vec<WorkerLastStatus>, influxdb::Error> {
+    let mut workers = vec![];
+    for m in worker_measurements(c) {
+        workers.extend(query_all_worker_status_of(c, &m).await?);
+    }
+    workers.sort_by(|a, b| a.mac.cmp(&b.mac));
+    Ok(workers)
+}
+
+async fn query_all_worker_status_of<Q: QueryClient>(
+    c: &Q,
+    measurement: &str,
+) -> Result<Vec<WorkerLastStatus>, influxdb::Error> {
+    match c.query_language() {
+        QueryLanguage::Flux => query_last_status_of(c, measurement).await.map(|entries| {
+            entries
+                .into_iter()
+                .map(|(mac, e)| WorkerLastStatus {
+                    mac,
+                    status: e.status,
+                    wake: e.wake,
+                    last_seen: e.time,
+                    wake_history: None,
+                })
+                .collect()
+        }),
+        QueryLanguage::InfluxQL => {
+            // a single selector keeps the time of the last report
+            let query = format!(
+                "SELECT last(\"status\") AS status, wake FROM {} GROUP BY mac",
+                measurement
+            );
+            c.json_query(ReadQuery::new(&query))
+                .await
+                .map_err(inspect_error(&query, ""))
+                .and_then(|db_result| {
+                    deserialize_with(&query, db_result, |r| {
+                        r.deserialize_next_tagged::<EntryTag, WorkerLastStatus>()
+                    })
+                })
+                .map(|r| {
+                    r.series
+                        .into_iter()
+                        .filter_map(|s| {
+                            let mac = s.tags.mac;
+                            s.values
+                                .into_iter()
+                                .next()
+                                .map(|e| WorkerLastStatus { mac, ..e })
+                        })
+                        .collect()
+                })
+        }
+    }
+}
+
+pub async fn query_wake_counts<Q: QueryClient>(
+    c: &Q,
+    window: Duration,
+) -> Result<HashMap<String, WakeCounts>, influxdb::Error> {
+    #[derive(Deserialize)]
+    struct Entry {
+        count: u64,
+    }
+
+    if c.query_language() == QueryLanguage::Flux {
+        return Err(influxdb::Error::InvalidQueryError {
+            error: "Wake history requires InfluxQL!".into(),
+        });
+    }
+    let mut counts: HashMap<String, WakeCounts> = HashMap::new();
+    for m in worker_measurements(c) {
+        for wake in [true, false] {
+            let query = format!(
+                "SELECT count(\"status\") AS count FROM {} WHERE time > now() - {}s AND wake = {} GROUP BY mac",
+                m,
+                window.num_seconds(),
+                wake
+            );
+            let grouped = c
+                .json_query(ReadQuery::new(&query))
+                .await
+                .map_err(inspect_error(&query, ""))
+                .and_then(|db_result| {
+                    deserialize_with(&query, db_result, |r| {
+                        r.deserialize_next_tagged::<EntryTag, Entry>()
+                    })
+                })?;
+            for s in grouped.series {
+                let n: u64 = s.values.iter().map(|e| e.count).sum();
+                let entry = counts.entry(s.tags.mac).or_default();
+                if wake {
+                    entry.wake_true += n;
+                } else {
+                    entry.wake_false += n;
+                }
+            }
+        }
+    }
+    Ok(counts)
+}
+
+async fn query_last_status<Q: QueryClient>(
+    c: &Q,
+) -> Result<Vec<(String, LastStatus)>, influxdb::Error> {
+    // fan out across worker shards
+    let mut entries = vec![];
+    for m in worker_measurements(c) {
+        entries.extend(query_last_status_of(c, &m).await?);
+    }
+    Ok(entries)
+}
+
+async fn query_last_status_of<Q: QueryClient>(
+    c: &Q,
+    measurement: &str,
+) -> Result<Vec<(String, LastStatus)>, influxdb::Error> {
+    match c.query_language() {
+        QueryLanguage::Flux => c
+            .flux_query(flux::last_status_script(c.bucket(), measurement))
+            .await
+            .map(|csv| {
+                flux::parse_csv(&csv)
+                    .into_iter()
+                    .filter_map(|r| {
+                        Some((
+                            r.get("mac")?.clone(),
+                            LastStatus {
+                                time: r.get("_time")?.parse().ok()?,
+                                status: r.get("status")?.parse().ok()?,
+                                wake: r.get("wake")?.parse().ok()?,
+                            },
+                        ))
+                    })
+                    .collect()
+            }),
+        QueryLanguage::InfluxQL => {
+            let query = format!(
+                "SELECT last(\"status\") AS status,wake,time FROM {} GROUP BY mac",
+                measurement
+            );
+            c.json_query(ReadQuery::new(&query))
+                .await
+                .map_err(inspect_error(&query, ""))
+                .and_then(|db_result| {
+                    deserialize_with(&query, db_result, |r| {
+                        r.deserialize_next_tagged::<EntryTag, LastStatus>()
+                    })
+                })
+                .map(|r| {
+                    r.series
+                        .into_iter()
+                        .filter_map(|s| {
+                            let mac = s.tags.mac;
+                            s.values.into_iter().next().map(|e| (mac, e))
+                        })
+                        .collect()
+                })
+        }
+    }
+}
+
+async fn query_last_seen<Q: QueryClient>(
+    c: &Q,
+) -> Result<HashMap<String, DateTime<Utc>>, influxdb::Error> {
+    let mut last_seen = HashMap::new();
+    for m in worker_measurements(c) {
+        last_seen.extend(query_last_seen_of(c, &m).await?);
+    }
+    Ok(last_seen)
+}
+
+async fn query_last_seen_of<Q: QueryClient>(
+    c: &Q,
+    measurement: &str,
+) -> Result<HashMap<String, DateTime<Utc>>, influxdb::Error> {
+    #[derive(Deserialize)]
+    struct Entry {
+        time: DateTime<Utc>,
+    }
+
+    let min_status = WorkerStatus::Inquisitive as i32;
+    match c.query_language() {
+        QueryLanguage::Flux => c
+            .flux_query(flux::last_seen_script(c.bucket(), measurement, min_status))
+            .await
+            .map(|csv| {
+                flux::parse_csv(&csv)
+                    .into_iter()
+                    .filter_map(|r| Some((r.get("mac")?.clone(), r.get("_time")?.parse().ok()?)))
+                    .collect()
+            }),
+        QueryLanguage::InfluxQL => {
+            let query = format!(
+                "SELECT last(\"status\") AS status FROM {} WHERE status >= {} GROUP BY mac",
+                measurement, min_status
+            );
+            c.json_query(ReadQuery::new(&query))
+                .await
+                .map_err(inspect_error(&query, ""))
+                .and_then(|db_result| {
+                    deserialize_with(&query, db_result, |r| {
+                        r.deserialize_next_tagged::<EntryTag, Entry>()
+                    })
+                })
+                .map(|r| {
+                    r.series
+                        .into_iter()
+                        .filter_map(|s| s.values.first().map(|e| (s.tags.mac.clone(), e.time)))
+                        .collect()
+                })
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod test {
 
@@ -239,6 +1385,7 @@ pub mod test {
     use async_trait::async_trait;
     use mac_address::MacAddress;
     use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
 
     #[tokio::test]
     async fn test_log_workerstatus() {
@@ -263,20 +1410,122 @@ pub mod test {
             );
             let client = InfluxClientMock {
                 answer_map: HashMap::from([(write_query, "".into())]),
+                ..Default::default()
             };
             let r = log_workerstatus(&mac, status, true, &client).await;
             assert!(r.is_ok());
         }
     }
 
+    #[tokio::test]
+    async fn test_log_workerstatus_batch() {
+        init_logger();
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let other: MacAddress = "11:22:33:44:55:77".parse().unwrap();
+        let entries = [
+            (mac, WorkerStatus::Sleep, false),
+            (other, WorkerStatus::Sleep, true),
+            (mac, WorkerStatus::Awake, true),
+        ];
+        let now = Utc::now();
+        let batch = batch_entries(&entries, now);
+        let times: Vec<DateTime<Utc>> = batch.iter().map(|(_, e)| e.time).collect();
+        assert_eq!(times[1], now);
+        assert!(
+            times[0] < times[2],
+            "should order statuses of the same mac by distinct timestamps"
+        );
+        let lines = batch
+            .into_iter()
+            .map(|(_, e)| e.into_query("workerstatus"))
+            .collect::<Vec<WriteQuery>>()
+            .build()
+            .unwrap()
+            .get();
+        assert_eq!(
+            lines.lines().count(),
+            3,
+            "should write every status (no overwrite)"
+        );
+
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                "workerstatus,mac=11:22:33:44:55:66 status=0i,wake=false".to_string(),
+                "".to_string(),
+            )]),
+            ..Default::default()
+        };
+        assert_matches!(log_workerstatus_batch(&entries, &client).await, Ok(()));
+        assert_matches!(log_workerstatus_batch(&[], &client).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_worker_shards() {
+        init_logger();
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let shard = shard_of(&mac, 4);
+        assert!(shard < 4);
+        assert_eq!(shard, shard_of(&mac, 4), "should map mac to the same shard");
+        assert_eq!(shard_of(&mac, 1), 0);
+
+        let status_resp = |m: &str| {
+            format!(
+                r#"[{{"series": [{{"name": "workerstatus", "tags": {{"mac": "{}"}}, "columns": ["time", "status", "wake"], "values": [["{}", 0, true]]}}]}}]"#,
+                m,
+                Utc::now().to_rfc3339()
+            )
+        };
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    format!(
+                        "workerstatus_{},mac={} status=0i,wake=true",
+                        shard_of(&mac, 2),
+                        mac
+                    ),
+                    "".into(),
+                ),
+                (
+                    "SELECT last(\"status\") AS status,wake,time FROM workerstatus_0 GROUP BY mac"
+                        .into(),
+                    status_resp("11:22:33:44:55:00"),
+                ),
+                (
+                    "SELECT last(\"status\") AS status,wake,time FROM workerstatus_1 GROUP BY mac"
+                        .into(),
+                    status_resp("11:22:33:44:55:01"),
+                ),
+            ]),
+            worker_shards: 2,
+            ..Default::default()
+        };
+        assert_matches!(
+            log_workerstatus(&mac, WorkerStatus::Sleep, true, &client).await,
+            Ok(()),
+            "should write to the shard of the mac"
+        );
+        let mut stale_macs: Vec<String> = query_stale_macs(&client, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(m, _)| m.to_string())
+            .collect();
+        stale_macs.sort();
+        assert_eq!(
+            stale_macs,
+            vec!["11:22:33:44:55:00", "11:22:33:44:55:01"],
+            "should query candidates of all shards"
+        );
+    }
+
     #[tokio::test]
     async fn test_query_excess_pv() {
         const MEAN_RESP: &'static str = r#"[{
             "series": [{
                 "name":"test_query",
-                "columns": ["mean"],
+                "columns": ["mean", "count"],
                 "values": [ 
-                    [MEAN_VALUE]
+                    [MEAN_VALUE, 10]
                 ]
             }]}]"#;
 
@@ -290,10 +1539,10 @@ pub mod test {
         }
         init_logger();
         let pvcurrent_mean_query =
-            "SELECT mean(\"pv_current\") AS mean FROM pvstatus WHERE time > now() - 30m"
+            "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m"
                 .to_string();
         let battery_voltage_mean_query =
-            "SELECT mean(\"battery_voltage\") AS mean FROM pvstatus WHERE time > now() - 15m"
+            "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m"
                 .to_string();
         let mut client = InfluxClientMock {
             answer_map: HashMap::from([
@@ -306,6 +1555,7 @@ pub mod test {
                     "causes some influx error".into(),
                 ),
             ]),
+            ..Default::default()
         };
         assert_matches!(
             query_pv_excess(&client).await,
@@ -313,43 +1563,560 @@ pub mod test {
             "should not panic if queries fail"
         );
 
-        mean_r!(client, pvcurrent_mean_query, 4.2);
-        assert_matches!(
-            query_pv_excess(&client).await.unwrap(),
-            ExcessStatus::No,
-            "should not call failing second query if the SUN_LEVEL indicates NIGHT"
-        );
-        mean_r!(client, pvcurrent_mean_query, SUN_LEVELS[0]);
+        mean_r!(client, pvcurrent_mean_query, 4.2);
+        assert_matches!(
+            query_pv_excess(&client).await.unwrap(),
+            ExcessStatus::No,
+            "should not call failing second query if the SUN_LEVEL indicates NIGHT"
+        );
+        mean_r!(client, pvcurrent_mean_query, SUN_LEVELS[0]);
+        assert_matches!(
+            query_pv_excess(&client).await,
+            Err(_),
+            "should call second (failing) query to check for YES/MAYBE excess"
+        );
+        mean_r!(
+            client,
+            battery_voltage_mean_query,
+            MAYBE_VOLTAGE_THRESHOLDS[1]
+        );
+        assert_matches!(
+            query_pv_excess(&client).await.unwrap(),
+            ExcessStatus::No,
+            "should have too low voltage for MAYBE with SUN_LEVEL[0]"
+        );
+        mean_r!(client, pvcurrent_mean_query, SUN_LEVELS[1]);
+        assert_matches!(
+            query_pv_excess(&client).await.unwrap(),
+            ExcessStatus::Maybe,
+            "should have enough voltage for MAYBE with SUN_LEVEL[1]"
+        );
+        mean_r!(
+            client,
+            battery_voltage_mean_query,
+            YES_VOLTAGE_THRESHOLDS[1]
+        );
+        assert_matches!(
+            query_pv_excess(&client).await.unwrap(),
+            ExcessStatus::Yes,
+            "should have enough voltage for YES with SUN_LEVEL[1]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_report() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m".into(),
+                    mean_resp(30.5),
+                ),
+                (
+                    "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m".into(),
+                    mean_resp(13.25),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let report = query_excess_report(&client).await.unwrap();
+        assert_matches!(report.excess, ExcessStatus::Yes);
+        assert_eq!(
+            report.to_string(),
+            "2 (pv_current: 30.50, sun level: 2, battery_voltage: 13.25)",
+            "should log the decision inputs"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weekend_thresholds() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let mut client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m".into(),
+                    mean_resp(30.5),
+                ),
+                (
+                    "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m".into(),
+                    mean_resp(13.25),
+                ),
+            ]),
+            ..Default::default()
+        };
+        assert_matches!(
+            query_excess_report_on(&client, Weekday::Sat)
+                .await
+                .unwrap()
+                .excess,
+            ExcessStatus::Yes,
+            "should use weekday thresholds if weekend thresholds are unset"
+        );
+        client.weekend_thresholds = Some(ExcessThresholds {
+            yes_voltage: [13.6, 13.4, 13.1],
+            ..WEEKDAY_THRESHOLDS
+        });
+        assert_matches!(
+            query_excess_report_on(&client, Weekday::Wed)
+                .await
+                .unwrap()
+                .excess,
+            ExcessStatus::Yes,
+            "should use weekday thresholds on a weekday"
+        );
+        for weekday in [Weekday::Sat, Weekday::Sun] {
+            assert_matches!(
+                query_excess_report_on(&client, weekday)
+                    .await
+                    .unwrap()
+                    .excess,
+                ExcessStatus::Maybe,
+                "should use weekend thresholds on {}",
+                weekday
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_voltage_scale() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let voltage_query = "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m";
+        let mut client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m".into(),
+                mean_resp(30.5),
+            )]),
+            voltage_scale: Some(0.001),
+            ..Default::default()
+        };
+        // sun level 2: maybe above 12.5V, yes above 13.0V
+        for (millivolts, expected) in [
+            (12400.0, ExcessStatus::No),
+            (12700.0, ExcessStatus::Maybe),
+            (13100.0, ExcessStatus::Yes),
+        ] {
+            client
+                .answer_map
+                .insert(voltage_query.into(), mean_resp(millivolts));
+            let report = query_excess_report(&client).await.unwrap();
+            assert_eq!(
+                report.excess as u8, expected as u8,
+                "should classify {}mV after scaling",
+                millivolts
+            );
+            assert_eq!(report.mean_voltage, Some(millivolts * 0.001));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_voltage_aggregation() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let bank_query = |field: &str| {
+            format!(
+                "SELECT mean(\"{}\") AS mean, count(\"{}\") AS count FROM pvstatus WHERE time > now() - 15m",
+                field, field
+            )
+        };
+        let mut client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m".into(),
+                mean_resp(30.5),
+            )]),
+            voltage_fields: vec!["bank1_voltage".into(), "bank2_voltage".into()],
+            voltage_aggregation: Some("min".parse().unwrap()),
+            ..Default::default()
+        };
+        // sun level 2: maybe above 12.5V, yes above 13.0V
+        for (bank1, bank2, expected) in [
+            (13.2, 12.6, ExcessStatus::Maybe),
+            (12.4, 13.3, ExcessStatus::No),
+            (13.1, 13.2, ExcessStatus::Yes),
+        ] {
+            client
+                .answer_map
+                .insert(bank_query("bank1_voltage"), mean_resp(bank1));
+            client
+                .answer_map
+                .insert(bank_query("bank2_voltage"), mean_resp(bank2));
+            let report = query_excess_report(&client).await.unwrap();
+            assert_eq!(
+                report.excess as u8, expected as u8,
+                "should classify the weakest bank of {}V and {}V",
+                bank1, bank2
+            );
+            assert_eq!(report.mean_voltage, Some(f32::min(bank1, bank2)));
+        }
+        assert_eq!(Aggregation::Mean.apply(&[12.0, 13.0]), Some(12.5));
+        assert_eq!(Aggregation::Max.apply(&[12.0, 13.0]), Some(13.0));
+        assert_eq!(Aggregation::Min.apply(&[]), None);
+        assert_matches!("median".parse::<Aggregation>(), Err(_));
+    }
+
+    #[tokio::test]
+    async fn test_soc_signal() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let current_query = "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m";
+        let soc_query = "SELECT mean(\"soc\") AS mean, count(\"soc\") AS count FROM pvstatus WHERE time > now() - 15m";
+        let mut client = InfluxClientMock {
+            excess_signal: Some("soc".parse().unwrap()),
+            ..Default::default()
+        };
+        for (current, soc, expected) in [
+            // sun level 0: never excess
+            (3.0, 99.0, ExcessStatus::No),
+            // sun level 1: maybe above 80%, yes above 95%
+            (10.0, 75.0, ExcessStatus::No),
+            (10.0, 85.0, ExcessStatus::Maybe),
+            (10.0, 97.0, ExcessStatus::Yes),
+            // sun level 3: maybe above 60%, yes above 80%
+            (45.0, 55.0, ExcessStatus::No),
+            (45.0, 65.0, ExcessStatus::Maybe),
+            (45.0, 85.0, ExcessStatus::Yes),
+        ] {
+            client
+                .answer_map
+                .insert(current_query.into(), mean_resp(current));
+            client.answer_map.insert(soc_query.into(), mean_resp(soc));
+            let report = query_excess_report(&client).await.unwrap();
+            assert_eq!(
+                report.excess as u8, expected as u8,
+                "should classify {}% soc with {}A",
+                soc, current
+            );
+            assert_eq!(report.mean_voltage, None);
+        }
+        assert_eq!("voltage".parse::<ExcessSignal>(), Ok(ExcessSignal::Voltage));
+        assert_matches!("current".parse::<ExcessSignal>(), Err(_));
+    }
+
+    #[tokio::test]
+    async fn test_require_charging() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let voltage_query = "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - ";
+        let mut client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m".into(),
+                    mean_resp(30.5),
+                ),
+                (format!("{}15m", voltage_query), mean_resp(13.2)),
+            ]),
+            require_charging: true,
+            ..Default::default()
+        };
+        for (older, recent, expected) in [
+            (13.2, 13.2, ExcessStatus::Maybe),
+            (13.1, 13.3, ExcessStatus::Yes),
+            (13.3, 13.1, ExcessStatus::Maybe),
+        ] {
+            client
+                .answer_map
+                .insert(format!("{}5m", voltage_query), mean_resp(recent));
+            client.answer_map.insert(
+                format!("{}15m AND time <= now() - 5m", voltage_query),
+                mean_resp(older),
+            );
+            let report = query_excess_report(&client).await.unwrap();
+            assert_eq!(
+                report.excess as u8, expected as u8,
+                "should gate Yes by voltage trend from {} to {}",
+                older, recent
+            );
+            assert!(report.voltage_trend.is_some());
+        }
+        client.require_charging = false;
+        assert_matches!(
+            query_excess_report(&client).await,
+            Ok(ExcessReport {
+                excess: ExcessStatus::Yes,
+                voltage_trend: None,
+                ..
+            }),
+            "should ignore the trend by default"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_charging_banks() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let bank_query = |bank: &str, window: &str| {
+            format!(
+                "SELECT mean(\"{0}\") AS mean, count(\"{0}\") AS count FROM pvstatus WHERE time > now() - {1}",
+                bank, window
+            )
+        };
+        let mut answers = HashMap::from([(
+            "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m".to_string(),
+            mean_resp(30.5),
+        )]);
+        // bank1 is rising but the lower bank2 is resting
+        for (bank, older, recent) in [("bank1_voltage", 13.4, 13.6), ("bank2_voltage", 13.2, 13.2)]
+        {
+            answers.insert(bank_query(bank, "15m"), mean_resp(13.3));
+            answers.insert(bank_query(bank, "5m"), mean_resp(recent));
+            answers.insert(
+                bank_query(bank, "15m AND time <= now() - 5m"),
+                mean_resp(older),
+            );
+        }
+        let mut client = InfluxClientMock {
+            answer_map: answers,
+            voltage_fields: vec!["bank1_voltage".into(), "bank2_voltage".into()],
+            voltage_aggregation: Some(Aggregation::Min),
+            require_charging: true,
+            ..Default::default()
+        };
+        assert_matches!(
+            query_excess_report(&client).await,
+            Ok(ExcessReport {
+                excess: ExcessStatus::Maybe,
+                voltage_trend: Some(t),
+                ..
+            }) if t == 0.0,
+            "should gate by the trend of the aggregated banks"
+        );
+        client.voltage_aggregation = Some(Aggregation::Max);
+        assert_matches!(
+            query_excess_report(&client).await,
+            Ok(ExcessReport {
+                excess: ExcessStatus::Yes,
+                ..
+            }),
+            "should follow the aggregation of the banks"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_temperature_gate() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let temperature_query = "SELECT mean(\"temperature\") AS mean, count(\"temperature\") AS count FROM pvstatus WHERE time > now() - 15m";
+        let mut client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m".into(),
+                    mean_resp(30.5),
+                ),
+                (
+                    "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m".into(),
+                    mean_resp(13.25),
+                ),
+            ]),
+            temperature_gate: Some(TemperatureGate {
+                min: 5.0,
+                max: 40.0,
+                cap: ExcessStatus::Maybe,
+            }),
+            ..Default::default()
+        };
+        for (temperature, expected) in [
+            (20.0, ExcessStatus::Yes),
+            (0.0, ExcessStatus::Maybe),
+            (45.0, ExcessStatus::Maybe),
+        ] {
+            client
+                .answer_map
+                .insert(temperature_query.into(), mean_resp(temperature));
+            let report = query_excess_report(&client).await.unwrap();
+            assert_eq!(
+                report.excess as u8, expected as u8,
+                "should cap excess outside the temperature range ({})",
+                temperature
+            );
+            assert_eq!(report.mean_temperature, Some(temperature));
+        }
+        client.temperature_gate = Some(TemperatureGate {
+            min: 5.0,
+            max: 40.0,
+            cap: ExcessStatus::No,
+        });
+        let report = query_excess_report(&client).await.unwrap();
+        assert_matches!(report.excess, ExcessStatus::No, "should cap at no");
+        assert!(report.to_string().ends_with(", temperature: 45.00)"));
+    }
+
+    #[tokio::test]
+    async fn test_clamp_negative_current() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let current_query = "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m";
+        let mut client = InfluxClientMock {
+            answer_map: HashMap::from([
+                // samples [30.5, 30.5, -50.0] (sensor glitch)
+                (current_query.into(), mean_resp(3.67)),
+                (
+                    format!("{} AND \"pv_current\" >= 0", current_query),
+                    mean_resp(30.5),
+                ),
+                (
+                    "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m".into(),
+                    mean_resp(13.25),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let report = query_excess_report(&client).await.unwrap();
+        assert_matches!(
+            report.excess,
+            ExcessStatus::No,
+            "should be dragged down by negative current"
+        );
+        client.clamp_negative_current = true;
+        let report = query_excess_report(&client).await.unwrap();
+        assert_eq!(report.mean_current, Some(30.5));
+        assert_matches!(
+            report.excess,
+            ExcessStatus::Yes,
+            "should ignore negative current"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sun_field() {
+        init_logger();
+        let mean_resp = |mean: f32| {
+            format!(
+                r#"[{{"series": [{{"name": "weather", "columns": ["mean", "count"], "values": [[{}, 10]]}}]}}]"#,
+                mean
+            )
+        };
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT mean(\"irradiance\") AS mean, count(\"irradiance\") AS count FROM weather WHERE time > now() - 30m".into(),
+                    mean_resp(450.0),
+                ),
+                (
+                    "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m".into(),
+                    mean_resp(13.25),
+                ),
+            ]),
+            weekday_thresholds: Some(ExcessThresholds {
+                sun_levels: [100.0, 300.0, 600.0],
+                ..WEEKDAY_THRESHOLDS
+            }),
+            sun_measurement: Some("weather".into()),
+            sun_field: Some("irradiance".into()),
+            ..Default::default()
+        };
+        let report = query_excess_report_on(&client, Weekday::Mon).await.unwrap();
+        assert_eq!(report.mean_current, Some(450.0));
+        assert_eq!(
+            report.sun_level, 2,
+            "should derive the sun level from the configured field"
+        );
+        assert_matches!(report.excess, ExcessStatus::Yes);
+    }
+
+    #[tokio::test]
+    async fn test_deserialization_error() {
+        init_logger();
+        let query = "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m";
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                query.into(),
+                r#"[{"series": [{"name": "pvstatus", "columns": ["mean", "count"], "values": [["not a number", 10]]}]}]"#.into(),
+            )]),
+            ..Default::default()
+        };
+        let before = metrics::INFLUX_DESERIALIZATION_ERRORS.get();
         assert_matches!(
-            query_pv_excess(&client).await,
-            Err(_),
-            "should call second (failing) query to check for YES/MAYBE excess"
+            mean_query(&client, "pvstatus", "pv_current", "30m").await,
+            Err(influxdb::Error::DeserializationError { .. })
         );
-        mean_r!(
-            client,
-            battery_voltage_mean_query,
-            MAYBE_VOLTAGE_THRESHOLDS[1]
+        assert!(
+            metrics::INFLUX_DESERIALIZATION_ERRORS.get() > before,
+            "should count deserialization errors"
         );
-        assert_matches!(
-            query_pv_excess(&client).await.unwrap(),
-            ExcessStatus::No,
-            "should have too low voltage for MAYBE with SUN_LEVEL[0]"
+
+        let msg = deserialization_error_msg(query, &"x".repeat(PAYLOAD_SNIPPET_LEN + 1), "bad");
+        assert!(msg.starts_with("[Schema-Mismatch]"));
+        assert!(msg.contains(query), "should log the query");
+        assert!(
+            msg.contains(&format!("'{}...'", "x".repeat(PAYLOAD_SNIPPET_LEN))),
+            "should truncate the payload"
         );
-        mean_r!(client, pvcurrent_mean_query, SUN_LEVELS[1]);
+    }
+
+    #[tokio::test]
+    async fn test_mean_query_min_samples() {
+        init_logger();
+        let mean_query_str =
+            "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m";
+        let mean_resp = |count: u32| {
+            format!(
+                r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[42.5, {}]]}}]}}]"#,
+                count
+            )
+        };
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(mean_query_str.into(), mean_resp(MOCK_MIN_SAMPLES - 1))]),
+            ..Default::default()
+        };
         assert_matches!(
-            query_pv_excess(&client).await.unwrap(),
-            ExcessStatus::Maybe,
-            "should have enough voltage for MAYBE with SUN_LEVEL[1]"
-        );
-        mean_r!(
-            client,
-            battery_voltage_mean_query,
-            YES_VOLTAGE_THRESHOLDS[1]
+            mean_query(&client, "pvstatus", "pv_current", "30m").await,
+            Ok(None),
+            "should treat mean of too few samples as missing"
         );
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(mean_query_str.into(), mean_resp(MOCK_MIN_SAMPLES))]),
+            ..Default::default()
+        };
         assert_matches!(
-            query_pv_excess(&client).await.unwrap(),
-            ExcessStatus::Yes,
-            "should have enough voltage for YES with SUN_LEVEL[1]"
+            mean_query(&client, "pvstatus", "pv_current", "30m").await,
+            Ok(Some(mean)) if mean == 42.5
         );
     }
 
@@ -367,6 +2134,7 @@ pub mod test {
                     query_output.into(),
                 ),
             ]),
+            ..Default::default()
         };
         assert_matches!(
             query_history_interval(&req, &client).await,
@@ -383,12 +2151,258 @@ pub mod test {
                     query_output.into(),
                 ),
             ]),
+            ..Default::default()
         };
         assert_matches!(
             query_history_interval(&reqwithmac, &client_mac).await,
             Ok(output) if output == query_output,
             "should query with workerstatus if Some(mac)"
         );
+        let reqwithmeasurement = IntervalReq::new(None, n, n + Duration::days(7))
+            .with_measurements(vec!["excessstatus".into()]);
+        let client_measurement = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    format!("SELECT battery_voltage, pv_voltage, pv_current, temperature FROM pvstatus WHERE {} ORDER BY time ASC;SELECT * FROM excessstatus WHERE {} ORDER BY time ASC",
+                        req.query_condition(), req.query_condition()),
+                    query_output.into(),
+                ),
+            ]),
+            ..Default::default()
+        };
+        assert_matches!(
+            query_history_interval(&reqwithmeasurement, &client_measurement).await,
+            Ok(output) if output == query_output,
+            "should query additional measurements"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_field_map() {
+        use chrono::{Duration, Utc};
+        init_logger();
+        let n = Utc::now();
+        let req = IntervalReq::new(None, n, n + Duration::days(1));
+        let field_map = HashMap::from([
+            ("pv_current".to_string(), "solar_current".to_string()),
+            ("battery_voltage".to_string(), "batt_v".to_string()),
+        ]);
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT mean(\"solar_current\") AS mean, count(\"solar_current\") AS count FROM pvstatus WHERE time > now() - 30m".into(),
+                    r#"[{"series": [{"name": "pvstatus", "columns": ["mean", "count"], "values": [[4.2, 10]]}]}]"#.into(),
+                ),
+                (
+                    format!("SELECT batt_v AS battery_voltage, pv_voltage, solar_current AS pv_current, temperature FROM pvstatus WHERE {} ORDER BY time ASC", req.query_condition()),
+                    "history".into(),
+                ),
+            ]),
+            field_map: field_map.clone(),
+        };
+        assert_matches!(
+            mean_query(&client, "pvstatus", "pv_current", "30m").await,
+            Ok(Some(_)),
+            "should query the mapped field"
+        );
+        assert_matches!(
+            query_history_interval(&req, &client).await,
+            Ok(h) if h == "history",
+            "should select mapped fields with their logical names"
+        );
+        let flux_client = InfluxClientMock {
+            field_map,
+            ..Default::default()
+        };
+        assert!(flux::history_script(
+            "test",
+            ("0", "1"),
+            "pvstatus",
+            &PV_FIELDS.map(|f| flux_client.field(f)),
+            None
+        )
+        .contains(r#"r._field == "batt_v""#));
+    }
+
+    #[test]
+    fn test_convert_timezone() {
+        let history = r#"{"results":[{"statement_id":0,"series":[{"name":"pvstatus",
+            "columns":["time","pv_current"],"values":[["2022-01-10T12:00:00Z",4.2]]}]}]}"#;
+        for (tz, expected) in [
+            (chrono_tz::Europe::Berlin, "2022-01-10T13:00:00+01:00"),
+            (chrono_tz::America::New_York, "2022-01-10T07:00:00-05:00"),
+        ] {
+            let converted: serde_json::Value =
+                serde_json::from_str(&convert_timezone(history, &tz).unwrap()).unwrap();
+            assert_eq!(
+                converted["results"][0]["series"][0]["values"][0],
+                serde_json::json!([expected, 4.2]),
+                "should convert time column to {:?}",
+                tz
+            );
+        }
+    }
+
+    #[test]
+    fn test_history_to_csv() {
+        let history = r#"{"results":[{"statement_id":0,"series":[{"name":"pvstatus",
+            "columns":["time","pv_current","battery_voltage"],"values":[
+                ["2022-01-10T12:00:00Z",4.2,12.8],
+                ["2022-01-10T12:05:00Z",null,12.9]]}]},
+            {"statement_id":1,"series":[{"name":"workerstatus",
+            "columns":["time","mac","status","note"],"values":[
+                ["2022-01-10T12:01:00Z","11:22:33:44:55:66",1,"up, \"idle\""]]}]}]}"#;
+        assert_eq!(
+            history_to_csv(history).unwrap(),
+            "name,time,pv_current,battery_voltage\n\
+             pvstatus,2022-01-10T12:00:00Z,4.2,12.8\n\
+             pvstatus,2022-01-10T12:05:00Z,,12.9\n\
+             name,time,mac,status,note\n\
+             workerstatus,2022-01-10T12:01:00Z,11:22:33:44:55:66,1,\"up, \"\"idle\"\"\"\n",
+            "should write a header row per measurement"
+        );
+        assert_eq!(
+            history_to_csv(r#"{"results":[{"statement_id":0}]}"#).unwrap(),
+            "",
+            "should write nothing without series"
+        );
+    }
+
+    #[test]
+    fn test_annotate_excess() {
+        let history = r#"{"results":[{"statement_id":0,"series":[{"name":"pvstatus",
+            "columns":["time","pv_current"],"values":[
+                ["2022-01-10T11:55:00Z",1.0],
+                ["2022-01-10T12:00:00Z",4.2],
+                ["2022-01-10T12:07:00Z",20.5],
+                ["2022-01-10T12:20:00Z",30.1]]}]},
+            {"statement_id":1,"series":[{"name":"excessstatus",
+            "columns":["time","excess"],"values":[
+                ["2022-01-10T12:00:00Z",0],
+                ["2022-01-10T12:10:00Z",2]]}]}]}"#;
+        let annotated: serde_json::Value =
+            serde_json::from_str(&annotate_excess(history).unwrap()).unwrap();
+        assert_eq!(
+            annotated["results"].as_array().unwrap().len(),
+            1,
+            "should merge the excess series"
+        );
+        let series = &annotated["results"][0]["series"][0];
+        assert_eq!(
+            series["columns"],
+            serde_json::json!(["time", "pv_current", "excess"])
+        );
+        assert_eq!(
+            series["values"],
+            serde_json::json!([
+                ["2022-01-10T11:55:00Z", 1.0, null],
+                ["2022-01-10T12:00:00Z", 4.2, 0],
+                ["2022-01-10T12:07:00Z", 20.5, 0],
+                ["2022-01-10T12:20:00Z", 30.1, 2]
+            ]),
+            "should annotate rows with the excess active at their time"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_all_worker_status() {
+        init_logger();
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                "SELECT last(\"status\") AS status, wake FROM workerstatus GROUP BY mac".into(),
+                r#"[{"series": [
+                    {"name": "workerstatus", "tags": {"mac": "11:22:33:44:55:77"}, "columns": ["time", "status", "wake"], "values": [["2022-01-10T12:00:00Z", 3, false]]},
+                    {"name": "workerstatus", "tags": {"mac": "11:22:33:44:55:66"}, "columns": ["time", "status", "wake"], "values": [["2022-01-10T11:55:00Z", 0, true]]}
+                ]}]"#
+                    .into(),
+            )]),
+            ..Default::default()
+        };
+        let workers = query_all_worker_status(&client).await.unwrap();
+        assert_eq!(
+            workers,
+            vec![
+                WorkerLastStatus {
+                    mac: "11:22:33:44:55:66".into(),
+                    status: 0,
+                    wake: true,
+                    last_seen: "2022-01-10T11:55:00Z".parse().unwrap(),
+                    wake_history: None,
+                },
+                WorkerLastStatus {
+                    mac: "11:22:33:44:55:77".into(),
+                    status: 3,
+                    wake: false,
+                    last_seen: "2022-01-10T12:00:00Z".parse().unwrap(),
+                    wake_history: None,
+                },
+            ],
+            "should list the last status of each mac (ordered by mac)"
+        );
+        assert_eq!(
+            serde_json::to_value(&workers[0]).unwrap(),
+            serde_json::json!({
+                "mac": "11:22:33:44:55:66",
+                "status": 0,
+                "wake": true,
+                "last_seen": "2022-01-10T11:55:00Z"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_wake_counts() {
+        init_logger();
+        let count_resp = |counts: &[(&str, u64)]| {
+            let series: Vec<String> = counts
+                .iter()
+                .map(|(m, n)| {
+                    format!(
+                        r#"{{"name": "workerstatus", "tags": {{"mac": "{}"}}, "columns": ["time", "count"], "values": [["1970-01-01T00:00:00Z", {}]]}}"#,
+                        m, n
+                    )
+                })
+                .collect();
+            format!(r#"[{{"series": [{}]}}]"#, series.join(","))
+        };
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT count(\"status\") AS count FROM workerstatus WHERE time > now() - 3600s AND wake = true GROUP BY mac"
+                        .into(),
+                    count_resp(&[("11:22:33:44:55:66", 12), ("11:22:33:44:55:77", 3)]),
+                ),
+                (
+                    "SELECT count(\"status\") AS count FROM workerstatus WHERE time > now() - 3600s AND wake = false GROUP BY mac"
+                        .into(),
+                    count_resp(&[("11:22:33:44:55:66", 4)]),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let counts = query_wake_counts(&client, Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            counts,
+            HashMap::from([
+                (
+                    "11:22:33:44:55:66".to_string(),
+                    WakeCounts {
+                        wake_true: 12,
+                        wake_false: 4
+                    }
+                ),
+                (
+                    "11:22:33:44:55:77".to_string(),
+                    WakeCounts {
+                        wake_true: 3,
+                        wake_false: 0
+                    }
+                ),
+            ]),
+            "should count the reports of each mac by wake intent"
+        );
     }
 
     #[tokio::test]
@@ -455,8 +2469,9 @@ pub mod test {
                 "SELECT last(\"status\") AS status,wake,time FROM workerstatus GROUP BY mac".into(),
                 query_output.into(),
             )]),
+            ..Default::default()
         };
-        let stale_macs: Vec<(String, bool)> = query_stale_macs(&client)
+        let stale_macs: Vec<(String, bool)> = query_stale_macs(&client, None)
             .await
             .unwrap()
             .into_iter()
@@ -468,8 +2483,238 @@ pub mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_never_stale() {
+        init_logger();
+        let time_stale = Utc::now() - Duration::minutes(WORKER_STALE_MINS + 1);
+        let serie = r#"{
+            "name":"workerstatus",
+            "tags": { "mac": "MAC" },
+            "columns": ["time", "status", "wake"],
+            "values": [ VALUES ]
+        }"#;
+        let query_output = r#"[{"series": [SERIES]}]"#.replace(
+            "SERIES",
+            &[
+                ("11:22:33:44:55:66", WorkerStatus::Working as i32),
+                ("11:22:33:44:55:77", WorkerStatus::Inquisitive as i32),
+            ]
+            .into_iter()
+            .map(|(m, s)| {
+                serie.replace("MAC", m).replace(
+                    "VALUES",
+                    &format!("[\"{}\", {}, true]", time_stale.to_rfc3339(), s),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(","),
+        );
+        let mut client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                "SELECT last(\"status\") AS status,wake,time FROM workerstatus GROUP BY mac".into(),
+                query_output,
+            )]),
+            ..Default::default()
+        };
+        assert_eq!(query_stale_macs(&client, None).await.unwrap().len(), 2);
+        client.never_stale = vec!["working".parse::<WorkerStatus>().unwrap() as i32];
+        assert_eq!(
+            query_stale_macs(&client, None).await.unwrap(),
+            vec![("11:22:33:44:55:77".parse().unwrap(), true)],
+            "should exclude working mac past the stale window"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_stale_macs_max_age() {
+        init_logger();
+        let now = Utc::now();
+        let serie = r#"{
+            "name":"workerstatus",
+            "tags": { "mac": "MAC" },
+            "columns": COLUMNS,
+            "values": [ VALUES ]
+        }"#;
+        let series = |entries: &[(&str, String)], columns: &str| {
+            r#"[{"series": [SERIES]}]"#.replace(
+                "SERIES",
+                &entries
+                    .iter()
+                    .map(|(m, v)| {
+                        serie
+                            .replace("MAC", m)
+                            .replace("COLUMNS", columns)
+                            .replace("VALUES", v)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )
+        };
+        // the heartbeat keeps logging Sleep points of candidates
+        let sleep = format!("[\"{}\", 0, true]", now.to_rfc3339());
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT last(\"status\") AS status,wake,time FROM workerstatus GROUP BY mac"
+                        .into(),
+                    series(
+                        &[
+                            ("11:22:33:44:55:66", sleep.clone()),
+                            ("11:22:33:44:55:77", sleep.clone()),
+                            ("11:22:33:44:55:88", sleep.clone()),
+                        ],
+                        r#"["time", "status", "wake"]"#,
+                    ),
+                ),
+                (
+                    "SELECT last(\"status\") AS status FROM workerstatus WHERE status >= 2 GROUP BY mac"
+                        .into(),
+                    series(
+                        &[
+                            (
+                                "11:22:33:44:55:66",
+                                format!("[\"{}\", 3]", (now - Duration::days(100)).to_rfc3339()),
+                            ),
+                            (
+                                "11:22:33:44:55:77",
+                                format!("[\"{}\", 3]", (now - Duration::days(1)).to_rfc3339()),
+                            ),
+                        ],
+                        r#"["time", "status"]"#,
+                    ),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let stale_macs: Vec<String> = query_stale_macs(&client, Some(Duration::days(30)))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(m, _)| m.to_string())
+            .collect();
+        assert_eq!(
+            stale_macs,
+            vec!["11:22:33:44:55:77"],
+            "should exclude candidates last seen before max age or never seen"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flux_queries() {
+        init_logger();
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    flux::mean_script("test", "pvstatus", "pv_current", "30m"),
+                    ",result,table,count,_value\r\n,_result,0,10,42.5\r\n".into(),
+                ),
+                (
+                    flux::last_status_script("test", "workerstatus"),
+                    format!(
+                        ",result,table,_time,mac,status,wake\r\n\
+                        ,_result,0,{},11:22:33:44:55:66,0,true\r\n\
+                        ,_result,1,{},11:22:33:44:55:77,3,true\r\n\
+                        ,_result,2,{},11:22:33:44:55:88,0,false\r\n",
+                        Utc::now().to_rfc3339(),
+                        (Utc::now() - Duration::minutes(WORKER_STALE_MINS + 1)).to_rfc3339(),
+                        Utc::now().to_rfc3339(),
+                    ),
+                ),
+            ]),
+            ..Default::default()
+        };
+        assert_matches!(
+            mean_query(&client, "pvstatus", "pv_current", "30m").await,
+            Ok(Some(mean)) if mean == 42.5,
+            "should query mean with flux"
+        );
+        let stale_macs: Vec<(String, bool)> = query_stale_macs(&client, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(m, b)| (m.to_string(), b))
+            .collect();
+        assert_eq!(
+            stale_macs,
+            vec![
+                ("11:22:33:44:55:66".to_string(), true),
+                ("11:22:33:44:55:77".to_string(), true)
+            ],
+            "should query stale macs with flux"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_retry() {
+        let query = "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m";
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                query.into(),
+                r#"[{"series": [{"name": "pvstatus", "columns": ["mean", "count"], "values": [[30.0, 10]]}]}]"#.into(),
+            )]),
+            connection_failures: AtomicU32::new(2),
+            ..Default::default()
+        };
+        let delay = std::time::Duration::from_millis(1);
+        assert_matches!(
+            with_retry(3, delay, || client.json_query(ReadQuery::new(query))).await,
+            Ok(_),
+            "should succeed after two connection errors"
+        );
+        client.connection_failures.store(2, Ordering::SeqCst);
+        assert_matches!(
+            with_retry(1, delay, || client.json_query(ReadQuery::new(query))).await,
+            Err(influxdb::Error::ConnectionError { .. }),
+            "should give up after the retries"
+        );
+        let calls = AtomicU32::new(0);
+        assert_matches!(
+            with_retry(3, delay, || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async {
+                    Err::<(), _>(influxdb::Error::DeserializationError {
+                        error: "mocked".into(),
+                    })
+                }
+            })
+            .await,
+            Err(influxdb::Error::DeserializationError { .. })
+        );
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            1,
+            "should not retry deserialization errors"
+        );
+    }
+
+    #[derive(Debug, Default)]
     pub struct InfluxClientMock {
-        answer_map: HashMap<String, String>,
+        pub answer_map: HashMap<String, String>,
+        pub field_map: HashMap<String, String>,
+        pub worker_shards: u32,
+        // WEEKDAY_THRESHOLDS if None
+        pub weekday_thresholds: Option<ExcessThresholds>,
+        pub weekend_thresholds: Option<ExcessThresholds>,
+        // pvstatus and pv_current if None
+        pub sun_measurement: Option<String>,
+        pub sun_field: Option<String>,
+        // 1.0 if None
+        pub voltage_scale: Option<f32>,
+        // Voltage if None
+        pub excess_signal: Option<ExcessSignal>,
+        // battery_voltage if empty
+        pub voltage_fields: Vec<String>,
+        // Mean if None
+        pub voltage_aggregation: Option<Aggregation>,
+        pub temperature_gate: Option<TemperatureGate>,
+        pub clamp_negative_current: bool,
+        pub require_charging: bool,
+        pub never_stale: Vec<i32>,
+        // mock an unreachable database on ping
+        pub ping_err: bool,
+        // mock connection errors of this many queries
+        pub connection_failures: AtomicU32,
+        pub excess_url: Option<String>,
     }
 
     impl InfluxClientMock {
@@ -477,6 +2722,15 @@ pub mod test {
         where
             Q: Query + Send,
         {
+            if self
+                .connection_failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(influxdb::Error::ConnectionError {
+                    error: "mocked connection reset".into(),
+                });
+            }
             let query_str = query.build()?.get();
             let key = if let influxdb::QueryType::ReadQuery = query.get_type() {
                 if self.answer_map.contains_key(&query_str) {
@@ -490,9 +2744,18 @@ pub mod test {
             assert!(key.is_some(), "Incorrect query: '{}'", &query_str);
             let k = key.unwrap();
             debug!("Mock query resonse for: {}", k);
-            Ok(self.answer_map[k].clone())
+            match self.answer_map[k].strip_prefix("ERROR: ") {
+                // mock a database error
+                Some(error) => Err(influxdb::Error::DatabaseError {
+                    error: error.into(),
+                }),
+                None => Ok(self.answer_map[k].clone()),
+            }
         }
     }
+    // mocked MIN_SAMPLES
+    pub const MOCK_MIN_SAMPLES: u32 = 3;
+
     fn init_logger() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
@@ -512,15 +2775,101 @@ pub mod test {
         }
         async fn query<Q>(&self, q: Q) -> Result<String, influxdb::Error>
         where
-            Q: Query + Send,
+            Q: Query + Clone + Send,
         {
             Ok(self.query_result(q)?.to_string())
         }
+        async fn flux_query(&self, script: String) -> Result<String, influxdb::Error> {
+            self.query_result(ReadQuery::new(script))
+        }
+        async fn ping(&self) -> Result<(), influxdb::Error> {
+            if self.ping_err {
+                Err(influxdb::Error::ConnectionError {
+                    error: "mocked unreachable database".into(),
+                })
+            } else {
+                Ok(())
+            }
+        }
+        fn query_language(&self) -> QueryLanguage {
+            // mock flux if answers are given for flux scripts
+            if self
+                .answer_map
+                .keys()
+                .any(|k| k.starts_with("from(bucket:"))
+            {
+                QueryLanguage::Flux
+            } else {
+                QueryLanguage::InfluxQL
+            }
+        }
+        fn bucket(&self) -> &str {
+            "test"
+        }
         fn workerstatus(&self) -> &str {
             "workerstatus"
         }
         fn pvstatus(&self) -> &str {
             "pvstatus"
         }
+        fn min_samples(&self) -> u32 {
+            MOCK_MIN_SAMPLES
+        }
+        fn worker_shards(&self) -> u32 {
+            self.worker_shards
+        }
+        fn field<'a>(&'a self, logical: &'a str) -> &'a str {
+            self.field_map
+                .get(logical)
+                .map(|f| f.as_str())
+                .unwrap_or(logical)
+        }
+        fn weekday_thresholds(&self) -> &ExcessThresholds {
+            self.weekday_thresholds
+                .as_ref()
+                .unwrap_or(&WEEKDAY_THRESHOLDS)
+        }
+        fn weekend_thresholds(&self) -> Option<&ExcessThresholds> {
+            self.weekend_thresholds.as_ref()
+        }
+        fn sun_measurement(&self) -> &str {
+            self.sun_measurement.as_deref().unwrap_or("pvstatus")
+        }
+        fn sun_field(&self) -> &str {
+            self.sun_field.as_deref().unwrap_or("pv_current")
+        }
+        fn voltage_scale(&self) -> f32 {
+            self.voltage_scale.unwrap_or(1.0)
+        }
+        fn excess_signal(&self) -> &ExcessSignal {
+            self.excess_signal
+                .as_ref()
+                .unwrap_or(&ExcessSignal::Voltage)
+        }
+        fn voltage_fields(&self) -> Vec<&str> {
+            if self.voltage_fields.is_empty() {
+                vec!["battery_voltage"]
+            } else {
+                self.voltage_fields.iter().map(|f| f.as_str()).collect()
+            }
+        }
+        fn voltage_aggregation(&self) -> Aggregation {
+            self.voltage_aggregation.unwrap_or(Aggregation::Mean)
+        }
+        fn temperature_gate(&self) -> Option<&TemperatureGate> {
+            self.temperature_gate.as_ref()
+        }
+        fn never_stale(&self) -> &[i32] {
+            &self.never_stale
+        }
+        fn clamp_negative_current(&self) -> bool {
+            self.clamp_negative_current
+        }
+        fn require_charging(&self) -> bool {
+            self.require_charging
+        }
+        fn excess_url(&self) -> Option<&str> {
+            self.excess_url.as_deref()
+        }
     }
 }
@@ -1,7 +1,7 @@
 use crate::context::Context;
 use crate::errors::ApiError;
-use crate::influx_gateway::query_history_interval;
-use crate::server::RequestHandler;
+use crate::influx_gateway::{annotate_excess, convert_timezone, history_to_csv};
+use crate::server::{query_param, RequestHandler};
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use mac_address::MacAddress;
@@ -12,9 +12,39 @@ pub struct IntervalReq {
     mac: Option<MacAddress>,
     start: DateTime<Utc>,
     stop: DateTime<Utc>,
+    // additional (allowlisted) measurements included in the history
+    #[serde(default)]
+    measurements: Vec<String>,
+    // add the excess active at each pvstatus row
+    #[serde(default)]
+    annotate_excess: bool,
 }
 
 impl IntervalReq {
+    pub fn from_query(query_str: &str) -> Result<Self, ApiError> {
+        // '?start=<rfc3339>&stop=<rfc3339>[&mac=<mac>]'
+        let time = |key: &str| {
+            let v = query_param(query_str, key)
+                .ok_or_else(|| api_baderr!("Missing query parameter '{}'!", key))?;
+            DateTime::parse_from_rfc3339(&v)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|e| api_baderr!("Invalid {} '{}'! {}", key, v, e))
+        };
+        let mac = match query_param(query_str, "mac") {
+            Some(m) => Some(
+                m.parse()
+                    .map_err(|e| api_baderr!("Invalid mac '{}'! {}", m, e))?,
+            ),
+            None => None,
+        };
+        Ok(IntervalReq {
+            mac,
+            start: time("start")?,
+            stop: time("stop")?,
+            measurements: vec![],
+            annotate_excess: false,
+        })
+    }
     pub fn query_condition(&self) -> String {
         format!(
             "time > '{}' AND time < '{}'",
@@ -22,37 +52,147 @@ impl IntervalReq {
             self.stop.to_rfc3339()
         )
     }
+    pub fn range(&self) -> (String, String) {
+        (self.start.to_rfc3339(), self.stop.to_rfc3339())
+    }
     pub fn mac(&self) -> Option<MacAddress> {
         self.mac
     }
+    pub fn measurements(&self) -> &[String] {
+        &self.measurements
+    }
+    pub fn annotate_excess(&self) -> bool {
+        self.annotate_excess
+    }
 }
 
 const MAX_QUERY_DAYS: i64 = 20;
-fn validate_request(req: &IntervalReq) -> Result<(), ApiError> {
+fn validate_request(
+    req: &IntervalReq,
+    allowed_measurements: &[String],
+    min_duration: Duration,
+) -> Result<(), ApiError> {
     let dur = req.stop - req.start;
-    if dur > Duration::days(MAX_QUERY_DAYS) {
+    if dur < Duration::zero() {
+        Err(api_baderr!("Reversed interval (stop is before start)!"))
+    } else if dur == Duration::zero() {
+        Err(api_baderr!("Empty interval (start equals stop)!"))
+    } else if dur < min_duration {
+        Err(api_baderr!(
+            "'{}' is below min query duration '{}'!",
+            dur,
+            min_duration
+        ))
+    } else if dur > Duration::days(MAX_QUERY_DAYS) {
         Err(api_baderr!("'{}' exceeded max query duration!", dur))
+    } else if let Some(m) = req
+        .measurements
+        .iter()
+        .find(|m| !allowed_measurements.contains(m))
+    {
+        Err(api_baderr!("'{}' is not an allowed measurement!", m))
     } else {
         Ok(())
     }
 }
 
+fn restrict_to_self(
+    mac: Option<MacAddress>,
+    remote_mac: Option<MacAddress>,
+) -> Result<Option<MacAddress>, ApiError> {
+    // only the history of the requester (never of another mac)
+    let remote_mac = remote_mac.ok_or_else(|| {
+        api_err!(
+            hyper::StatusCode::FORBIDDEN,
+            "mac address of requestor not found!"
+        )
+    })?;
+    match mac {
+        Some(m) if m != remote_mac => Err(api_err!(
+            hyper::StatusCode::FORBIDDEN,
+            "Querying the history of {} is not allowed!",
+            m
+        )),
+        _ => Ok(Some(remote_mac)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct IntervalRes {
+    pub history: String,
+    // the history is partial (workerstatus query failed)
+    pub warning: Option<String>,
+    // duration of the history queries
+    pub query_ms: u128,
+}
+
 pub struct IntervalRequestHandler {}
 
 #[async_trait]
-impl RequestHandler<IntervalReq, String> for IntervalRequestHandler {
-    async fn handle(&self, req: IntervalReq, context: Context) -> Result<String, ApiError> {
+impl RequestHandler<IntervalReq, IntervalRes> for IntervalRequestHandler {
+    async fn handle(&self, req: IntervalReq, context: Context) -> Result<IntervalRes, ApiError> {
         let mut req = req;
-        if req.mac.is_none() {
+        if context.restrict_interval_to_self {
+            req.mac = restrict_to_self(req.mac, context.remote_mac().await?)?;
+        } else if req.mac.is_none() {
             // try using the mac of the requester for query
             req.mac = context.remote_mac().await?;
         }
-        if let Err(e) = validate_request(&req) {
+        if let Err(e) = validate_request(
+            &req,
+            &context.interval_measurements,
+            context.min_query_duration,
+        ) {
             Err(e)
+        } else if req.annotate_excess && !context.status_store.history_is_json() {
+            Err(api_baderr!(
+                "Excess annotation requires an InfluxQL history!"
+            ))
         } else {
-            Ok(query_history_interval(&req, &context.influx_client)
-                .await
-                .map_err(|e| fwd_err!("Query failed! {}", e))?)
+            let query_start = std::time::Instant::now();
+            let (history, warning) = match context.status_store.history_interval(&req).await {
+                Ok(history) => (history, None),
+                Err(e) if context.partial_history && req.mac.is_some() => {
+                    // degrade to pvstatus history without the workerstatus
+                    warn!("Query with workerstatus failed, retrying without! {}", e);
+                    let pv_req = IntervalReq { mac: None, ..req };
+                    let history = context
+                        .status_store
+                        .history_interval(&pv_req)
+                        .await
+                        .map_err(|e| fwd_err!("Query failed! {}", e))?;
+                    (history, Some(format!("workerstatus query failed! {}", e)))
+                }
+                Err(e) => return Err(fwd_err!("Query failed! {}", e)),
+            };
+            let query_ms = query_start.elapsed().as_millis();
+            let history = if req.annotate_excess {
+                annotate_excess(&history)
+                    .map_err(|e| server_err!("Failed to annotate history with excess! {}", e))?
+            } else {
+                history
+            };
+            // flux responds with csv
+            let history = if context.response_timezone == chrono_tz::UTC
+                || !context.status_store.history_is_json()
+            {
+                history
+            } else {
+                convert_timezone(&history, &context.response_timezone)
+                    .map_err(|e| server_err!("Failed to convert history timezone! {}", e))?
+            };
+            // flux history is already csv
+            let history = if context.accept_csv && context.status_store.history_is_json() {
+                history_to_csv(&history)
+                    .map_err(|e| server_err!("Failed to convert history to csv! {}", e))?
+            } else {
+                history
+            };
+            Ok(IntervalRes {
+                history,
+                warning,
+                query_ms,
+            })
         }
     }
 }
@@ -60,11 +200,26 @@ impl RequestHandler<IntervalReq, String> for IntervalRequestHandler {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::influx_gateway::test::InfluxClientMock;
     use chrono::{DateTime, Duration, Utc};
+    use std::collections::HashMap;
+    use std::sync::Arc;
 
     impl IntervalReq {
         pub fn new(mac: Option<MacAddress>, start: DateTime<Utc>, stop: DateTime<Utc>) -> Self {
-            IntervalReq { mac, start, stop }
+            IntervalReq {
+                mac,
+                start,
+                stop,
+                measurements: vec![],
+                annotate_excess: false,
+            }
+        }
+        pub fn with_measurements(self, measurements: Vec<String>) -> Self {
+            IntervalReq {
+                measurements,
+                ..self
+            }
         }
     }
     #[test]
@@ -74,9 +229,162 @@ mod test {
             mac: None,
             start: n,
             stop: n + Duration::days(MAX_QUERY_DAYS),
+            measurements: vec![],
+            annotate_excess: false,
         };
-        assert_matches!(validate_request(&req), Ok(()));
+        assert_matches!(validate_request(&req, &[], Duration::zero()), Ok(()));
         req.stop = n + Duration::days(MAX_QUERY_DAYS + 1);
-        assert_matches!(validate_request(&req), Err(_));
+        assert_matches!(validate_request(&req, &[], Duration::zero()), Err(_));
+    }
+
+    #[test]
+    fn test_from_query() {
+        for query_str in [
+            "start=2022-01-10T11:00:00Z&stop=2022-01-10T13:00:00%2B01:00&mac=11:22:33:44:55:66",
+            // e.g. 'curl -G --data-urlencode' or URLSearchParams
+            "start=2022-01-10T11%3A00%3A00Z&stop=2022-01-10T13%3A00%3A00%2B01%3A00&mac=11%3A22%3A33%3A44%3A55%3A66",
+        ] {
+            let req = IntervalReq::from_query(query_str).unwrap();
+            assert_eq!(
+                req.range(),
+                (
+                    "2022-01-10T11:00:00+00:00".to_string(),
+                    "2022-01-10T12:00:00+00:00".to_string()
+                ),
+                "should decode '{}'",
+                query_str
+            );
+            assert_eq!(req.mac(), "11:22:33:44:55:66".parse().ok());
+        }
+        assert_matches!(
+            IntervalReq::from_query("start=2022-01-10T11:00:00Z&stop=2022-01-10T12:00:00Z"),
+            Ok(IntervalReq { mac: None, .. })
+        );
+        for query_str in [
+            "start=2022-01-10T11:00:00Z",
+            "start=yesterday&stop=2022-01-10T12:00:00Z",
+            "start=2022-01-10T11:00:00Z&stop=2022-01-10T12:00:00Z&mac=11:22",
+            // an unencoded '+' is a space
+            "start=2022-01-10T11:00:00Z&stop=2022-01-10T13:00:00+01:00",
+        ] {
+            assert_matches!(
+                IntervalReq::from_query(query_str),
+                Err(e) if e.code == hyper::StatusCode::BAD_REQUEST,
+                "should reject '{}'",
+                query_str
+            );
+        }
+    }
+
+    #[test]
+    fn test_restrict_to_self() {
+        let own: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let foreign: MacAddress = "11:22:33:44:55:77".parse().unwrap();
+        assert_matches!(
+            restrict_to_self(None, Some(own)),
+            Ok(Some(m)) if m == own,
+            "should query the mac of the requester"
+        );
+        assert_matches!(restrict_to_self(Some(own), Some(own)), Ok(Some(m)) if m == own);
+        assert_matches!(
+            restrict_to_self(Some(foreign), Some(own)),
+            Err(e) if e.code == hyper::StatusCode::FORBIDDEN,
+            "should reject a foreign mac"
+        );
+        assert_matches!(
+            restrict_to_self(None, None),
+            Err(e) if e.code == hyper::StatusCode::FORBIDDEN,
+            "should reject requesters without a mac"
+        );
+    }
+
+    #[test]
+    fn test_interval_bounds_validation() {
+        let n = Utc::now();
+        assert_matches!(
+            validate_request(&IntervalReq::new(None, n, n), &[], Duration::zero()),
+            Err(e) if e.message.contains("Empty interval"),
+            "should reject zero-length interval"
+        );
+        assert_matches!(
+            validate_request(
+                &IntervalReq::new(None, n, n - Duration::hours(1)),
+                &[],
+                Duration::zero()
+            ),
+            Err(e) if e.message.contains("Reversed interval"),
+            "should reject reversed interval"
+        );
+        let req = IntervalReq::new(None, n, n + Duration::minutes(5));
+        assert_matches!(
+            validate_request(&req, &[], Duration::minutes(10)),
+            Err(e) if e.message.contains("below min query duration"),
+            "should reject interval below min duration"
+        );
+        assert_matches!(validate_request(&req, &[], Duration::minutes(5)), Ok(()));
+    }
+
+    #[test]
+    fn test_measurements_validation() {
+        let n = Utc::now();
+        let allowed = vec!["excessstatus".to_string()];
+        let mut req = IntervalReq::new(None, n, n + Duration::days(1));
+        req.measurements = vec!["excessstatus".into()];
+        assert_matches!(
+            validate_request(&req, &allowed, Duration::zero()),
+            Ok(()),
+            "should accept allowlisted measurement"
+        );
+        req.measurements = vec!["excessstatus".into(), "secrets".into()];
+        assert_matches!(
+            validate_request(&req, &allowed, Duration::zero()),
+            Err(e) if e.message.contains("'secrets'"),
+            "should reject measurement which is not allowlisted"
+        );
+        req.measurements = vec!["".into()];
+        assert_matches!(
+            validate_request(&req, &allowed, Duration::zero()),
+            Err(_),
+            "should reject an empty measurement"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_history() {
+        let n = Utc::now();
+        let req = || IntervalReq::new("11:11:11:11:11:11".parse().ok(), n, n + Duration::days(1));
+        let pv_query = format!(
+            "SELECT battery_voltage, pv_voltage, pv_current, temperature FROM pvstatus WHERE {} ORDER BY time ASC",
+            req().query_condition()
+        );
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    format!(
+                        "{};SELECT status, wake FROM workerstatus WHERE {} AND mac = '11:11:11:11:11:11' ORDER BY time ASC",
+                        pv_query,
+                        req().query_condition()
+                    ),
+                    "ERROR: measurement not found".into(),
+                ),
+                (pv_query, r#"{"results": []}"#.into()),
+            ]),
+            ..Default::default()
+        };
+        let mut context = Context::load().unwrap();
+        context.status_store = Arc::new(client);
+        context.partial_history = false;
+        assert_matches!(
+            IntervalRequestHandler {}.handle(req(), context.clone()).await,
+            Err(e) if e.code == hyper::StatusCode::BAD_GATEWAY,
+            "should fail without partial history"
+        );
+        context.partial_history = true;
+        assert_matches!(
+            IntervalRequestHandler {}.handle(req(), context).await,
+            Ok(IntervalRes { history, warning: Some(w), .. })
+                if history == r#"{"results": []}"# && w.contains("measurement not found"),
+            "should respond with pvstatus history and a warning"
+        );
     }
 }
@@ -0,0 +1,72 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+// coalesces repeated identical log messages within a window
+#[derive(Debug, Default)]
+pub struct LogThrottle {
+    // time the message was last emitted and the number of suppressed repetitions since
+    messages: HashMap<String, (DateTime<Utc>, u32)>,
+}
+
+impl LogThrottle {
+    pub fn check(&mut self, msg: &str, window: Duration, now: DateTime<Utc>) -> Option<String> {
+        // the message to log (with a summary of the suppressed repetitions) or None
+        if window <= Duration::zero() {
+            return Some(msg.to_string());
+        }
+        // forget expired messages without repetitions
+        self.messages
+            .retain(|_, (emitted, suppressed)| *suppressed > 0 || *emitted > now - window);
+        match self.messages.get_mut(msg) {
+            Some((emitted, suppressed)) if *emitted > now - window => {
+                *suppressed += 1;
+                None
+            }
+            entry => {
+                let suppressed = entry.map(|(_, s)| *s).unwrap_or(0);
+                self.messages.insert(msg.to_string(), (now, 0));
+                if suppressed > 0 {
+                    Some(format!("{} (repeated {} times)", msg, suppressed))
+                } else {
+                    Some(msg.to_string())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_throttle() {
+        let mut throttle = LogThrottle::default();
+        let window = Duration::minutes(10);
+        let now = Utc::now();
+        let msg = "pv excess query failed!";
+        assert_eq!(throttle.check(msg, window, now), Some(msg.to_string()));
+        for minutes in 1..4 {
+            assert_eq!(
+                throttle.check(msg, window, now + Duration::minutes(minutes)),
+                None,
+                "should suppress repetitions within the window"
+            );
+        }
+        assert_eq!(
+            throttle.check("other error!", window, now),
+            Some("other error!".into()),
+            "should not suppress other messages"
+        );
+        assert_eq!(
+            throttle.check(msg, window, now + Duration::minutes(11)),
+            Some(format!("{} (repeated 3 times)", msg)),
+            "should summarize repetitions after the window"
+        );
+        assert_eq!(
+            throttle.check(msg, Duration::zero(), now),
+            Some(msg.to_string()),
+            "should not throttle without a window"
+        );
+    }
+}
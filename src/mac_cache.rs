@@ -0,0 +1,66 @@
+use chrono::{DateTime, Duration, Utc};
+use mac_address::MacAddress;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacCacheMode {
+    // reuse resolved macs for the cache ttl
+    Ttl,
+    // resolve the mac on each request (networks with DHCP churn)
+    Off,
+}
+
+impl FromStr for MacCacheMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ttl" => Ok(MacCacheMode::Ttl),
+            "off" => Ok(MacCacheMode::Off),
+            _ => Err(format!("Unknown mac cache mode '{}'!", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MacCache {
+    // resolved macs with the time of resolution
+    entries: HashMap<IpAddr, (MacAddress, DateTime<Utc>)>,
+}
+
+impl MacCache {
+    pub fn get(&self, ip: &IpAddr, ttl: Duration, now: DateTime<Utc>) -> Option<MacAddress> {
+        self.entries
+            .get(ip)
+            .filter(|(_, t)| *t > now - ttl)
+            .map(|(m, _)| *m)
+    }
+    pub fn insert(&mut self, ip: IpAddr, mac: MacAddress, now: DateTime<Utc>) {
+        self.entries.insert(ip, (mac, now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mac_cache() {
+        let ip: IpAddr = "192.168.178.2".parse().unwrap();
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let ttl = Duration::seconds(60);
+        let now = Utc::now();
+        let mut cache = MacCache::default();
+        assert_eq!(cache.get(&ip, ttl, now), None);
+        cache.insert(ip, mac, now);
+        assert_eq!(cache.get(&ip, ttl, now + Duration::seconds(59)), Some(mac));
+        assert_eq!(
+            cache.get(&ip, ttl, now + ttl),
+            None,
+            "should expire after the ttl"
+        );
+        assert_matches!("OFF".parse(), Ok(MacCacheMode::Off));
+        assert_matches!("lru".parse::<MacCacheMode>(), Err(_));
+    }
+}
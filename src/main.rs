@@ -13,35 +13,136 @@ extern crate assert_matches;
 
 #[macro_use]
 mod macros;
+mod admin_handler;
+mod backoff;
+mod circuit_breaker;
+mod config;
 mod context;
+mod debug_handler;
 mod errors;
 mod influx_gateway;
+mod log_throttle;
+mod mac_cache;
+mod metrics;
 mod neighbor;
+#[cfg(feature = "otel")]
+mod otel;
+mod selftest;
 mod server;
+mod shutdown;
+mod solar;
+mod state_file;
+mod status_store;
+mod wake_handler;
 mod wake_heartbeat;
+mod wake_spread;
+mod wake_transport;
+mod wake_webhook;
+mod workers_handler;
 mod interval_handler;
 mod excess_handler;
+mod excess_smoother;
+mod excess_source;
+mod flux;
 mod report_handler;
 
+use std::process::ExitCode;
+
+// sysexits.h: configuration error (do not restart)
+const EXIT_CONFIG: u8 = 78;
+// runtime error (restart)
+const EXIT_RUNTIME: u8 = 1;
+
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     env_logger::init();
-    let context_r = crate::context::Context::load();
-    if let Err(e) = context_r {
-        error!("Invalid configuration! {}", e);
-        panic!();
+    let args: Vec<String> = std::env::args().collect();
+    let config = config::ConfigSource::load();
+    if matches!(&config, Ok(config) if selftest::selftest_requested(config, &args)) {
+        // verify the WOL setup and exit
+        return match selftest::selftest_wol(std::time::Duration::from_secs(5)).await {
+            Ok(()) => {
+                info!("[selftest] WOL ok");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!("[selftest] WOL failed! {:#}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    #[cfg(feature = "otel")]
+    if let Ok(config) = &config {
+        match otel::init_otel(config) {
+            Ok(true) => info!("[otel] exporting spans"),
+            Ok(false) => {}
+            Err(e) => {
+                error!("Invalid configuration! {}", e);
+                return ExitCode::from(EXIT_CONFIG);
+            }
+        }
     }
+    let exit_code =
+        run(config.and_then(|config| crate::context::Context::load_from(&config))).await;
+    #[cfg(feature = "otel")]
+    otel::shutdown_otel();
+    exit_code
+}
+
+async fn run(context_r: Result<crate::context::Context, String>) -> ExitCode {
     // 'context' provides config and state to the request handlers
-    let context = context_r.unwrap();
-    let wake_heartbeat = wake_heartbeat::wake_heartbeat_loop(context.clone());
+    let context = match context_r {
+        Ok(context) => context,
+        Err(e) => {
+            error!("Invalid configuration! {}", e);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    if context.startup_warmup {
+        let warmup_context = context.clone();
+        tokio::spawn(async move { warmup_context.warmup().await });
+    }
+    // shared by the server and the heartbeat
+    let shutdown = match shutdown::shutdown_signal() {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            error!("Failed to listen for shutdown signals! {}", e);
+            return ExitCode::from(EXIT_RUNTIME);
+        }
+    };
+    use futures::{FutureExt, TryFutureExt};
+    let wake_heartbeat = if context.isolate_heartbeat {
+        wake_heartbeat::spawn_isolated_heartbeat(context.clone(), shutdown.clone()).left_future()
+    } else {
+        wake_heartbeat::wake_heartbeat_loop(context.clone(), shutdown.clone())
+            .map_err(errors::GenericError::from)
+            .right_future()
+    };
 
-    info!("[Informant-Server] {}", context.local_addr);
+    for addr in &context.local_addrs {
+        info!("[Informant-Server] {}", addr);
+    }
 
     use server::{HyperServerWrapper, InformantServer};
     let wrapper = InformantServer::new(context);
-    let server = wrapper.serve();
+    let server = wrapper.serve(shutdown);
     if let Err(e) = futures::try_join!(server, wake_heartbeat) {
         error!("server error: {}", e);
-        panic!();
+        return ExitCode::from(EXIT_RUNTIME);
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_config_exit_code() {
+        assert_eq!(
+            run(Err("Invalid host config!".into())).await,
+            ExitCode::from(EXIT_CONFIG),
+            "should not exit like a runtime error"
+        );
     }
 }
@@ -0,0 +1,172 @@
+use crate::influx_gateway::WorkerStatus;
+use chrono::{DateTime, Utc};
+use mac_address::MacAddress;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// prometheus counters (text exposition format on /metrics)
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static INFLUX_DESERIALIZATION_ERRORS: Counter = Counter::new(
+    "pv_informant_influx_deserialization_errors_total",
+    "InfluxDB responses which could not be deserialized (schema mismatch)",
+);
+
+pub static EXCESS_UNUSED_HEARTBEATS: Counter = Counter::new(
+    "pv_informant_excess_unused_heartbeats_total",
+    "Heartbeats with excess available but all workers already active",
+);
+
+static COUNTERS: [&Counter; 2] = [&INFLUX_DESERIALIZATION_ERRORS, &EXCESS_UNUSED_HEARTBEATS];
+
+// per-mac gauges of the latest worker statuses
+#[derive(Debug)]
+pub struct WorkerGauges {
+    // status and time of the last report (by mac bytes)
+    workers: BTreeMap<[u8; 6], (i32, Option<DateTime<Utc>>)>,
+}
+
+impl WorkerGauges {
+    const fn new() -> Self {
+        WorkerGauges {
+            workers: BTreeMap::new(),
+        }
+    }
+    pub fn observe(
+        &mut self,
+        mac: &MacAddress,
+        status: WorkerStatus,
+        seen: Option<DateTime<Utc>>,
+        max_macs: usize,
+    ) {
+        // bound the cardinality (ignore further macs)
+        if !self.workers.contains_key(&mac.bytes()) && self.workers.len() >= max_macs {
+            return;
+        }
+        let entry = self.workers.entry(mac.bytes()).or_insert((0, None));
+        entry.0 = status as i32;
+        if seen.is_some() {
+            entry.1 = seen;
+        }
+    }
+    fn render(&self) -> String {
+        if self.workers.is_empty() {
+            return String::new();
+        }
+        let last_seen = "pv_informant_worker_last_seen_seconds";
+        let status = "pv_informant_worker_status";
+        let mut out = format!(
+            "# HELP {} Unix time of the last report of a worker\n# TYPE {} gauge\n",
+            last_seen, last_seen
+        );
+        for (mac, (_, seen)) in &self.workers {
+            if let Some(seen) = seen {
+                let mac = MacAddress::new(*mac);
+                out += &format!("{}{{mac=\"{}\"}} {}\n", last_seen, mac, seen.timestamp());
+            }
+        }
+        out += &format!(
+            "# HELP {} Latest status of a worker (0: sleep, 1: awake, 2: inquisitive, 3: working)\n# TYPE {} gauge\n",
+            status, status
+        );
+        for (mac, (s, _)) in &self.workers {
+            let mac = MacAddress::new(*mac);
+            out += &format!("{}{{mac=\"{}\"}} {}\n", status, mac, s);
+        }
+        out
+    }
+}
+
+pub static WORKER_GAUGES: Mutex<WorkerGauges> = Mutex::new(WorkerGauges::new());
+
+pub fn render() -> String {
+    let counters: String = COUNTERS
+        .iter()
+        .map(|c| {
+            format!(
+                "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                c.name,
+                c.help,
+                c.name,
+                c.name,
+                c.get()
+            )
+        })
+        .collect();
+    counters + &WORKER_GAUGES.lock().unwrap().render()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let counter = Counter::new("test_total", "test counter");
+        counter.inc();
+        counter.inc();
+        assert_eq!(counter.get(), 2);
+        assert!(
+            render().contains("# TYPE pv_informant_influx_deserialization_errors_total counter\n")
+        );
+    }
+
+    #[test]
+    fn test_worker_gauges() {
+        let macs: Vec<MacAddress> = [
+            "11:22:33:44:55:66",
+            "11:22:33:44:55:77",
+            "11:22:33:44:55:88",
+        ]
+        .iter()
+        .map(|m| m.parse().unwrap())
+        .collect();
+        let seen = DateTime::parse_from_rfc3339("2023-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut gauges = WorkerGauges::new();
+        assert_eq!(gauges.render(), "", "should render nothing without workers");
+        gauges.observe(&macs[0], WorkerStatus::Working, Some(seen), 2);
+        gauges.observe(&macs[1], WorkerStatus::Sleep, None, 2);
+        gauges.observe(&macs[2], WorkerStatus::Working, Some(seen), 2);
+        gauges.observe(&macs[0], WorkerStatus::Sleep, None, 2);
+        let out = gauges.render();
+        assert!(
+            out.contains(
+                "pv_informant_worker_last_seen_seconds{mac=\"11:22:33:44:55:66\"} 1685620800\n"
+            ),
+            "should keep the time of the last report"
+        );
+        assert!(out.contains("pv_informant_worker_status{mac=\"11:22:33:44:55:66\"} 0\n"));
+        assert!(out.contains("pv_informant_worker_status{mac=\"11:22:33:44:55:77\"} 0\n"));
+        assert!(
+            !out.contains("11:22:33:44:55:88"),
+            "should ignore macs beyond the cap"
+        );
+        assert!(
+            !out.contains("pv_informant_worker_last_seen_seconds{mac=\"11:22:33:44:55:77\"}"),
+            "should not render last seen of macs which never reported"
+        );
+    }
+}
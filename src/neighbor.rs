@@ -1,81 +1,283 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use futures::stream::{self, StreamExt};
 use mac_address::MacAddress;
+use rand::Rng;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::process::Stdio;
-use tokio::net::UdpSocket;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::process::Command;
 use wake_on_lan;
 
 pub type MacIpMapping = HashMap<MacAddress, Option<IpAddr>>;
+// ipv4 address and prefix length by interface name
+pub type InterfacePrefixes = HashMap<String, (Ipv4Addr, u8)>;
 
 #[async_trait]
 pub trait NetworkGateway {
     async fn ping(&self, ip: IpAddr) -> Result<bool, std::io::Error>;
+    async fn tcp_connect(&self, addr: SocketAddr) -> Result<bool, std::io::Error>;
     async fn ip_neigh(&self) -> Result<String>;
+    async fn interface_prefix(&self) -> Result<InterfacePrefixes>;
+    async fn wake(
+        &self,
+        sleeping_macs: &HashSet<MacAddress>,
+        mac_mapping: &MacIpMapping,
+    ) -> Result<()>;
 }
 
-struct LinuxNetworkGateway {}
+#[derive(Debug, Clone)]
+pub struct LinuxNetworkGateway {
+    // kill 'ip neigh' if it hangs (e.g. wedged netlink)
+    pub neigh_timeout: Duration,
+    // wake target ip instead of the arp-resolved ip (e.g. directed broadcast to another vlan)
+    pub wake_ip_override: HashMap<MacAddress, IpAddr>,
+    // unicast probes to the last ip in addition to the magic packet (0: off)
+    pub unicast_probes: usize,
+    pub wake_options: WakeOptions,
+    // paths of the 'ping' and 'ip' binaries
+    pub ping_bin: String,
+    pub ip_bin: String,
+}
+
+impl Default for LinuxNetworkGateway {
+    fn default() -> Self {
+        LinuxNetworkGateway {
+            neigh_timeout: Duration::from_secs(5),
+            wake_ip_override: HashMap::new(),
+            unicast_probes: 0,
+            wake_options: WakeOptions::default(),
+            ping_bin: "ping".into(),
+            ip_bin: "ip".into(),
+        }
+    }
+}
+
+impl LinuxNetworkGateway {
+    fn ping_command(&self, ip: IpAddr) -> Command {
+        let mut cmd = Command::new(&self.ping_bin);
+        cmd.args(&[&ip.to_string(), "-c", "1", "-W", "1"]);
+        cmd
+    }
+    fn ip_neigh_command(&self) -> Command {
+        let mut cmd = Command::new(&self.ip_bin);
+        cmd.arg("neigh");
+        cmd
+    }
+    fn ip_addr_command(&self) -> Command {
+        let mut cmd = Command::new(&self.ip_bin);
+        cmd.args(["-o", "addr", "show"]);
+        cmd
+    }
+}
 
-const LINUX_NET: &LinuxNetworkGateway = &LinuxNetworkGateway {};
+// like 'ping -W 1'
+const TCP_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+async fn output_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<Vec<u8>> {
+    let mut child = cmd.stdout(Stdio::piped()).kill_on_drop(true).spawn()?;
+    let mut stdout = child.stdout.take().with_context(|| "no stdout")?;
+    let mut buf = Vec::new();
+    let res = tokio::time::timeout(timeout, async {
+        stdout.read_to_end(&mut buf).await?;
+        child.wait().await
+    })
+    .await;
+    match res {
+        Ok(status) => {
+            status?;
+            Ok(buf)
+        }
+        Err(_) => {
+            // kill and reap the child
+            child.kill().await?;
+            bail!("timed out after {:?}", timeout)
+        }
+    }
+}
 
 #[async_trait]
 impl NetworkGateway for LinuxNetworkGateway {
     async fn ping(&self, ip: IpAddr) -> Result<bool, std::io::Error> {
         debug!("ping {}", ip);
-        Command::new("ping")
-            .args(&[&ip.to_string(), "-c", "1", "-W", "1"])
+        self.ping_command(ip)
             .stdout(Stdio::null())
             .status()
             .await
             .map(|s| s.success())
     }
+    async fn tcp_connect(&self, addr: SocketAddr) -> Result<bool, std::io::Error> {
+        debug!("tcp connect {}", addr);
+        // refused or timed out connections count as sleeping
+        Ok(matches!(
+            tokio::time::timeout(TCP_PROBE_TIMEOUT, TcpStream::connect(addr)).await,
+            Ok(Ok(_))
+        ))
+    }
     async fn ip_neigh(&self) -> Result<String> {
         Ok(String::from_utf8(
-            Command::new("ip")
-                .arg("neigh")
-                .output()
+            output_with_timeout(&mut self.ip_neigh_command(), self.neigh_timeout)
                 .await
-                .with_context(|| "'ip neigh' failed")?
-                .stdout,
+                .with_context(|| format!("'{} neigh' failed", self.ip_bin))?,
         )?)
     }
+    async fn interface_prefix(&self) -> Result<InterfacePrefixes> {
+        let output = output_with_timeout(&mut self.ip_addr_command(), self.neigh_timeout)
+            .await
+            .with_context(|| format!("'{} -o addr' failed", self.ip_bin))?;
+        Ok(parse_interface_prefixes(&String::from_utf8(output)?))
+    }
+    async fn wake(
+        &self,
+        sleeping_macs: &HashSet<MacAddress>,
+        mac_mapping: &MacIpMapping,
+    ) -> Result<()> {
+        let interfaces = self.interface_prefix().await.unwrap_or_else(|e| {
+            warn!(
+                "Failed to read interface prefixes, assuming /{}! {}",
+                self.wake_options.subnet_prefix, e
+            );
+            InterfacePrefixes::new()
+        });
+        if self.wake_ip_override.is_empty() {
+            wake_macs(sleeping_macs, mac_mapping, self.wake_options, &interfaces).await?;
+        } else {
+            wake_macs(
+                sleeping_macs,
+                &override_wake_ips(mac_mapping, &self.wake_ip_override),
+                self.wake_options,
+                &interfaces,
+            )
+            .await?;
+        }
+        if self.unicast_probes > 0 {
+            let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
+            send_unicast_probes(sleeping_macs, mac_mapping, &socket, self.unicast_probes).await?;
+        }
+        Ok(())
+    }
 }
 
-pub async fn macs_to_addrs(macs: &HashSet<MacAddress>) -> Result<MacIpMapping> {
-    _macs_to_addrs(macs, LINUX_NET).await
+pub fn parse_wake_ip_override(s: &str) -> Result<HashMap<MacAddress, IpAddr>, String> {
+    parse_mac_ips(s, "wake ip override")
 }
 
-pub async fn addr_to_mac(addr: std::net::IpAddr) -> Result<Option<MacAddress>> {
-    _addr_to_mac(addr, LINUX_NET).await
+pub fn parse_mac_ips(s: &str, name: &str) -> Result<HashMap<MacAddress, IpAddr>, String> {
+    // mac=ip pairs separated by ','
+    s.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (mac, ip) = pair
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| format!("Missing '=' in {} '{}'!", name, pair))?;
+            Ok((
+                mac.parse()
+                    .map_err(|e| format!("Invalid {} mac '{}'! {}", name, mac, e))?,
+                ip.parse()
+                    .map_err(|e| format!("Invalid {} ip '{}'! {}", name, ip, e))?,
+            ))
+        })
+        .collect()
+}
+
+fn override_wake_ips(
+    mac_mapping: &MacIpMapping,
+    wake_ip_override: &HashMap<MacAddress, IpAddr>,
+) -> MacIpMapping {
+    let mut mapping = mac_mapping.clone();
+    for (mac, ip) in wake_ip_override {
+        if let Some(ip_opt) = mapping.get_mut(mac) {
+            *ip_opt = Some(*ip);
+        }
+    }
+    mapping
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ArpSelection {
+    // first listed entry of a mac
+    #[default]
+    First,
+    // first REACHABLE entry (otherwise first)
+    PreferReachable,
+    // first entry on the interface (otherwise first)
+    PreferInterface(String),
+}
+
+impl FromStr for ArpSelection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "first" => Ok(ArpSelection::First),
+            None if s == "prefer-reachable" => Ok(ArpSelection::PreferReachable),
+            Some(("prefer-interface", iface)) if !iface.is_empty() => {
+                Ok(ArpSelection::PreferInterface(iface.into()))
+            }
+            _ => Err(format!("Unknown arp selection '{}'!", s)),
+        }
+    }
 }
 
-async fn _macs_to_addrs(
+struct NeighEntry<'a> {
+    ip: IpAddr,
+    dev: Option<&'a str>,
+    state: Option<&'a str>,
+}
+
+impl ArpSelection {
+    fn select(&self, entries: &[NeighEntry]) -> Option<IpAddr> {
+        let preferred = match self {
+            ArpSelection::First => None,
+            ArpSelection::PreferReachable => entries.iter().find(|e| e.state == Some("REACHABLE")),
+            ArpSelection::PreferInterface(iface) => {
+                entries.iter().find(|e| e.dev == Some(iface.as_str()))
+            }
+        };
+        preferred.or(entries.first()).map(|e| e.ip)
+    }
+}
+
+pub async fn macs_to_addrs(
     macs: &HashSet<MacAddress>,
     net: &impl NetworkGateway,
+    selection: &ArpSelection,
 ) -> Result<MacIpMapping> {
-    let mut addrs: HashMap<MacAddress, Option<IpAddr>> =
-        macs.iter().map(|m| (m.clone(), None)).collect();
-    for line in net.ip_neigh().await?.split("\n") {
-        let mut segs = line.split(" ");
-        let ip_addr = segs.next();
-        let mut found = false;
-        for s in segs {
-            if found {
-                let mac: MacAddress = s.parse()?;
-                if macs.contains(&mac) {
-                    addrs.insert(mac, ip_addr.unwrap().parse().ok());
-                    break;
-                }
+    let neigh = net.ip_neigh().await?;
+    // all entries of a mac (in listed order)
+    let mut entries: HashMap<MacAddress, Vec<NeighEntry>> = HashMap::new();
+    for line in neigh.split("\n") {
+        let segs: Vec<&str> = line.split(" ").collect();
+        let after = |key: &str| {
+            segs.iter()
+                .position(|s| *s == key)
+                .and_then(|i| segs.get(i + 1).copied())
+        };
+        if let Some(mac_str) = after("lladdr") {
+            let mac: MacAddress = mac_str.parse()?;
+            if let (true, Ok(ip)) = (macs.contains(&mac), segs[0].parse()) {
+                entries.entry(mac).or_default().push(NeighEntry {
+                    ip,
+                    dev: after("dev"),
+                    state: segs.last().copied(),
+                });
             }
-            found = s == "lladdr";
         }
     }
-    Ok(addrs)
+    Ok(macs
+        .iter()
+        .map(|m| {
+            let ip = entries.get(m).and_then(|e| selection.select(e));
+            (*m, ip)
+        })
+        .collect())
 }
 
-async fn _addr_to_mac(
+pub async fn addr_to_mac(
     addr: std::net::IpAddr,
     net: &impl NetworkGateway,
 ) -> Result<Option<MacAddress>> {
@@ -102,56 +304,242 @@ async fn _addr_to_mac(
     Ok(None)
 }
 
-pub async fn sleeping_macs(mac_mapping: &MacIpMapping) -> HashSet<MacAddress> {
-    _sleeping_macs(mac_mapping, LINUX_NET).await
+#[derive(Debug, Clone, Copy)]
+pub struct PingLimits {
+    // max pings in flight
+    pub concurrency: usize,
+    // max random delay before launching a ping
+    pub jitter: Duration,
+}
+
+impl Default for PingLimits {
+    fn default() -> Self {
+        PingLimits {
+            concurrency: 8,
+            jitter: Duration::ZERO,
+        }
+    }
 }
 
-async fn _sleeping_macs(
+pub async fn sleeping_macs(
     mac_mapping: &MacIpMapping,
     net: &impl NetworkGateway,
+    limits: PingLimits,
+    tcp_port: Option<u16>,
 ) -> HashSet<MacAddress> {
-    // macs which respond to ping are awake (ip-address from arp-table)
-    let mut sleeping = HashSet::new();
-    for (mac, ip_opt) in mac_mapping.into_iter() {
-        if ip_opt.is_none() || !net.ping(ip_opt.unwrap()).await.unwrap_or(false) {
-            // interpret mac/ip as sleeping if ping not successful
-            sleeping.insert(mac.clone());
+    // macs which respond to ping (or accept a tcp connection) are awake (ip-address from arp-table)
+    stream::iter(mac_mapping.iter())
+        .map(|(mac, ip_opt)| async move {
+            let awake = match ip_opt {
+                Some(ip) => {
+                    if !limits.jitter.is_zero() {
+                        let delay = rand::thread_rng().gen_range(Duration::ZERO..=limits.jitter);
+                        tokio::time::sleep(delay).await;
+                    }
+                    // e.g. icmp blocked but ssh open
+                    net.ping(*ip).await.unwrap_or(false)
+                        || match tcp_port {
+                            Some(port) => net
+                                .tcp_connect(SocketAddr::new(*ip, port))
+                                .await
+                                .unwrap_or(false),
+                            None => false,
+                        }
+                }
+                None => false,
+            };
+            (mac, awake)
+        })
+        .buffer_unordered(limits.concurrency.max(1))
+        // interpret mac/ip as sleeping if ping not successful
+        .filter_map(|(mac, awake)| async move { (!awake).then_some(*mac) })
+        .collect()
+        .await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WakeOptions {
+    // send magic packets grouped by destination with this spacing (10ms per packet if None)
+    pub group_spacing: Option<Duration>,
+    // prefix length of the ipv4 subnets (for the directed broadcast)
+    pub subnet_prefix: u8,
+    // also send the magic packet to the known ip (switches may drop directed broadcasts)
+    pub unicast: bool,
+}
+
+impl Default for WakeOptions {
+    fn default() -> Self {
+        WakeOptions {
+            group_spacing: None,
+            subnet_prefix: 24,
+            unicast: false,
         }
     }
-    sleeping
 }
 
-fn addr_to_broadcast(ip_opt: &Option<IpAddr>) -> IpAddr {
+pub fn parse_subnet_prefix(s: &str) -> Result<u8, String> {
+    match s.trim().trim_start_matches('/').parse() {
+        Ok(p) if (8..=30).contains(&p) => Ok(p),
+        _ => Err(format!("Invalid subnet prefix '{}'! (8 to 30)", s)),
+    }
+}
+
+pub fn parse_interface_prefixes(ip_addr: &str) -> InterfacePrefixes {
+    // '2: enp4s0    inet 192.168.178.26/24 brd 192.168.178.255 scope global enp4s0 ...'
+    let mut prefixes = InterfacePrefixes::new();
+    for line in ip_addr.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match words.as_slice() {
+            [_, iface, "inet", cidr, ..] => cidr
+                .split_once('/')
+                .and_then(|(a, p)| Some((a.parse().ok()?, p.parse().ok()?)))
+                .map(|addr| (iface.to_string(), addr)),
+            _ => None,
+        };
+        if let Some((iface, addr)) = parsed {
+            // first address of an interface
+            prefixes.entry(iface).or_insert(addr);
+        }
+    }
+    prefixes
+}
+
+fn prefix_mask(prefix: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32u32.saturating_sub(prefix.into()))
+        .unwrap_or(0)
+}
+
+fn addr_to_broadcast(
+    ip_opt: &Option<IpAddr>,
+    interfaces: &InterfacePrefixes,
+    fallback_prefix: u8,
+) -> IpAddr {
     match ip_opt {
         Some(IpAddr::V4(ip)) => {
-            let i: [u8; 4] = ip.octets();
-            // assume subnet a.b.c.1/24 => broadcast a.b.c.255
-            IpAddr::V4(Ipv4Addr::new(i[0], i[1], i[2], 255))
+            // prefix of the interface subnet covering the ip (no broadcast for /31 and /32)
+            // the most specific subnet if several cover it (independent of the map order)
+            let prefix = interfaces
+                .values()
+                .filter(|(_, p)| (8..=30).contains(p))
+                .filter(|(addr, p)| {
+                    u32::from(*addr) & prefix_mask(*p) == u32::from(*ip) & prefix_mask(*p)
+                })
+                .map(|(_, p)| *p)
+                .max()
+                .unwrap_or(fallback_prefix);
+            // a.b.c.d/prefix => host bits set
+            IpAddr::V4(Ipv4Addr::from(u32::from(*ip) | !prefix_mask(prefix)))
         }
         _ => IpAddr::V4(Ipv4Addr::BROADCAST),
     }
 }
 
+#[async_trait]
+pub trait WakeSocket {
+    fn set_broadcast(&self, on: bool) -> std::io::Result<()>;
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize>;
+}
+
+#[async_trait]
+impl WakeSocket for UdpSocket {
+    fn set_broadcast(&self, on: bool) -> std::io::Result<()> {
+        UdpSocket::set_broadcast(self, on)
+    }
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+        UdpSocket::send_to(self, buf, target).await
+    }
+}
+
+// discard port
+pub const WOL_PORT: u16 = 9;
+
 pub async fn wake_macs(
     sleeping_macs: &HashSet<MacAddress>,
     mac_mapping: &MacIpMapping,
+    options: WakeOptions,
+    interfaces: &InterfacePrefixes,
+) -> Result<()> {
+    wake_macs_on_port(sleeping_macs, mac_mapping, WOL_PORT, options, interfaces).await
+}
+
+pub async fn wake_macs_on_port(
+    sleeping_macs: &HashSet<MacAddress>,
+    mac_mapping: &MacIpMapping,
+    port: u16,
+    options: WakeOptions,
+    interfaces: &InterfacePrefixes,
+) -> Result<()> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
+    send_magic_packets(
+        sleeping_macs,
+        mac_mapping,
+        &socket,
+        port,
+        options,
+        interfaces,
+    )
+    .await
+}
+
+async fn send_magic_packets(
+    sleeping_macs: &HashSet<MacAddress>,
+    mac_mapping: &MacIpMapping,
+    socket: &(impl WakeSocket + Sync),
+    port: u16,
+    options: WakeOptions,
+    interfaces: &InterfacePrefixes,
 ) -> Result<()> {
     // send magic packet to sleeping macs
     let mut interval = tokio::time::interval(std::time::Duration::from_millis(10));
-    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
-    socket.set_broadcast(true)?;
+    let broadcast = match socket.set_broadcast(true) {
+        Ok(_) => true,
+        Err(e) => {
+            warn!(
+                "Broadcast not permitted, waking known IPs by unicast only! {}",
+                e
+            );
+            false
+        }
+    };
+    let mut targets: Vec<(&MacAddress, IpAddr)> = vec![];
     for m in sleeping_macs {
-        let pkt = wake_on_lan::MagicPacket::new(&m.bytes());
         let ip_opt = mac_mapping.get(m).unwrap_or(&None);
-        let brd_ip: IpAddr = addr_to_broadcast(ip_opt);
-        interval.tick().await;
+        let dst_ip: IpAddr = match (broadcast, ip_opt) {
+            (true, _) => addr_to_broadcast(ip_opt, interfaces, options.subnet_prefix),
+            (false, Some(ip)) => *ip,
+            (false, None) => {
+                warn!("Can not wake {} without broadcast (ip not available)", m);
+                continue;
+            }
+        };
+        targets.push((m, dst_ip));
+        if let (true, true, Some(ip)) = (broadcast, options.unicast, ip_opt) {
+            targets.push((m, *ip));
+        }
+    }
+    if options.group_spacing.is_some() {
+        targets.sort_by_key(|(m, ip)| (*ip, m.bytes()));
+    }
+    let mut previous_ip = None;
+    for (m, dst_ip) in targets {
+        let ip_opt = mac_mapping.get(m).unwrap_or(&None);
+        let pkt = wake_on_lan::MagicPacket::new(&m.bytes());
+        match options.group_spacing {
+            // one distinct packet per mac in quick succession to the same destination
+            Some(spacing) if previous_ip == Some(dst_ip) => tokio::time::sleep(spacing).await,
+            _ => {
+                interval.tick().await;
+            }
+        }
+        previous_ip = Some(dst_ip);
         socket
-            .send_to(pkt.magic_bytes(), SocketAddr::new(brd_ip, 9))
+            .send_to(pkt.magic_bytes(), SocketAddr::new(dst_ip, port))
             .await?;
         info!(
             "Waking {} with {} ({})",
             m,
-            brd_ip,
+            dst_ip,
             ip_opt
                 .map(|i| i.to_string())
                 .unwrap_or("ip not available".into())
@@ -160,22 +548,108 @@ pub async fn wake_macs(
     Ok(())
 }
 
+async fn send_unicast_probes(
+    sleeping_macs: &HashSet<MacAddress>,
+    mac_mapping: &MacIpMapping,
+    socket: &(impl WakeSocket + Sync),
+    count: usize,
+) -> Result<()> {
+    // directed traffic to the last ip (for NICs which wake on unicast but ignore broadcast)
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(10));
+    for m in sleeping_macs {
+        if let Some(Some(ip)) = mac_mapping.get(m) {
+            for _ in 0..count {
+                interval.tick().await;
+                socket.send_to(&[0], SocketAddr::new(*ip, WOL_PORT)).await?;
+            }
+            info!("Probing {} with {} unicast packets to {}", m, count, ip);
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_command_paths() {
+        let net = LinuxNetworkGateway {
+            ping_bin: "/bin/busybox-ping".into(),
+            ip_bin: "/sbin/ip".into(),
+            ..Default::default()
+        };
+        let ping = net.ping_command(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(ping.as_std().get_program(), "/bin/busybox-ping");
+        assert_eq!(
+            ping.as_std().get_args().next(),
+            Some(std::ffi::OsStr::new("127.0.0.1"))
+        );
+        assert_eq!(net.ip_neigh_command().as_std().get_program(), "/sbin/ip");
+        assert_eq!(net.ip_addr_command().as_std().get_program(), "/sbin/ip");
+        assert_eq!(
+            LinuxNetworkGateway::default()
+                .ip_neigh_command()
+                .as_std()
+                .get_program(),
+            "ip"
+        );
+    }
 
     #[tokio::test]
     async fn test_net_commands() {
-        let r = LINUX_NET.ip_neigh().await;
+        let net = LinuxNetworkGateway::default();
+        let r = net.ip_neigh().await;
         assert!(r.is_ok());
-        let r2 = LINUX_NET.ping(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
+        let r2 = net.ping(IpAddr::V4(Ipv4Addr::LOCALHOST)).await;
         assert!(r2.unwrap());
     }
 
-    struct NetworkGatewayMock {
-        ping_resp: HashMap<IpAddr, bool>,
-        neigh_resp: String,
+    #[tokio::test]
+    async fn test_output_timeout() {
+        let pid_file =
+            std::env::temp_dir().join(format!("pv_informant_{}.pid", std::process::id()));
+        let start = std::time::Instant::now();
+        let r = output_with_timeout(
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!("echo $$ > {}; exec sleep 10", pid_file.display())),
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_matches!(r, Err(e) if e.to_string().contains("timed out"));
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "should not wait for the command"
+        );
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+        assert!(
+            !std::path::Path::new(&format!("/proc/{}", pid.trim())).exists(),
+            "should kill and reap the child"
+        );
+        assert_matches!(
+            output_with_timeout(Command::new("echo").arg("ok"), Duration::from_secs(5)).await,
+            Ok(out) if out == b"ok\n"
+        );
+    }
+
+    #[derive(Default)]
+    pub struct NetworkGatewayMock {
+        pub ping_resp: HashMap<IpAddr, bool>,
+        // accepted tcp connections (refused otherwise)
+        pub tcp_resp: HashMap<SocketAddr, bool>,
+        pub neigh_resp: String,
+        // 'ip -o addr' output
+        pub addr_resp: String,
+        pub neigh_calls: AtomicUsize,
+        pub pinged: Mutex<Vec<IpAddr>>,
+        pub woken: Mutex<Vec<(MacAddress, Option<IpAddr>)>>,
+        pub in_flight: AtomicUsize,
+        pub max_in_flight: AtomicUsize,
     }
 
     #[async_trait]
@@ -189,18 +663,47 @@ mod test {
                 ))
             } else {
                 println!("(mocked) ping: {}", ip);
+                self.pinged.lock().unwrap().push(ip);
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
                 Ok(self.ping_resp[&ip])
             }
         }
+        async fn tcp_connect(&self, addr: SocketAddr) -> Result<bool, std::io::Error> {
+            println!("(mocked) tcp connect: {}", addr);
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(self.tcp_resp.get(&addr).copied().unwrap_or(false))
+        }
         async fn ip_neigh(&self) -> Result<String> {
+            self.neigh_calls.fetch_add(1, Ordering::SeqCst);
             Ok(self.neigh_resp.clone())
         }
+        async fn interface_prefix(&self) -> Result<InterfacePrefixes> {
+            Ok(parse_interface_prefixes(&self.addr_resp))
+        }
+        async fn wake(
+            &self,
+            sleeping_macs: &HashSet<MacAddress>,
+            mac_mapping: &MacIpMapping,
+        ) -> Result<()> {
+            let mut woken = self.woken.lock().unwrap();
+            for m in sleeping_macs {
+                println!("(mocked) wake: {}", m);
+                woken.push((m.clone(), *mac_mapping.get(m).unwrap_or(&None)));
+            }
+            Ok(())
+        }
     }
     macro_rules! neigh_resp {
         ( $value:literal ) => {
             &NetworkGatewayMock {
-                ping_resp: HashMap::new(),
                 neigh_resp: $value.into(),
+                ..Default::default()
             }
         };
     }
@@ -214,7 +717,7 @@ mod test {
         "#
         );
         let ip = |s: &str| s.parse::<IpAddr>().unwrap();
-        assert_matches!(_addr_to_mac(ip("192.168.178.1"), bad_sample).await, Err(_));
+        assert_matches!(addr_to_mac(ip("192.168.178.1"), bad_sample).await, Err(_));
         let sample = neigh_resp!(
             r#"
 192.168.178.26 dev enp4s0 lladdr 12:34:56:78:9a:bc REACHABLE
@@ -223,12 +726,12 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
 2a04:4540:4540:4540:4540:4540:4540:4540 dev enp4s0 lladdr 11:22:33:44:55:66 router REACHABLE
         "#
         );
-        assert!(_addr_to_mac(ip("192.168.178.55"), sample)
+        assert!(addr_to_mac(ip("192.168.178.55"), sample)
             .await
             .unwrap()
             .is_none());
         assert_eq!(
-            _addr_to_mac(ip("192.168.178.26"), sample)
+            addr_to_mac(ip("192.168.178.26"), sample)
                 .await
                 .unwrap()
                 .unwrap()
@@ -236,7 +739,7 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
             "12:34:56:78:9A:BC"
         );
         assert_eq!(
-            _addr_to_mac(ip("2a04:4540:4540:4540:4540:4540:4540:4540"), sample)
+            addr_to_mac(ip("2a04:4540:4540:4540:4540:4540:4540:4540"), sample)
                 .await
                 .unwrap()
                 .unwrap()
@@ -263,7 +766,9 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
         .map(mac)
         .collect();
         // invalid mac
-        assert!(_macs_to_addrs(&macs, bad_sample).await.is_err());
+        assert!(macs_to_addrs(&macs, bad_sample, &ArpSelection::First)
+            .await
+            .is_err());
         let sample = neigh_resp!(
             r#"
 192.168.178.2 dev enp4s0 lladdr 22:22:22:22:22:22 REACHABLE
@@ -274,7 +779,9 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
 2a04:4540:4540:4540:4540:4540:4540:4540 dev enp4s0 lladdr 11:22:33:44:55:66 router REACHABLE
         "#
         );
-        let r = _macs_to_addrs(&macs, sample).await.unwrap();
+        let r = macs_to_addrs(&macs, sample, &ArpSelection::First)
+            .await
+            .unwrap();
         assert!(
             r.get(&mac("22:22:22:22:22:22")).is_none(),
             "should map non-searched ips to None"
@@ -301,12 +808,48 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
         }
     }
     #[tokio::test]
+    async fn test_duplicate_arp_entries() {
+        let sample = neigh_resp!(
+            r#"
+192.168.20.5 dev enp4s0.20 lladdr 12:34:56:78:9a:bc STALE
+192.168.178.5 dev enp4s0 lladdr 12:34:56:78:9a:bc REACHABLE
+192.168.178.6 dev enp4s0 lladdr 44:55:66:77:88:99 STALE
+        "#
+        );
+        let mac = |s: &str| s.parse::<MacAddress>().unwrap();
+        let macs: HashSet<MacAddress> = [mac("12:34:56:78:9a:bc"), mac("44:55:66:77:88:99")]
+            .into_iter()
+            .collect();
+        for (selection, expected_ip) in [
+            ("first", "192.168.20.5"),
+            ("prefer-reachable", "192.168.178.5"),
+            ("prefer-interface=enp4s0", "192.168.178.5"),
+            ("prefer-interface=enp4s0.20", "192.168.20.5"),
+            ("prefer-interface=wlan0", "192.168.20.5"),
+        ] {
+            let selection: ArpSelection = selection.parse().unwrap();
+            let r = macs_to_addrs(&macs, sample, &selection).await.unwrap();
+            assert_matches!(
+                r[&mac("12:34:56:78:9a:bc")],
+                Some(ip) if ip.to_string() == expected_ip,
+                "should select {} with {:?}", expected_ip, selection
+            );
+            assert_matches!(
+                r[&mac("44:55:66:77:88:99")],
+                Some(ip) if ip.to_string() == "192.168.178.6",
+                "should fall back to the only entry with {:?}", selection
+            );
+        }
+        assert_matches!("prefer-interface=".parse::<ArpSelection>(), Err(_));
+        assert_matches!("last".parse::<ArpSelection>(), Err(_));
+    }
+    #[tokio::test]
     async fn test_awake_macs() {
         macro_rules! ping_resp {
             ( $value:expr ) => {
                 &NetworkGatewayMock {
                     ping_resp: $value.into_iter().collect(),
-                    neigh_resp: "".into(),
+                    ..Default::default()
                 }
             };
         }
@@ -323,7 +866,7 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
         let awake_mac: MacAddress = "12:34:56:78:9a:bc".parse().unwrap();
         let none_mac_mapping: MacIpMapping = [(awake_mac.clone(), None)].into_iter().collect();
         assert_eq!(
-            _sleeping_macs(&none_mac_mapping, net).await,
+            sleeping_macs(&none_mac_mapping, net, PingLimits::default(), None).await,
             [awake_mac].into_iter().collect::<HashSet<MacAddress>>(),
             "should interpret unavaliable ips as sleeping"
         );
@@ -342,7 +885,7 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
         .collect();
 
         assert_eq!(
-            _sleeping_macs(&mac_mapping, net).await,
+            sleeping_macs(&mac_mapping, net, PingLimits::default(), None).await,
             [sleep_mac, sleep_mac2, failing_mac, uavail_mac]
                 .into_iter()
                 .collect::<HashSet<MacAddress>>(),
@@ -350,20 +893,471 @@ fe80::abcd:abcd:abcd:abcd dev enp4s0 lladdr 44:4e:6d:c2:37:4b router DELAY
         );
     }
 
+    #[tokio::test]
+    async fn test_awake_tcp_probe() {
+        let ssh_ip: IpAddr = "192.168.178.30".parse().unwrap();
+        let sleep_ip: IpAddr = "192.168.178.31".parse().unwrap();
+        let net = NetworkGatewayMock {
+            ping_resp: [(ssh_ip, false), (sleep_ip, false)].into_iter().collect(),
+            tcp_resp: [(SocketAddr::new(ssh_ip, 22), true)].into_iter().collect(),
+            ..Default::default()
+        };
+        let ssh_mac: MacAddress = "12:34:56:78:9a:30".parse().unwrap();
+        let sleep_mac: MacAddress = "12:34:56:78:9a:31".parse().unwrap();
+        let mac_mapping: MacIpMapping = [(ssh_mac, Some(ssh_ip)), (sleep_mac, Some(sleep_ip))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            sleeping_macs(&mac_mapping, &net, PingLimits::default(), None).await,
+            [ssh_mac, sleep_mac]
+                .into_iter()
+                .collect::<HashSet<MacAddress>>(),
+            "should only ping without a tcp port"
+        );
+        assert_eq!(
+            sleeping_macs(&mac_mapping, &net, PingLimits::default(), Some(22)).await,
+            [sleep_mac].into_iter().collect::<HashSet<MacAddress>>(),
+            "should consider macs accepting the tcp connection awake"
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gateway = LinuxNetworkGateway::default();
+        assert_matches!(gateway.tcp_connect(addr).await, Ok(true));
+        drop(listener);
+        assert_matches!(
+            gateway.tcp_connect(addr).await,
+            Ok(false),
+            "should treat refused connections as sleeping"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ping_concurrency() {
+        let mac_mapping: MacIpMapping = (1..=10u8)
+            .map(|i| {
+                (
+                    MacAddress::new([0x12, 0x34, 0x56, 0x78, 0x9a, i]),
+                    Some(IpAddr::V4(Ipv4Addr::new(192, 168, 178, i))),
+                )
+            })
+            .collect();
+        let net = NetworkGatewayMock {
+            ping_resp: mac_mapping
+                .values()
+                .map(|ip| (ip.unwrap(), false))
+                .collect(),
+            ..Default::default()
+        };
+        let limits = PingLimits {
+            concurrency: 3,
+            jitter: Duration::from_millis(2),
+        };
+        assert_eq!(
+            sleeping_macs(&mac_mapping, &net, limits, None).await.len(),
+            10
+        );
+        assert_eq!(net.pinged.lock().unwrap().len(), 10);
+        let max_in_flight = net.max_in_flight.load(Ordering::SeqCst);
+        assert!(
+            max_in_flight <= 3,
+            "should not exceed ping concurrency ({} in flight)",
+            max_in_flight
+        );
+        assert!(max_in_flight > 1, "should ping concurrently");
+
+        // tcp probes share the slot of the ping
+        net.max_in_flight.store(0, Ordering::SeqCst);
+        assert_eq!(
+            sleeping_macs(&mac_mapping, &net, limits, Some(22))
+                .await
+                .len(),
+            10
+        );
+        let max_in_flight = net.max_in_flight.load(Ordering::SeqCst);
+        assert!(
+            max_in_flight <= 3,
+            "should not exceed ping concurrency with tcp probes ({} in flight)",
+            max_in_flight
+        );
+    }
+
+    #[derive(Default)]
+    struct WakeSocketMock {
+        broadcast_err: bool,
+        sent: Mutex<Vec<SocketAddr>>,
+        payloads: Mutex<Vec<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl WakeSocket for WakeSocketMock {
+        fn set_broadcast(&self, _on: bool) -> std::io::Result<()> {
+            if self.broadcast_err {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "mocking denied broadcast!",
+                ))
+            } else {
+                Ok(())
+            }
+        }
+        async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+            self.sent.lock().unwrap().push(target);
+            self.payloads.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_magic_packets() {
+        let known: MacAddress = "12:34:56:78:9a:bc".parse().unwrap();
+        let unknown: MacAddress = "22:22:22:22:22:22".parse().unwrap();
+        let mac_mapping: MacIpMapping = [
+            (known.clone(), "192.168.178.23".parse().ok()),
+            (unknown.clone(), None),
+        ]
+        .into_iter()
+        .collect();
+        let macs: HashSet<MacAddress> = [known, unknown].into_iter().collect();
+
+        let socket = WakeSocketMock::default();
+        send_magic_packets(
+            &macs,
+            &mac_mapping,
+            &socket,
+            WOL_PORT,
+            WakeOptions::default(),
+            &InterfacePrefixes::new(),
+        )
+        .await
+        .unwrap();
+        let mut sent: Vec<String> = socket
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        sent.sort();
+        assert_eq!(sent, vec!["192.168.178.255:9", "255.255.255.255:9"]);
+
+        let unicast_socket = WakeSocketMock {
+            broadcast_err: true,
+            ..Default::default()
+        };
+        assert_matches!(
+            send_magic_packets(
+                &macs,
+                &mac_mapping,
+                &unicast_socket,
+                WOL_PORT,
+                WakeOptions::default(),
+                &InterfacePrefixes::new()
+            )
+            .await,
+            Ok(()),
+            "should not fail if broadcast is not permitted"
+        );
+        assert_eq!(
+            *unicast_socket.sent.lock().unwrap(),
+            vec!["192.168.178.23:9".parse::<SocketAddr>().unwrap()],
+            "should send unicast to known ips only"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wol_unicast() {
+        let known: MacAddress = "12:34:56:78:9a:bc".parse().unwrap();
+        let unknown: MacAddress = "22:22:22:22:22:22".parse().unwrap();
+        let mac_mapping: MacIpMapping = [(known, "192.168.178.23".parse().ok()), (unknown, None)]
+            .into_iter()
+            .collect();
+        let options = WakeOptions {
+            unicast: true,
+            ..Default::default()
+        };
+
+        let socket = WakeSocketMock::default();
+        send_magic_packets(
+            &HashSet::from([known]),
+            &mac_mapping,
+            &socket,
+            WOL_PORT,
+            options,
+            &InterfacePrefixes::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *socket.sent.lock().unwrap(),
+            vec![
+                "192.168.178.255:9".parse::<SocketAddr>().unwrap(),
+                "192.168.178.23:9".parse().unwrap()
+            ],
+            "should send to the broadcast and the known ip"
+        );
+        assert_eq!(socket.payloads.lock().unwrap().len(), 2);
+
+        let socket = WakeSocketMock::default();
+        send_magic_packets(
+            &HashSet::from([unknown]),
+            &mac_mapping,
+            &socket,
+            WOL_PORT,
+            options,
+            &InterfacePrefixes::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *socket.sent.lock().unwrap(),
+            vec!["255.255.255.255:9".parse::<SocketAddr>().unwrap()],
+            "should only broadcast without a known ip"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wake_group_spacing() {
+        let macs: Vec<MacAddress> = [
+            "12:34:56:78:9a:01",
+            "12:34:56:78:9a:02",
+            "12:34:56:78:9a:03",
+            "12:34:56:78:9a:04",
+        ]
+        .iter()
+        .map(|m| m.parse().unwrap())
+        .collect();
+        let mac_mapping: MacIpMapping = [
+            (macs[0], "192.168.178.23".parse().ok()),
+            (macs[1], "10.0.2.7".parse().ok()),
+            (macs[2], "192.168.178.24".parse().ok()),
+            (macs[3], "10.0.2.8".parse().ok()),
+        ]
+        .into_iter()
+        .collect();
+        let socket = WakeSocketMock::default();
+        send_magic_packets(
+            &macs.iter().cloned().collect(),
+            &mac_mapping,
+            &socket,
+            WOL_PORT,
+            WakeOptions {
+                group_spacing: Some(Duration::from_millis(1)),
+                ..Default::default()
+            },
+            &InterfacePrefixes::new(),
+        )
+        .await
+        .unwrap();
+        let sent: Vec<String> = socket
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                "10.0.2.255:9",
+                "10.0.2.255:9",
+                "192.168.178.255:9",
+                "192.168.178.255:9"
+            ],
+            "should group packets by destination"
+        );
+        let payloads = socket.payloads.lock().unwrap();
+        for m in &macs {
+            assert_eq!(
+                payloads
+                    .iter()
+                    .filter(|p| **p == wake_on_lan::MagicPacket::new(&m.bytes()).magic_bytes())
+                    .count(),
+                1,
+                "should send one distinct magic packet per mac"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unicast_probes() {
+        let known: MacAddress = "12:34:56:78:9a:bc".parse().unwrap();
+        let unknown: MacAddress = "22:22:22:22:22:22".parse().unwrap();
+        let mac_mapping: MacIpMapping = [(known, "192.168.178.23".parse().ok()), (unknown, None)]
+            .into_iter()
+            .collect();
+        let socket = WakeSocketMock::default();
+        send_unicast_probes(&HashSet::from([known, unknown]), &mac_mapping, &socket, 3)
+            .await
+            .unwrap();
+        assert_eq!(
+            *socket.sent.lock().unwrap(),
+            vec!["192.168.178.23:9".parse::<SocketAddr>().unwrap(); 3],
+            "should send a burst of probes to the known ip only"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wake_ip_override() {
+        let vlan: MacAddress = "12:34:56:78:9a:bc".parse().unwrap();
+        let local: MacAddress = "22:22:22:22:22:22".parse().unwrap();
+        let mac_mapping: MacIpMapping = [
+            (vlan, "192.168.178.23".parse().ok()),
+            (local, "192.168.178.24".parse().ok()),
+        ]
+        .into_iter()
+        .collect();
+        let wake_ip_override =
+            parse_wake_ip_override("12:34:56:78:9a:bc=10.0.2.255, 33:33:33:33:33:33=10.0.3.7")
+                .unwrap();
+        assert_eq!(wake_ip_override.len(), 2);
+        assert_matches!(parse_wake_ip_override("12:34:56:78:9a:bc"), Err(_));
+        assert_matches!(parse_wake_ip_override("12:34:56:78:9a:bc=10.0.2"), Err(_));
+        assert_matches!(
+            parse_mac_ips("12:34:56:78:9a:bc=10.0.2", "last known ip"),
+            Err(e) if e.starts_with("Invalid last known ip ip"),
+            "should report the config of the pairs"
+        );
+
+        let mapping = override_wake_ips(&mac_mapping, &wake_ip_override);
+        assert_eq!(mapping.len(), 2, "should not add macs which are not woken");
+        let socket = WakeSocketMock::default();
+        send_magic_packets(
+            &HashSet::from([vlan]),
+            &mapping,
+            &socket,
+            WOL_PORT,
+            WakeOptions::default(),
+            &InterfacePrefixes::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *socket.sent.lock().unwrap(),
+            vec!["10.0.2.255:9".parse::<SocketAddr>().unwrap()],
+            "should wake with the override instead of the arp ip"
+        );
+        let socket = WakeSocketMock::default();
+        send_magic_packets(
+            &HashSet::from([local]),
+            &mapping,
+            &socket,
+            WOL_PORT,
+            WakeOptions::default(),
+            &InterfacePrefixes::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *socket.sent.lock().unwrap(),
+            vec!["192.168.178.255:9".parse::<SocketAddr>().unwrap()],
+            "should wake with the arp ip without override"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interface_prefix() {
+        let net = NetworkGatewayMock {
+            addr_resp: r#"1: lo    inet 127.0.0.1/8 scope host lo\       valid_lft forever preferred_lft forever
+1: lo    inet6 ::1/128 scope host \       valid_lft forever preferred_lft forever
+2: enp4s0    inet 192.168.178.26/23 brd 192.168.179.255 scope global dynamic enp4s0\       valid_lft 80000sec preferred_lft 80000sec
+2: enp4s0    inet6 fe80::abcd:abcd:abcd:abcd/64 scope link \       valid_lft forever preferred_lft forever
+3: wg0    inet 10.8.0.1/32 scope global wg0\       valid_lft forever preferred_lft forever
+4: br0    inet 10.0.0.1/16 brd 10.0.255.255 scope global br0\       valid_lft forever preferred_lft forever"#
+                .into(),
+            ..Default::default()
+        };
+        let interfaces = net.interface_prefix().await.unwrap();
+        assert_eq!(interfaces.len(), 4, "should skip inet6 addresses");
+        assert_eq!(
+            interfaces["enp4s0"],
+            ("192.168.178.26".parse().unwrap(), 23)
+        );
+        let broadcast = |ip: &str, fallback_prefix| {
+            addr_to_broadcast(&ip.parse().ok(), &interfaces, fallback_prefix).to_string()
+        };
+        assert_eq!(
+            broadcast("192.168.178.55", 24),
+            "192.168.179.255",
+            "should use the prefix of the interface covering the ip"
+        );
+        assert_eq!(broadcast("10.0.3.4", 24), "10.0.255.255");
+        assert_eq!(
+            broadcast("10.8.0.5", 24),
+            "10.8.0.255",
+            "should ignore /32 interfaces"
+        );
+        assert_eq!(
+            broadcast("172.16.2.5", 23),
+            "172.16.3.255",
+            "should fall back to the configured prefix"
+        );
+
+        // a /16 and a /24 covering the same ip on different interfaces
+        let overlapping = || -> InterfacePrefixes {
+            HashMap::from([
+                ("br0".into(), ("10.0.0.1".parse().unwrap(), 16)),
+                ("eth1".into(), ("10.0.5.1".parse().unwrap(), 24)),
+                ("eth2".into(), ("10.0.6.1".parse().unwrap(), 20)),
+            ])
+        };
+        // fresh maps iterate in different orders
+        for _ in 0..10 {
+            assert_eq!(
+                addr_to_broadcast(&"10.0.5.20".parse().ok(), &overlapping(), 24).to_string(),
+                "10.0.5.255",
+                "should use the longest covering prefix"
+            );
+        }
+        assert_eq!(
+            addr_to_broadcast(&"10.0.7.20".parse().ok(), &overlapping(), 24).to_string(),
+            "10.0.15.255"
+        );
+    }
+
     #[test]
     fn test_addr_to_broadcast() {
-        assert_eq!(addr_to_broadcast(&None).to_string(), "255.255.255.255");
+        let no_interfaces = InterfacePrefixes::new();
         assert_eq!(
-            addr_to_broadcast(&"fe80::abcd:abcd:abcd:abcd".parse().ok()).to_string(),
+            addr_to_broadcast(&None, &no_interfaces, 24).to_string(),
             "255.255.255.255"
         );
         assert_eq!(
-            addr_to_broadcast(&"192.168.178.23".parse().ok()).to_string(),
+            addr_to_broadcast(
+                &"fe80::abcd:abcd:abcd:abcd".parse().ok(),
+                &no_interfaces,
+                24
+            )
+            .to_string(),
+            "255.255.255.255"
+        );
+        assert_eq!(
+            addr_to_broadcast(&"192.168.178.23".parse().ok(), &no_interfaces, 24).to_string(),
             "192.168.178.255"
         );
         assert_eq!(
-            addr_to_broadcast(&"192.168.122.55".parse().ok()).to_string(),
+            addr_to_broadcast(&"192.168.122.55".parse().ok(), &no_interfaces, 24).to_string(),
             "192.168.122.255"
         );
+        assert_eq!(
+            addr_to_broadcast(&"192.168.122.55".parse().ok(), &no_interfaces, 23).to_string(),
+            "192.168.123.255",
+            "should set the host bits of a /23"
+        );
+        assert_eq!(
+            addr_to_broadcast(&"192.168.122.55".parse().ok(), &no_interfaces, 25).to_string(),
+            "192.168.122.127"
+        );
+        assert_eq!(
+            addr_to_broadcast(&"192.168.122.200".parse().ok(), &no_interfaces, 25).to_string(),
+            "192.168.122.255"
+        );
+        assert_eq!(
+            addr_to_broadcast(&None, &no_interfaces, 23).to_string(),
+            "255.255.255.255"
+        );
+        assert_eq!(parse_subnet_prefix("/23"), Ok(23));
+        assert_matches!(parse_subnet_prefix("31"), Err(_));
+        assert_matches!(parse_subnet_prefix("x"), Err(_));
     }
 }
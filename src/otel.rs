@@ -0,0 +1,65 @@
+use crate::config::ConfigSource;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::layer::SubscriberExt;
+
+// export the request and heartbeat spans via OTLP (if OTEL_EXPORTER_OTLP_ENDPOINT is set)
+pub fn init_otel(config: &ConfigSource) -> Result<bool, String> {
+    let endpoint = match config.var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => return Ok(false),
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+        .map_err(|e| format!("Invalid otlp exporter config! {}", e))?;
+    // logging stays with env_logger (no 'log' bridge)
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| format!("Failed to install the otlp exporter! {}", e))?;
+    Ok(true)
+}
+
+// flush the pending spans on shutdown
+pub fn shutdown_otel() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+pub mod test {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::export::trace::SpanData;
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // spans exported while running 'f' (with a thread-local subscriber)
+    pub async fn exported_spans<F: std::future::Future>(f: F) -> Vec<SpanData> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            f.await;
+        }
+        provider.force_flush();
+        exporter.get_finished_spans().unwrap()
+    }
+
+    #[test]
+    fn test_init_otel_disabled() {
+        assert_eq!(
+            super::init_otel(&crate::config::ConfigSource::default()),
+            Ok(false),
+            "should not export without OTEL_EXPORTER_OTLP_ENDPOINT"
+        );
+    }
+}
@@ -1,19 +1,49 @@
 use crate::context::Context;
 use crate::errors::ApiError;
-use crate::influx_gateway::{log_workerstatus, WorkerStatus};
+use crate::influx_gateway::{ExcessStatus, WorkerStatus};
+use crate::metrics;
 use crate::server::RequestHandler;
+use crate::status_store::StatusStore;
 use crate::{api_err, fwd_err};
 use async_trait::async_trait;
+use chrono::Utc;
 use hyper::StatusCode;
+use mac_address::MacAddress;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize)]
 pub struct ReportRes {
     // has the mac of the requester recently been woken by magic packet
     woken: bool,
+    // stance of the server on the mac (REPORT_REASON)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
 }
 
+fn wake_reason(
+    excess: Option<ExcessStatus>,
+    woken: bool,
+    scheduled: bool,
+    lowercase: bool,
+) -> String {
+    // e.g. 'excess=Yes, recently woken' ('excess=yes' with EXCESS_JSON_LOWERCASE)
+    let mut reasons = vec![match excess {
+        Some(e) => format!("excess={}", e.name(lowercase)),
+        None => "no heartbeat yet".into(),
+    }];
+    if woken {
+        reasons.push("recently woken".into());
+    }
+    if scheduled {
+        reasons.push("scheduled wake".into());
+    }
+    reasons.join(", ")
+}
+
+// reject typos and unexpected fields
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ReportReq {
     working: bool,
     wake: bool,
@@ -21,6 +51,53 @@ pub struct ReportReq {
 
 pub struct ReportRequestHandler {}
 
+pub const X_WORKER_TOKEN: &str = "x-worker-token";
+
+pub fn parse_worker_tokens(s: &str) -> Result<HashMap<MacAddress, String>, String> {
+    // mac=token pairs separated by ','
+    s.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (mac, token) = pair
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| format!("Missing '=' in worker token '{}'!", pair))?;
+            if token.is_empty() {
+                return Err(format!("Empty worker token of '{}'!", mac));
+            }
+            Ok((
+                mac.parse()
+                    .map_err(|e| format!("Invalid worker token mac '{}'! {}", mac, e))?,
+                token.to_string(),
+            ))
+        })
+        .collect()
+}
+
+fn verify_worker_token(
+    tokens: &HashMap<MacAddress, String>,
+    mac: &MacAddress,
+    token: Option<&str>,
+) -> Result<(), ApiError> {
+    // no tokens configured: trust the resolved mac
+    if tokens.is_empty() {
+        return Ok(());
+    }
+    match tokens.get(mac) {
+        Some(t) if Some(t.as_str()) == token => Ok(()),
+        Some(_) => Err(api_err!(
+            StatusCode::UNAUTHORIZED,
+            "Invalid worker token of {}!",
+            mac
+        )),
+        None => Err(api_err!(
+            StatusCode::UNAUTHORIZED,
+            "No worker token configured for {}!",
+            mac
+        )),
+    }
+}
+
 #[async_trait]
 impl RequestHandler<ReportReq, ReportRes> for ReportRequestHandler {
     async fn handle(&self, req: ReportReq, context: Context) -> Result<ReportRes, ApiError> {
@@ -28,25 +105,287 @@ impl RequestHandler<ReportReq, ReportRes> for ReportRequestHandler {
         let mac = context.remote_mac().await?.ok_or_else(|| {
             api_err!(StatusCode::FORBIDDEN, "mac address of requestor not found!")
         })?;
-        log_workerstatus(
+        verify_worker_token(
+            &context.worker_tokens,
+            &mac,
+            context.worker_token.as_deref(),
+        )?;
+        // the requester is awake (exclude from racing heartbeats)
+        context.mark_active(&mac);
+        context.reset_backoff(Some(&mac));
+        let status = if req.working {
+            WorkerStatus::Working
+        } else {
+            WorkerStatus::Inquisitive
+        };
+        context
+            .status_store
+            .log_workerstatus(&mac, status.clone(), req.wake)
+            .await
+            .map_err(|e| fwd_err!("Failed to log reported status! {}", e))?;
+        metrics::WORKER_GAUGES.lock().unwrap().observe(
             &mac,
-            if req.working {
-                WorkerStatus::Working
-            } else {
-                WorkerStatus::Inquisitive
-            },
-            req.wake,
-            &context.influx_client,
-        )
+            status,
+            Some(Utc::now()),
+            context.metrics_max_macs,
+        );
+        let woken = context.woken_in_previous_heartbeat(&mac);
+        let reason = context.report_reason.then(|| {
+            wake_reason(
+                context.last_heartbeat().map(|h| h.excess),
+                woken,
+                context.scheduled_wake_macs.contains(&mac),
+                context.excess_json_lowercase,
+            )
+        });
+        Ok(ReportRes { woken, reason })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BulkReportReq {
+    // validated per entry (a bad entry does not fail the batch)
+    entries: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BulkEntry {
+    mac: MacAddress,
+    status: WorkerStatus,
+    wake: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkEntryError {
+    index: usize,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkReportRes {
+    written: usize,
+    errors: Vec<BulkEntryError>,
+}
+
+pub struct BulkReportRequestHandler {}
+
+async fn bulk_report(
+    req: BulkReportReq,
+    max_entries: usize,
+    store: &(impl StatusStore + ?Sized),
+) -> Result<BulkReportRes, ApiError> {
+    if req.entries.len() > max_entries {
+        return Err(api_err!(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Too many entries ({} > {})!",
+            req.entries.len(),
+            max_entries
+        ));
+    }
+    let mut batch = vec![];
+    let mut errors = vec![];
+    for (index, entry) in req.entries.iter().enumerate() {
+        // deserialize from the borrowed value (mac_address requires &str)
+        match BulkEntry::deserialize(entry) {
+            Ok(e) => batch.push((e.mac, e.status, e.wake)),
+            Err(e) => errors.push(BulkEntryError {
+                index,
+                error: e.to_string(),
+            }),
+        }
+    }
+    store
+        .log_workerstatus_batch(&batch)
         .await
-        .map_err(|e| fwd_err!("Failed to log reported status! {}", e))?;
-        Ok(ReportRes {
-            woken: context.woken_in_previous_heartbeat(&mac),
-        })
+        .map_err(|e| fwd_err!("Failed to log bulk status! {}", e))?;
+    Ok(BulkReportRes {
+        written: batch.len(),
+        errors,
+    })
+}
+
+#[async_trait]
+impl RequestHandler<BulkReportReq, BulkReportRes> for BulkReportRequestHandler {
+    async fn handle(
+        &self,
+        req: BulkReportReq,
+        context: Context,
+    ) -> Result<BulkReportRes, ApiError> {
+        bulk_report(req, context.bulk_max_entries, context.status_store.as_ref()).await
     }
 }
 
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use super::*;
+    use crate::influx_gateway::test::InfluxClientMock;
+
+    #[test]
+    fn test_worker_token() {
+        let tokens =
+            parse_worker_tokens("11:22:33:44:55:66=s3cr3t, 11:22:33:44:55:77=other").unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_matches!(parse_worker_tokens("11:22:33:44:55:66"), Err(_));
+        assert_matches!(parse_worker_tokens("11:22:33:44:55:66="), Err(_));
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let unknown: MacAddress = "11:22:33:44:55:88".parse().unwrap();
+        assert_matches!(verify_worker_token(&tokens, &mac, Some("s3cr3t")), Ok(()));
+        for (m, token) in [(mac, Some("other")), (mac, None), (unknown, Some("s3cr3t"))] {
+            assert_matches!(
+                verify_worker_token(&tokens, &m, token),
+                Err(e) if e.code == StatusCode::UNAUTHORIZED,
+                "should reject token {:?} of {}",
+                token,
+                m
+            );
+        }
+        assert_matches!(
+            verify_worker_token(&HashMap::new(), &unknown, None),
+            Ok(()),
+            "should not require tokens by default"
+        );
+    }
+
+    #[test]
+    fn test_wake_reason() {
+        for (excess, woken, scheduled, expected) in [
+            (
+                Some(ExcessStatus::Yes),
+                true,
+                false,
+                "excess=Yes, recently woken",
+            ),
+            (Some(ExcessStatus::No), false, false, "excess=No"),
+            (
+                Some(ExcessStatus::Maybe),
+                true,
+                true,
+                "excess=Maybe, recently woken, scheduled wake",
+            ),
+            (None, false, true, "no heartbeat yet, scheduled wake"),
+        ] {
+            assert_eq!(wake_reason(excess, woken, scheduled, false), expected);
+        }
+        assert_eq!(
+            wake_reason(Some(ExcessStatus::Maybe), true, false, true),
+            "excess=maybe, recently woken",
+            "should follow EXCESS_JSON_LOWERCASE"
+        );
+        assert!(
+            !serde_json::to_value(ReportRes {
+                woken: false,
+                reason: None
+            })
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .contains_key("reason"),
+            "should omit the reason by default"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_reason() {
+        use crate::status_store::test::MemoryStore;
+        use crate::wake_heartbeat::HeartbeatSummary;
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::Yes));
+        context.resolved_mac = Some(mac);
+        let report = |context: Context| async move {
+            let req = ReportReq {
+                working: true,
+                wake: true,
+            };
+            ReportRequestHandler {}.handle(req, context).await.unwrap()
+        };
+        assert_eq!(report(context.clone()).await.reason, None);
+        context.report_reason = true;
+        context.set_last_heartbeat(HeartbeatSummary {
+            excess: ExcessStatus::Yes,
+            candidates: 1,
+            awake: 0,
+            woken: 1,
+            scheduled: 0,
+        });
+        assert_eq!(
+            report(context.clone()).await.reason.as_deref(),
+            Some("excess=Yes")
+        );
+        context.just_woke([mac].into_iter().collect());
+        let res = report(context.clone()).await;
+        assert!(res.woken);
+        assert_eq!(
+            res.reason.as_deref(),
+            Some("excess=Yes, recently woken"),
+            "should explain the woken state"
+        );
+        context.excess_json_lowercase = true;
+        assert_eq!(
+            report(context).await.reason.as_deref(),
+            Some("excess=yes, recently woken"),
+            "should follow EXCESS_JSON_LOWERCASE"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_failed_write() {
+        let mac: MacAddress = "11:22:33:44:55:99".parse().unwrap();
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(InfluxClientMock {
+            answer_map: HashMap::from([("workerstatus".into(), "ERROR: write failed".into())]),
+            ..Default::default()
+        });
+        context.resolved_mac = Some(mac);
+        context.metrics_max_macs = 1000;
+        let req = ReportReq {
+            working: true,
+            wake: false,
+        };
+        assert_matches!(
+            ReportRequestHandler {}.handle(req, context).await,
+            Err(e) if e.code == StatusCode::BAD_GATEWAY
+        );
+        assert!(
+            !metrics::render().contains(&mac.to_string()),
+            "should not expose a status which was not stored"
+        );
+    }
+
+    fn bulk_req(entries: &str) -> BulkReportReq {
+        serde_json::from_str(&format!(r#"{{"entries": [{}]}}"#, entries)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bulk_report() {
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([(
+                "workerstatus,mac=11:22:33:44:55:66 status=0i,wake=false".to_string(),
+                "".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let entry = r#"{"mac": "11:22:33:44:55:66", "status": "Sleep", "wake": false}"#;
+        assert_matches!(
+            bulk_report(bulk_req(&[entry; 3].join(",")), 2, &client).await,
+            Err(e) if e.code == StatusCode::PAYLOAD_TOO_LARGE,
+            "should reject oversized batch"
+        );
+        let entries = [
+            entry,
+            r#"{"mac": "not-a-mac", "status": "Sleep", "wake": false}"#,
+            r#"{"mac": "11:22:33:44:55:77", "status": "Dreaming", "wake": true}"#,
+            r#"{"mac": "11:22:33:44:55:88", "status": "Awake", "wake": true}"#,
+        ];
+        let res = bulk_report(bulk_req(&entries.join(",")), 10, &client)
+            .await
+            .unwrap();
+        assert_eq!(res.written, 2, "should write the valid entries");
+        assert_eq!(
+            res.errors.iter().map(|e| e.index).collect::<Vec<_>>(),
+            vec![1, 2],
+            "should collect errors of invalid entries"
+        );
+    }
 }
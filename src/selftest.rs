@@ -0,0 +1,83 @@
+use crate::config::ConfigSource;
+use crate::neighbor::{wake_macs_on_port, InterfacePrefixes, MacIpMapping, WakeOptions};
+use anyhow::{bail, Context, Result};
+use mac_address::MacAddress;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// locally administered mac (not assigned to any device)
+const SELFTEST_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+pub fn selftest_requested(config: &ConfigSource, args: &[String]) -> bool {
+    // SELFTEST=wol (env var or config file) or --selftest-wol
+    config.var("SELFTEST").as_deref() == Ok("wol") || args.iter().any(|a| a == "--selftest-wol")
+}
+
+pub async fn selftest_wol(timeout: Duration) -> Result<()> {
+    // receive a magic packet sent to the loopback listener via 'wake_macs'
+    let listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .with_context(|| "Failed to bind self-test listener")?;
+    let port = listener.local_addr()?.port();
+    let mac = MacAddress::new(SELFTEST_MAC);
+    let mac_mapping: MacIpMapping = [(mac, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)))]
+        .into_iter()
+        .collect();
+    wake_macs_on_port(
+        &HashSet::from([mac]),
+        &mac_mapping,
+        port,
+        WakeOptions::default(),
+        &InterfacePrefixes::new(),
+    )
+    .await
+    .with_context(|| "Failed to send magic packet")?;
+    let mut buf = [0u8; 256];
+    let (n, from) = tokio::time::timeout(timeout, listener.recv_from(&mut buf))
+        .await
+        .with_context(|| "No magic packet received")??;
+    if buf[..n] != wake_on_lan::MagicPacket::new(&SELFTEST_MAC).magic_bytes()[..] {
+        bail!("Received invalid magic packet from {}", from);
+    }
+    info!("[selftest] received magic packet from {}", from);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_selftest_requested() {
+        let config = |selftest: &str| {
+            ConfigSource::load_with(HashMap::from([("SELFTEST".into(), selftest.into())])).unwrap()
+        };
+        let unset = ConfigSource::default();
+        assert!(selftest_requested(&config("wol"), &[]));
+        assert!(selftest_requested(&unset, &["--selftest-wol".into()]));
+        assert!(!selftest_requested(&unset, &["--help".into()]));
+        assert!(!selftest_requested(&config("other"), &[]));
+
+        let path =
+            std::env::temp_dir().join(format!("pv_informant_selftest_{}.toml", std::process::id()));
+        std::fs::write(&path, "SELFTEST = \"wol\"\n").unwrap();
+        let file_config = ConfigSource::load_with(HashMap::from([(
+            "CONFIG_FILE".into(),
+            path.to_string_lossy().to_string(),
+        )]))
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            selftest_requested(&file_config, &[]),
+            "should honour SELFTEST of the config file"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_selftest_wol() {
+        assert_matches!(selftest_wol(Duration::from_secs(2)).await, Ok(()));
+    }
+}
@@ -1,22 +1,88 @@
 use std::convert::Infallible;
+use std::io::Read;
 use std::str::FromStr;
 
+use crate::admin_handler::{AdminStatusRequestHandler, ResetBackoffRequestHandler};
 use crate::api_baderr;
-use crate::context::Context;
+use crate::context::{Context, WARMUP_RETRY_SECONDS};
+use crate::debug_handler::{SnapshotRequestHandler, StatusRequestHandler};
 use crate::errors::{ApiError, GenericError, Result};
 use crate::excess_handler::ExcessRequestHandler;
-use crate::interval_handler::IntervalRequestHandler;
-use crate::report_handler::ReportRequestHandler;
-use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH};
+use crate::interval_handler::{IntervalReq, IntervalRequestHandler};
+use crate::metrics;
+use crate::report_handler::{BulkReportRequestHandler, ReportRequestHandler, X_WORKER_TOKEN};
+use crate::shutdown::ShutdownSignal;
+use crate::wake_handler::WakeRequestHandler;
+use crate::workers_handler::WorkersRequestHandler;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING,
+    CONTENT_LENGTH, RETRY_AFTER, WARNING,
+};
+use hyper::server::accept;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{
-    body::to_bytes, header, server::conn::AddrStream, Body, Method, Request, Response, Server,
-    StatusCode,
+    header, server::conn::AddrStream, Body, Method, Request, Response, Server, StatusCode,
 };
 use log::{error, debug, warn};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::fmt;
 use std::net::{SocketAddr, IpAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use tokio::net::{UnixListener, UnixStream};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    // 'unix:/path/to/sock'
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = std::net::AddrParseError;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddr::Unix(path.into())),
+            None => s.parse().map(ListenAddr::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub fn parse_listen_addrs(s: &str) -> std::result::Result<Vec<ListenAddr>, String> {
+    // comma-separated (e.g. lan and vpn address)
+    let addrs: Vec<ListenAddr> = s
+        .split(',')
+        .map(|a| {
+            a.trim()
+                .parse()
+                .map_err(|e| format!("Invalid listen address '{}'! {}", a.trim(), e))
+        })
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(addrs)
+}
+
+fn bind_unix(path: &Path) -> std::io::Result<UnixListener> {
+    // replace the socket of a previous run
+    if std::fs::metadata(path)
+        .map(|m| m.file_type().is_socket())
+        .unwrap_or(false)
+    {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
 
 pub struct InformantServer {
     context: Context,
@@ -29,34 +95,96 @@ impl InformantServer {
 
 #[async_trait]
 pub trait HyperServerWrapper {
-    async fn serve(&self) -> std::result::Result<(), hyper::Error>;
+    // serve until the shutdown signal (completing in-flight requests)
+    async fn serve(&self, shutdown: ShutdownSignal) -> std::result::Result<(), GenericError>;
 }
 
 #[async_trait]
 impl HyperServerWrapper for InformantServer {
-    async fn serve(&self) -> std::result::Result<(), hyper::Error> {
-        let context = &self.context.clone();
-        let service = make_service_fn(move |stream: &AddrStream| {
-            let mut context = context.clone();
-            context.remote_addr = Some(stream.remote_addr());
-
-            async {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    let mut context = context.clone();
-                    if let Ok(remote_ip) = parse_header::<IpAddr>(req.headers(), HeaderName::from_static("x-forwarded-for")) {
-                        context.remote_addr = Some(SocketAddr::new(remote_ip, context.remote_addr.unwrap().port()));
-                    }
-                    route_request(req, context.to_owned())
-                }))
-            }
-        });
-        Server::bind(&context.local_addr).serve(service).await
+    async fn serve(&self, shutdown: ShutdownSignal) -> std::result::Result<(), GenericError> {
+        // all listen addresses concurrently
+        futures::future::try_join_all(
+            self.context
+                .local_addrs
+                .iter()
+                .map(|addr| serve_on(addr.clone(), self.context.clone(), shutdown.clone())),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+async fn serve_on(
+    addr: ListenAddr,
+    context: Context,
+    shutdown: ShutdownSignal,
+) -> std::result::Result<(), GenericError> {
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let service = make_service_fn(move |stream: &AddrStream| {
+                let mut context = context.clone();
+                context.remote_addr = Some(stream.remote_addr());
+                async {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        route_forwarded(req, context.to_owned())
+                    }))
+                }
+            });
+            Ok(Server::bind(&addr)
+                .serve(service)
+                .with_graceful_shutdown(shutdown)
+                .await?)
+        }
+        ListenAddr::Unix(path) => {
+            let listener = bind_unix(&path)?;
+            let accept = accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|r| Some(r.map(|(stream, _)| stream)))
+            });
+            let service = make_service_fn(move |_: &UnixStream| {
+                // no peer ip (unless forwarded by a reverse proxy)
+                let context = context.clone();
+                async {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        route_forwarded(req, context.to_owned())
+                    }))
+                }
+            });
+            Ok(Server::builder(accept)
+                .serve(service)
+                .with_graceful_shutdown(shutdown)
+                .await?)
+        }
     }
 }
 
+async fn route_forwarded(
+    req: Request<Body>,
+    mut context: Context,
+) -> std::result::Result<Response<Body>, GenericError> {
+    if let Ok(remote_ip) =
+        parse_header::<IpAddr>(req.headers(), HeaderName::from_static("x-forwarded-for"))
+    {
+        let port = context.remote_addr.map(|a| a.port()).unwrap_or(0);
+        context.remote_addr = Some(SocketAddr::new(remote_ip, port));
+    }
+    route_request(req, context).await
+}
+
 const INTERVAL: IntervalRequestHandler = IntervalRequestHandler {};
 const REPORT: ReportRequestHandler = ReportRequestHandler {};
+const BULK_REPORT: BulkReportRequestHandler = BulkReportRequestHandler {};
 const EXCESS: ExcessRequestHandler = ExcessRequestHandler {};
+const ADMIN_STATUS: AdminStatusRequestHandler = AdminStatusRequestHandler {};
+const RESET_BACKOFF: ResetBackoffRequestHandler = ResetBackoffRequestHandler {};
+const WAKE: WakeRequestHandler = WakeRequestHandler {};
+const SNAPSHOT: SnapshotRequestHandler = SnapshotRequestHandler {};
+const STATUS: StatusRequestHandler = StatusRequestHandler {};
+const WORKERS: WorkersRequestHandler = WorkersRequestHandler {};
+
+const X_REQUEST_ID: &str = "x-request-id";
+const X_INFLUX_QUERY_MS: &str = "x-influx-query-ms";
 
 static INDEX: &[u8] = b"<p>GET /excess or POST json to /interval or /report</p>";
 // 5 MiB
@@ -77,6 +205,53 @@ fn parse_header<T: FromStr>(
         })
 }
 
+fn request_id(headers: &HeaderMap<HeaderValue>) -> String {
+    // reuse the id of the requester for correlating logs (generate one if absent)
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|hdr| hdr.to_str().ok())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+fn request_info(remote_addr: &impl fmt::Display, request_id: &str, uri: &hyper::Uri) -> String {
+    format!("[{}] [{}] {}", remote_addr, request_id, uri)
+}
+
+fn authorize_admin(headers: &HeaderMap<HeaderValue>, context: &Context) -> Result<()> {
+    let token = context
+        .admin_token
+        .as_ref()
+        .ok_or_else(|| api_err!(StatusCode::FORBIDDEN, "Admin endpoints are disabled!"))?;
+    match headers
+        .get(AUTHORIZATION)
+        .and_then(|hdr| hdr.to_str().ok())
+        .and_then(|hdr| hdr.strip_prefix("Bearer "))
+    {
+        Some(t) if t == token => Ok(()),
+        _ => Err(api_err!(StatusCode::UNAUTHORIZED, "Invalid admin token!")),
+    }
+}
+
+pub fn query_param(query_str: &str, key: &str) -> Option<String> {
+    // percent-decoded (form rules: '+' is a space)
+    form_urlencoded::parse(query_str.as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+async fn resolve_mac_early(context: &mut Context) -> Result<()> {
+    // reject unidentifiable requesters without parsing their body
+    if context.resolve_mac_early {
+        let mac = context.remote_mac().await?.ok_or_else(|| {
+            api_err!(StatusCode::FORBIDDEN, "mac address of requestor not found!")
+        })?;
+        context.resolved_mac = Some(mac);
+    }
+    Ok(())
+}
+
 #[async_trait]
 pub trait RequestHandler<D, S>
 where
@@ -92,54 +267,299 @@ fn json_reponse(json: String) -> Result<Response<Body>> {
         .body(Body::from(json))?)
 }
 
-async fn json_request<D>(req: Request<Body>) -> Result<D>
+fn csv_response(csv: String) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/csv")
+        .body(Body::from(csv))?)
+}
+
+fn accepts_csv(headers: &HeaderMap<HeaderValue>) -> bool {
+    // 'Accept: text/csv' (json otherwise)
+    headers
+        .get(ACCEPT)
+        .and_then(|hdr| hdr.to_str().ok())
+        .map(|hdr| {
+            hdr.split(',')
+                .any(|t| t.split(';').next().map(str::trim) == Some("text/csv"))
+        })
+        .unwrap_or(false)
+}
+
+async fn json_request<D>(req: Request<Body>, accept_chunked: bool) -> Result<D>
 where
     D: DeserializeOwned,
 {
-    let content_length: u32 = parse_header(req.headers(), CONTENT_LENGTH)
-        .map_err(|e| api_err!(StatusCode::LENGTH_REQUIRED, "{}", e.message))?;
-    if content_length > MAX_CONENT_LENGTH {
-        return Err(api_err!(
-            StatusCode::PAYLOAD_TOO_LARGE,
-            "Content too large! Max: {}!",
-            MAX_CONENT_LENGTH
-        ));
+    let content_length: Option<u32> = match parse_header(req.headers(), CONTENT_LENGTH) {
+        Ok(l) => Some(l),
+        // chunked requests have no content-length
+        Err(_) if accept_chunked && !req.headers().contains_key(CONTENT_LENGTH) => None,
+        Err(e) => return Err(api_err!(StatusCode::LENGTH_REQUIRED, "{}", e.message)),
+    };
+    if content_length.unwrap_or(0) > MAX_CONENT_LENGTH {
+        return Err(too_large());
     }
+    let gzip = match req.headers().get(CONTENT_ENCODING).map(|v| v.to_str()) {
+        None => false,
+        Some(Ok(e)) if e.eq_ignore_ascii_case("identity") => false,
+        Some(Ok(e)) if e.eq_ignore_ascii_case("gzip") => true,
+        Some(_) => {
+            return Err(api_err!(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported content-encoding! (gzip)"
+            ))
+        }
+    };
     // mac_address tries to deserialize from borrowed &str
     // (does not work with from_reader)
-    //use bytes::Buf;
-    //let whole_body = hyper::body::aggregate(req).await?;
-    //let value: D = serde_json::from_reader(std::io::BufReader::new(whole_body.reader()))
-    //.map_err(|e| api_baderr!("[JSON-Error] {}", e))?;
+    let b = read_body(req.into_body(), content_length).await?;
+    let b = if gzip { gunzip(&b)? } else { b };
+    serde_json::from_slice(&b).map_err(json_error)
+}
+
+fn gunzip(compressed: &[u8]) -> Result<Vec<u8>> {
+    // enforce the decompressed size (decompression bomb)
+    let mut buf = Vec::new();
+    flate2::read::GzDecoder::new(compressed)
+        .take(MAX_CONENT_LENGTH as u64 + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| api_baderr!("Invalid gzip body! {}", e))?;
+    if buf.len() > MAX_CONENT_LENGTH as usize {
+        return Err(too_large());
+    }
+    Ok(buf)
+}
 
-    let b = to_bytes(req.into_body()).await?;
-    serde_json::from_slice(&b).map_err(|e| api_baderr!("[JSON-Error] {}", e))
+fn too_large() -> ApiError {
+    api_err!(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "Content too large! Max: {}!",
+        MAX_CONENT_LENGTH
+    )
+}
+
+async fn read_body(mut body: Body, content_length: Option<u32>) -> Result<Vec<u8>> {
+    // enforce the actual body size (the content-length may be a lie)
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let len = (buf.len() + chunk.len()) as u64;
+        match content_length {
+            Some(l) if len > l as u64 => {
+                return Err(api_baderr!("Body exceeds content-length of {}!", l))
+            }
+            _ if len > MAX_CONENT_LENGTH as u64 => return Err(too_large()),
+            _ => buf.extend_from_slice(&chunk),
+        }
+    }
+    Ok(buf)
+}
+
+fn json_error(e: serde_json::Error) -> ApiError {
+    let msg = e.to_string();
+    match e.classify() {
+        // name the offending field without the position (e.g. "missing field `wake`")
+        serde_json::error::Category::Data => api_baderr!(
+            "Invalid request body: {}",
+            msg.rsplit_once(" at line ").map(|(m, _)| m).unwrap_or(&msg)
+        ),
+        _ => api_baderr!("[JSON-Error] {}", msg),
+    }
+}
+
+fn warning_header(warning: &str) -> Option<HeaderValue> {
+    // 199: miscellaneous warning (quotes and control characters removed)
+    let text: String = warning
+        .chars()
+        .filter(|c| c.is_ascii() && !c.is_ascii_control() && *c != '"')
+        .collect();
+    HeaderValue::from_str(&format!("199 pv_informant \"{}\"", text)).ok()
+}
+
+fn with_cache_control(resp: Result<Response<Body>>, max_age: u64) -> Result<Response<Body>> {
+    // only successful responses may be cached
+    resp.map(|mut r| {
+        if let Ok(v) = HeaderValue::from_str(&format!("max-age={}", max_age)) {
+            r.headers_mut().insert(CACHE_CONTROL, v);
+        }
+        r
+    })
+}
+
+fn error_body(e: ApiError, debug_upstream: bool) -> String {
+    match e.code {
+        // hide wildcard 500 error when not debugging
+        StatusCode::INTERNAL_SERVER_ERROR if !cfg!(debug_assertions) => {
+            "internal server error!".to_string()
+        }
+        // hide raw influxdb errors unless configured
+        StatusCode::BAD_GATEWAY if !debug_upstream => "upstream error!".to_string(),
+        _ => e.message,
+    }
+}
+
+fn not_ready() -> ApiError {
+    api_err!(StatusCode::SERVICE_UNAVAILABLE, "Not ready (warming up)!")
 }
 
 macro_rules! json_resp {
     { $value:expr } => { async move { json_reponse(serde_json::to_string(&$value.await?)?) }.await }
 }
 
+#[cfg_attr(
+    feature = "otel",
+    tracing::instrument(
+        name = "route_request",
+        skip_all,
+        fields(method = %req.method(), path = %req.uri().path())
+    )
+)]
 async fn route_request(
     req: Request<Body>,
     context: Context,
 ) -> std::result::Result<Response<Body>, GenericError> {
     let uri = req.uri();
-    let info_str = format!("[{}] {}", context.remote_addr.unwrap(), uri);
+    let request_id = request_id(req.headers());
+    let remote_addr = context
+        .remote_addr
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| "unix".into());
+    let info_str = request_info(&remote_addr, &request_id, uri);
+    let debug_upstream = context.debug_upstream_errors;
     let resp = match (req.method(), uri.path()) {
         (&Method::POST, "/") | (&Method::GET, "/") | (&Method::GET, "/index.html") => {
             Ok(Response::new(INDEX.into()))
         }
-        (&Method::POST, "/interval") => {
+        // browsers request it with the index (avoid 404 log noise)
+        (&Method::GET, "/favicon.ico") => Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap()),
+        (&Method::POST, "/interval") | (&Method::GET, "/interval") => {
             async move {
-                json_reponse(INTERVAL.handle(json_request(req).await?, context).await?)
-            }.await
+                let mut context = context;
+                if context.restrict_interval_to_self {
+                    resolve_mac_early(&mut context).await?;
+                }
+                let timing_header = context.query_timing_header;
+                context.accept_csv = accepts_csv(req.headers());
+                let csv = context.accept_csv;
+                let interval_req = if req.method() == Method::GET {
+                    // '?start=...&stop=...&mac=...'
+                    IntervalReq::from_query(req.uri().query().unwrap_or(""))?
+                } else {
+                    json_request(req, context.accept_chunked).await?
+                };
+                let res = INTERVAL.handle(interval_req, context).await?;
+                let mut response = if csv {
+                    csv_response(res.history)?
+                } else {
+                    json_reponse(res.history)?
+                };
+                if let Some(w) = res.warning.and_then(|w| warning_header(&w)) {
+                    response.headers_mut().insert(WARNING, w);
+                }
+                if timing_header {
+                    response
+                        .headers_mut()
+                        .insert(X_INFLUX_QUERY_MS, HeaderValue::from(res.query_ms as u64));
+                }
+                Ok(response)
+            }
+            .await
+        }
+        (&Method::GET, "/health") => match context.is_ready() {
+            true => Ok(Response::new("ok".into())),
+            false => Err(not_ready()),
+        },
+        (&Method::GET, "/status") => json_resp!(STATUS.handle((), context)),
+        // readiness probe (status store reachable now)
+        (&Method::GET, "/healthz") => match context.status_store.health_check().await {
+            Ok(()) => json_reponse(r#"{"influx": "ok"}"#.into()),
+            Err(e) => Err(api_err!(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "InfluxDB is unreachable! {}",
+                e
+            )),
+        },
+        // no misleading data before the status store is reachable
+        (&Method::GET, "/excess") | (&Method::POST, "/report") if !context.is_ready() => {
+            Err(not_ready())
         }
         (&Method::GET, "/excess") => {
-            json_resp!(EXCESS.handle(req.uri().query().unwrap_or("").into(), context))
+            let max_age = context.excess_cache_seconds;
+            with_cache_control(
+                json_resp!(EXCESS.handle(req.uri().query().unwrap_or("").into(), context)),
+                max_age,
+            )
         }
         (&Method::POST, "/report") => {
-            json_resp!(REPORT.handle(json_request(req).await?, context))
+            async move {
+                let mut context = context;
+                resolve_mac_early(&mut context).await?;
+                context.worker_token = req
+                    .headers()
+                    .get(X_WORKER_TOKEN)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string());
+                json_resp!(REPORT.handle(json_request(req, context.accept_chunked).await?, context))
+            }
+            .await
+        }
+        (&Method::POST, "/report/bulk") => {
+            async move {
+                authorize_admin(req.headers(), &context)?;
+                json_resp!(
+                    BULK_REPORT.handle(json_request(req, context.accept_chunked).await?, context)
+                )
+            }
+            .await
+        }
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render()))
+            .map_err(ApiError::from),
+        (&Method::POST, "/admin/status") => {
+            async move {
+                authorize_admin(req.headers(), &context)?;
+                json_reponse(serde_json::to_string(
+                    &ADMIN_STATUS
+                        .handle(json_request(req, context.accept_chunked).await?, context)
+                        .await?,
+                )?)
+            }
+            .await
+        }
+        (&Method::POST, "/admin/reset-backoff") => {
+            async move {
+                authorize_admin(req.headers(), &context)?;
+                json_reponse(serde_json::to_string(
+                    &RESET_BACKOFF
+                        .handle(json_request(req, context.accept_chunked).await?, context)
+                        .await?,
+                )?)
+            }
+            .await
+        }
+        (&Method::POST, "/wake") => {
+            async move {
+                authorize_admin(req.headers(), &context)?;
+                json_resp!(WAKE.handle((), context))
+            }
+            .await
+        }
+        (&Method::GET, "/debug/snapshot") => {
+            async move {
+                authorize_admin(req.headers(), &context)?;
+                json_resp!(SNAPSHOT.handle((), context))
+            }
+            .await
+        }
+        (&Method::GET, "/workers") => {
+            async move {
+                authorize_admin(req.headers(), &context)?;
+                json_resp!(WORKERS.handle(req.uri().query().unwrap_or("").into(), context))
+            }
+            .await
         }
         _ => {
             // Return 404 not found response.
@@ -150,10 +570,10 @@ async fn route_request(
             .into())
         }
     };
-    match resp {
+    let mut response = match resp {
         Ok(r) => {
             debug!("{}: OK", info_str);
-            Ok(r)
+            r
         }
         Err(e) => {
             match e.code {
@@ -162,24 +582,29 @@ async fn route_request(
                 }
                 _ => warn!("{}: {}", info_str, e),
             }
-            Ok(Response::builder()
+            let retry = e.code == StatusCode::SERVICE_UNAVAILABLE;
+            let mut r = Response::builder()
                 .status(e.code)
-                .body(Body::from(
-                    // hide wildcard 500 error when not debugging
-                    if cfg!(debug_assertions) || e.code != StatusCode::INTERNAL_SERVER_ERROR {
-                        e.message
-                    } else {
-                        "internal server error!".to_string()
-                    },
-                ))
-                .unwrap())
+                .body(Body::from(error_body(e, debug_upstream)))
+                .unwrap();
+            if retry {
+                r.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(WARMUP_RETRY_SECONDS));
+            }
+            r
         }
+    };
+    if let Ok(id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(X_REQUEST_ID, id);
     }
+    Ok(response)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::shutdown::shutdown_on;
+    use futures::FutureExt;
     use hyper::body::Body;
     use hyper::StatusCode;
     use mac_address::MacAddress;
@@ -247,21 +672,22 @@ mod test {
             .body(Body::from(json.clone()))
             .unwrap();
         assert_matches!(
-            json_request::<RequestMock>(bad_req).await,
+            json_request::<RequestMock>(bad_req, false).await,
             Err(e) if e.code == StatusCode::LENGTH_REQUIRED,
             "should require a content-length header"
         );
         assert_matches!(
-            json_request::<RequestMock>(create_req("abc", json.clone())).await,
+            json_request::<RequestMock>(create_req("abc", json.clone()), false).await,
             Err(e) if e.code == StatusCode::LENGTH_REQUIRED,
             "should require valid content-length header"
         );
         assert_matches!(json_request::<RequestMock>(
             create_req(MAX_CONENT_LENGTH + 1, json.clone()),
+            false,
         )
         .await, Err(e) if e.code == StatusCode::PAYLOAD_TOO_LARGE, "should reject too large content-length");
 
-        let req: RequestMock = json_request(create_req("1000", json.clone()))
+        let req: RequestMock = json_request(create_req("1000", json.clone()), false)
             .await
             .unwrap();
 
@@ -281,4 +707,729 @@ mod test {
             "should serialize handler response"
         );
     }
+
+    fn chunked_req(chunks: Vec<String>) -> Request<Body> {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for c in chunks {
+                if sender.send_data(c.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Request::builder().method(Method::POST).body(body).unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip_req(body: Vec<u8>) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_LENGTH, body.len())
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_gzip_body() {
+        let json = r#"{"mac": "00:00:00:00:00:00", "value": "127"}"#;
+        assert_matches!(
+            json_request::<RequestMock>(gzip_req(gzip(json.as_bytes())), false).await,
+            Ok(r) if r.value == "127",
+            "should decompress gzip body"
+        );
+        let bomb = gzip(&vec![b' '; MAX_CONENT_LENGTH as usize + 1]);
+        assert!(bomb.len() < MAX_CONENT_LENGTH as usize);
+        assert_matches!(
+            json_request::<RequestMock>(gzip_req(bomb), false).await,
+            Err(e) if e.code == StatusCode::PAYLOAD_TOO_LARGE,
+            "should reject decompressed body larger than the max content length"
+        );
+        assert_matches!(
+            json_request::<RequestMock>(gzip_req(json.as_bytes().to_vec()), false).await,
+            Err(e) if e.code == StatusCode::BAD_REQUEST,
+            "should reject invalid gzip body"
+        );
+        let mut req = create_req(json.len(), json.into());
+        req.headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert_matches!(
+            json_request::<RequestMock>(req, false).await,
+            Err(e) if e.code == StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "should reject unsupported encodings"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_size() {
+        let json = r#"{"mac": "00:00:00:00:00:00", "value": "127"}"#;
+        assert_matches!(
+            json_request::<RequestMock>(create_req(10, json.into()), false).await,
+            Err(e) if e.code == StatusCode::BAD_REQUEST,
+            "should reject body larger than content-length"
+        );
+        let chunks = || vec![json[..10].to_string(), json[10..].to_string()];
+        assert_matches!(
+            json_request::<RequestMock>(chunked_req(chunks()), false).await,
+            Err(e) if e.code == StatusCode::LENGTH_REQUIRED,
+            "should require content-length unless chunked requests are accepted"
+        );
+        assert_matches!(
+            json_request::<RequestMock>(chunked_req(chunks()), true).await,
+            Ok(r) if r.value == "127",
+            "should accept chunked body"
+        );
+        let huge = vec!["x".repeat(1 << 20); (MAX_CONENT_LENGTH >> 20) as usize + 1];
+        assert_matches!(
+            json_request::<RequestMock>(chunked_req(huge), true).await,
+            Err(e) if e.code == StatusCode::PAYLOAD_TOO_LARGE,
+            "should limit chunked body size"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_missing_fields() {
+        use crate::report_handler::ReportReq;
+        for (json, field) in [
+            (r#"{"working": true}"#, "`wake`"),
+            (r#"{"wake": true}"#, "`working`"),
+        ] {
+            assert_matches!(
+                json_request::<ReportReq>(create_req(json.len(), json.into()), false).await,
+                Err(e) if e.code == StatusCode::BAD_REQUEST
+                    && e.message == format!("Invalid request body: missing field {}", field),
+                "should name the missing field"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report_unknown_fields() {
+        use crate::report_handler::ReportReq;
+        let json = r#"{"working": true, "wake": false, "status": 2}"#;
+        assert_matches!(
+            json_request::<ReportReq>(create_req(json.len(), json.into()), false).await,
+            Err(e) if e.code == StatusCode::BAD_REQUEST
+                && e.message.starts_with("Invalid request body: unknown field `status`"),
+            "should reject unknown fields"
+        );
+        let json = r#"{"working": true, "wake": false}"#;
+        assert_matches!(
+            json_request::<ReportReq>(create_req(json.len(), json.into()), false).await,
+            Ok(_),
+            "should accept known fields"
+        );
+    }
+
+    #[test]
+    fn test_warning_header() {
+        assert_eq!(
+            warning_header("workerstatus query failed! \"x\"\n").unwrap(),
+            "199 pv_informant \"workerstatus query failed! x\""
+        );
+    }
+
+    #[test]
+    fn test_excess_cache_control() {
+        let resp = with_cache_control(json_reponse("2".into()), 5).unwrap();
+        assert_eq!(resp.headers()[CACHE_CONTROL], "max-age=5");
+        assert_matches!(
+            with_cache_control(Err(fwd_err!("influxdb not available")), 5),
+            Err(e) if e.code == StatusCode::BAD_GATEWAY,
+            "should not add cache header to errors"
+        );
+    }
+
+    #[test]
+    fn test_upstream_error_body() {
+        let upstream_err = || fwd_err!("Query failed! {}", "influxdb: connection refused");
+        assert_eq!(
+            error_body(upstream_err(), false),
+            "upstream error!",
+            "should hide upstream error detail by default"
+        );
+        assert_eq!(
+            error_body(upstream_err(), true),
+            "Query failed! influxdb: connection refused",
+            "should include upstream error detail when debugging"
+        );
+        assert_eq!(
+            error_body(api_baderr!("Invalid request!"), false),
+            "Invalid request!",
+            "should not hide client errors"
+        );
+    }
+
+    fn index_req(request_id: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(Method::GET).uri("/");
+        if let Some(id) = request_id {
+            builder = builder.header(X_REQUEST_ID, id);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn test_context() -> Context {
+        let mut context = Context::load().unwrap();
+        context.remote_addr = "127.0.0.1:80".parse().ok();
+        context
+    }
+
+    #[tokio::test]
+    async fn test_interval_csv() {
+        use crate::influx_gateway::ExcessStatus;
+        use crate::status_store::test::MemoryStore;
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::No));
+        let interval_req = |accept: &str| {
+            Request::builder()
+                .method(Method::GET)
+                .uri("/interval?start=2022-01-10T12:00:00Z&stop=2022-01-10T13:00:00Z")
+                .header(ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = route_request(interval_req("application/json"), context.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let resp = route_request(interval_req("text/html, text/csv;q=0.9"), context)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/csv",
+            "should respond with csv if accepted"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_annotate_excess_flux() {
+        use crate::influx_gateway::test::InfluxClientMock;
+        use std::collections::HashMap;
+        let mut context = Context::load().unwrap();
+        // flux history (csv)
+        context.status_store = std::sync::Arc::new(InfluxClientMock {
+            answer_map: HashMap::from([("from(bucket: \"pv\")".into(), "".into())]),
+            ..Default::default()
+        });
+        let json = r#"{"start": "2022-01-10T12:00:00Z", "stop": "2022-01-10T13:00:00Z", "annotate_excess": true}"#;
+        let mut req = create_req(json.len(), json.into());
+        *req.uri_mut() = "/interval".parse().unwrap();
+        let resp = route_request(req, context).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::BAD_REQUEST,
+            "should reject the excess annotation of a flux history"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_timing_header() {
+        use crate::influx_gateway::ExcessStatus;
+        use crate::status_store::test::MemoryStore;
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::No));
+        let interval_req = || {
+            let json = r#"{"start": "2022-01-10T12:00:00Z", "stop": "2022-01-10T13:00:00Z"}"#;
+            let mut req = create_req(json.len(), json.into());
+            *req.uri_mut() = "/interval".parse().unwrap();
+            req
+        };
+        let resp = route_request(interval_req(), context.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(
+            resp.headers().get(X_INFLUX_QUERY_MS).is_none(),
+            "should not add the header by default"
+        );
+        context.query_timing_header = true;
+        let resp = route_request(interval_req(), context).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_matches!(
+            resp.headers()[X_INFLUX_QUERY_MS]
+                .to_str()
+                .unwrap()
+                .parse::<u64>(),
+            Ok(_),
+            "should add the numeric query time"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_route_cache_control() {
+        use crate::influx_gateway::test::InfluxClientMock;
+        use crate::influx_gateway::ExcessStatus;
+        use crate::status_store::test::MemoryStore;
+        use std::collections::HashMap;
+        let get_excess = || {
+            Request::builder()
+                .method(Method::GET)
+                .uri("/excess")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::Yes));
+        context.excess_cache_seconds = 7;
+        let resp = route_request(get_excess(), context.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CACHE_CONTROL], "max-age=7");
+
+        context.excess_enabled = false;
+        let resp = route_request(get_excess(), context.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(
+            resp.headers().get(CACHE_CONTROL).is_none(),
+            "should not cache a disabled excess"
+        );
+
+        context.excess_enabled = true;
+        context.status_store = std::sync::Arc::new(InfluxClientMock {
+            answer_map: HashMap::from([(
+                "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m"
+                    .into(),
+                "ERROR: database unavailable".into(),
+            )]),
+            ..Default::default()
+        });
+        let resp = route_request(get_excess(), context).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert!(
+            resp.headers().get(CACHE_CONTROL).is_none(),
+            "should not cache upstream errors"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_json_casing() {
+        use crate::influx_gateway::ExcessStatus;
+        use crate::status_store::test::MemoryStore;
+        use crate::wake_heartbeat::HeartbeatSummary;
+        let get = |path: &str| {
+            Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .header(AUTHORIZATION, "Bearer s3cr3t")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let json = |resp: Response<Body>| async move {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::Maybe));
+        context.admin_token = Some("s3cr3t".into());
+        context.set_last_heartbeat(HeartbeatSummary {
+            excess: ExcessStatus::Yes,
+            candidates: 0,
+            awake: 0,
+            woken: 0,
+            scheduled: 0,
+        });
+        let excess = route_request(get("/excess"), context.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            json(excess).await,
+            "Maybe",
+            "should keep the pascal case by default"
+        );
+
+        context.excess_json_lowercase = true;
+        let excess = route_request(get("/excess"), context.clone())
+            .await
+            .unwrap();
+        assert_eq!(json(excess).await, "maybe");
+        let detail = route_request(get("/excess?detail=1"), context.clone())
+            .await
+            .unwrap();
+        assert_eq!(json(detail).await["status"], "maybe");
+        let snapshot = json(
+            route_request(get("/debug/snapshot"), context.clone())
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(
+            (&snapshot["excess"], &snapshot["last_heartbeat"]["excess"]),
+            (&serde_json::json!("yes"), &serde_json::json!("yes")),
+            "should lowercase the excess of all responses"
+        );
+        let wake = Request::builder()
+            .method(Method::POST)
+            .uri("/wake")
+            .header(AUTHORIZATION, "Bearer s3cr3t")
+            .body(Body::empty())
+            .unwrap();
+        let wake = json(route_request(wake, context.clone()).await.unwrap()).await;
+        assert_eq!(wake["excess"], "maybe");
+        assert_eq!(
+            serde_json::to_string(&ExcessStatus::Yes).unwrap(),
+            r#""Yes""#,
+            "should keep the pascal case in the state file"
+        );
+        assert_matches!(
+            serde_json::from_str::<ExcessStatus>(r#""maybe""#),
+            Ok(ExcessStatus::Maybe),
+            "should read both casings"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_ready() {
+        use crate::influx_gateway::ExcessStatus;
+        use crate::status_store::test::MemoryStore;
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::Yes));
+        let get = |path: &str| {
+            Request::builder()
+                .method(Method::GET)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mut report_req = create_req(2, "{}".into());
+        *report_req.uri_mut() = "/report".parse().unwrap();
+        context.set_ready(false);
+        for req in [get("/health"), get("/excess"), report_req] {
+            let resp = route_request(req, context.clone()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(
+                resp.headers()[RETRY_AFTER],
+                WARMUP_RETRY_SECONDS.to_string(),
+                "should tell clients to back off"
+            );
+        }
+
+        context.warmup().await;
+        assert!(
+            context.is_ready(),
+            "should be ready once the store is reachable"
+        );
+        let resp = route_request(get("/health"), context.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = route_request(get("/excess"), context).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(RETRY_AFTER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let status = || {
+            Request::builder()
+                .method(Method::GET)
+                .uri("/status")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let context = Context::load().unwrap();
+        let json = |resp: Response<Body>| async move {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let resp = route_request(status(), context.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            json(resp).await["next_heartbeat"],
+            serde_json::Value::Null,
+            "should not know the next heartbeat before the first tick"
+        );
+
+        context.record_tick(tokio::time::Instant::now());
+        let resp = route_request(status(), context.clone()).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::OK,
+            "should not require the admin token"
+        );
+        let next: chrono::DateTime<chrono::Utc> =
+            serde_json::from_value(json(resp).await["next_heartbeat"].clone()).unwrap();
+        let until_next = next - chrono::Utc::now();
+        assert!(
+            until_next > chrono::Duration::zero()
+                && until_next <= chrono::Duration::from_std(context.wake_interval).unwrap(),
+            "should respond with the next tick ({} from now)",
+            until_next
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn test_export_request_span() {
+        use crate::otel::test::exported_spans;
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/status")
+            .body(Body::empty())
+            .unwrap();
+        let spans = exported_spans(async {
+            route_request(req, Context::load().unwrap()).await.unwrap();
+        })
+        .await;
+        let span = spans
+            .iter()
+            .find(|s| s.name == "route_request")
+            .expect("should export a span of the request");
+        assert!(
+            span.attributes
+                .iter()
+                .any(|kv| kv.key.as_str() == "path" && kv.value.as_str() == "/status"),
+            "should include the path of the request"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        use crate::influx_gateway::test::InfluxClientMock;
+        let healthz = || {
+            Request::builder()
+                .method(Method::GET)
+                .uri("/healthz")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(InfluxClientMock::default());
+        let resp = route_request(healthz(), context.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"influx": "ok"})
+        );
+
+        context.status_store = std::sync::Arc::new(InfluxClientMock {
+            ping_err: true,
+            ..Default::default()
+        });
+        let resp = route_request(healthz(), context).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "should respond unavailable if influxdb is unreachable"
+        );
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(
+            String::from_utf8_lossy(&body).contains("mocked unreachable database"),
+            "should respond with the error"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_mac_early() {
+        let mut context = Context::load().unwrap();
+        // unidentifiable requester (no peer ip)
+        context.remote_addr = None;
+        let report_req = || {
+            let mut req = create_req(9, "not json!".into());
+            *req.uri_mut() = "/report".parse().unwrap();
+            req
+        };
+        let resp = route_request(report_req(), context.clone()).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::BAD_REQUEST,
+            "should parse the body before resolving the mac by default"
+        );
+        context.resolve_mac_early = true;
+        let resp = route_request(report_req(), context).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::FORBIDDEN,
+            "should reject the requester before parsing the body"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_favicon() {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/favicon.ico")
+            .body(Body::empty())
+            .unwrap();
+        let resp = route_request(req, test_context()).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::NO_CONTENT,
+            "should respond to favicon requests without an error"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("pv_informant_{}.sock", std::process::id()));
+        assert_eq!(
+            format!("unix:{}", path.display()).parse::<ListenAddr>(),
+            Ok(ListenAddr::Unix(path.clone()))
+        );
+        let mut context = Context::load().unwrap();
+        context.local_addrs = vec![ListenAddr::Unix(path.clone())];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            InformantServer::new(context)
+                .serve(shutdown_on(stopped.map(|_| ())))
+                .await
+        });
+        let mut stream = None;
+        for _ in 0..100 {
+            match UnixStream::connect(&path).await {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+        let (mut sender, conn) = hyper::client::conn::handshake(stream.unwrap())
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let resp = sender.send_request(index_req(None)).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::OK,
+            "should serve over the socket"
+        );
+        let body = r#"{"working": true, "wake": false}"#;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/report")
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(
+            resp.status(),
+            StatusCode::FORBIDDEN,
+            "should not find a mac without peer ip"
+        );
+        drop(sender);
+        stop.send(()).unwrap();
+        assert_matches!(
+            tokio::time::timeout(std::time::Duration::from_secs(5), server).await,
+            Ok(Ok(Ok(()))),
+            "should stop serving on shutdown"
+        );
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listen_addrs() {
+        assert_eq!(
+            parse_listen_addrs("127.0.0.1:3000, unix:/tmp/informant.sock"),
+            Ok(vec![
+                ListenAddr::Tcp("127.0.0.1:3000".parse().unwrap()),
+                ListenAddr::Unix("/tmp/informant.sock".into()),
+            ])
+        );
+        assert_matches!(parse_listen_addrs("127.0.0.1:3000,"), Err(_));
+
+        let free_addr = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .and_then(|l| l.local_addr())
+                .unwrap()
+        };
+        let addrs = [free_addr(), free_addr()];
+        let mut context = Context::load().unwrap();
+        context.local_addrs = addrs.iter().map(|a| ListenAddr::Tcp(*a)).collect();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            InformantServer::new(context)
+                .serve(shutdown_on(stopped.map(|_| ())))
+                .await
+        });
+        let client = hyper::Client::new();
+        for addr in addrs {
+            let uri: hyper::Uri = format!("http://{}/", addr).parse().unwrap();
+            let mut resp = None;
+            for _ in 0..100 {
+                match client.get(uri.clone()).await {
+                    Ok(r) => {
+                        resp = Some(r);
+                        break;
+                    }
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            }
+            assert_eq!(
+                resp.map(|r| r.status()),
+                Some(StatusCode::OK),
+                "should serve on {}",
+                addr
+            );
+        }
+        drop(client);
+        stop.send(()).unwrap();
+        assert_matches!(
+            tokio::time::timeout(std::time::Duration::from_secs(5), server).await,
+            Ok(Ok(Ok(()))),
+            "should stop serving on all addresses"
+        );
+    }
+
+    #[test]
+    fn test_authorize_admin() {
+        let mut context = test_context();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        context.admin_token = None;
+        assert_matches!(
+            authorize_admin(&headers, &context),
+            Err(e) if e.code == StatusCode::FORBIDDEN,
+            "should disable admin endpoints without configured token"
+        );
+        context.admin_token = Some("secret".into());
+        assert_matches!(authorize_admin(&headers, &context), Ok(()));
+        assert_matches!(
+            authorize_admin(&HeaderMap::new(), &context),
+            Err(e) if e.code == StatusCode::UNAUTHORIZED,
+            "should require a token"
+        );
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer wrong"));
+        assert_matches!(
+            authorize_admin(&headers, &context),
+            Err(e) if e.code == StatusCode::UNAUTHORIZED,
+            "should reject a wrong token"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let resp = route_request(index_req(Some("worker-42")), test_context())
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.headers()[X_REQUEST_ID],
+            "worker-42",
+            "should echo provided request id"
+        );
+        let remote_addr = "127.0.0.1:80".parse().unwrap();
+        assert!(
+            request_info(&remote_addr, "worker-42", &"/excess".parse().unwrap())
+                .contains("[worker-42]"),
+            "should log provided request id"
+        );
+
+        let resp = route_request(index_req(None), test_context())
+            .await
+            .unwrap();
+        let generated = resp.headers()[X_REQUEST_ID].to_str().unwrap();
+        assert_matches!(
+            Uuid::parse_str(generated),
+            Ok(_),
+            "should generate a request id if missing"
+        );
+    }
 }
@@ -0,0 +1,22 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::future::Future;
+use tokio::signal::unix::{signal, SignalKind};
+
+// resolves once for all clones (server and heartbeat)
+pub type ShutdownSignal = Shared<BoxFuture<'static, ()>>;
+
+pub fn shutdown_on(f: impl Future<Output = ()> + Send + 'static) -> ShutdownSignal {
+    f.boxed().shared()
+}
+
+pub fn shutdown_signal() -> std::io::Result<ShutdownSignal> {
+    // SIGTERM (systemd, docker) or SIGINT (ctrl-c)
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(shutdown_on(async move {
+        tokio::select! {
+            _ = terminate.recv() => info!("received SIGTERM, shutting down"),
+            _ = interrupt.recv() => info!("received SIGINT, shutting down"),
+        }
+    }))
+}
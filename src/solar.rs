@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+
+// sun elevation at sunrise/sunset (refraction and solar disk)
+const HORIZON_DEGREES: f64 = -0.833;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn sun_elevation(&self, now: DateTime<Utc>) -> f64 {
+        // approximate solar position (accurate to about 1 degree)
+        let d = now.timestamp() as f64 / 86400.0 + 2440587.5 - 2451545.0;
+        let anomaly = (357.529 + 0.98560028 * d).to_radians();
+        let mean_longitude = 280.459 + 0.98564736 * d;
+        let ecliptic_longitude =
+            (mean_longitude + 1.915 * anomaly.sin() + 0.020 * (2.0 * anomaly).sin()).to_radians();
+        let obliquity = (23.439 - 0.00000036 * d).to_radians();
+        let right_ascension = (obliquity.cos() * ecliptic_longitude.sin())
+            .atan2(ecliptic_longitude.cos())
+            .to_degrees();
+        let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+        let sidereal_hours = 18.697374558 + 24.06570982441908 * d;
+        let hour_angle = (sidereal_hours * 15.0 + self.longitude - right_ascension).to_radians();
+        let latitude = self.latitude.to_radians();
+        (latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos())
+            .asin()
+            .to_degrees()
+    }
+
+    // between local sunrise and sunset
+    pub fn is_daylight(&self, now: DateTime<Utc>) -> bool {
+        self.sun_elevation(now) > HORIZON_DEGREES
+    }
+}
+
+pub fn parse_location(
+    latitude: Option<String>,
+    longitude: Option<String>,
+) -> Result<Option<Location>, String> {
+    let parse = |v: &str, max: f64| match v.trim().parse::<f64>() {
+        Ok(c) if c.abs() <= max => Ok(c),
+        Ok(_) => Err(format!("Invalid coordinate '{}'! (-{} to {})", v, max, max)),
+        Err(e) => Err(format!("Invalid coordinate '{}'! {}", v, e)),
+    };
+    match (latitude, longitude) {
+        (None, None) => Ok(None),
+        (Some(lat), Some(lon)) => Ok(Some(Location {
+            latitude: parse(&lat, 90.0)?,
+            longitude: parse(&lon, 180.0)?,
+        })),
+        _ => Err("Invalid location config! (both LATITUDE and LONGITUDE required)".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_is_daylight() {
+        let berlin = Location {
+            latitude: 52.52,
+            longitude: 13.405,
+        };
+        // sunrise 02:43Z, sunset 19:33Z
+        assert!(!berlin.is_daylight(at("2024-06-21T02:30:00Z")));
+        assert!(berlin.is_daylight(at("2024-06-21T03:00:00Z")));
+        assert!(berlin.is_daylight(at("2024-06-21T12:00:00Z")));
+        assert!(!berlin.is_daylight(at("2024-06-21T19:45:00Z")));
+        assert!(!berlin.is_daylight(at("2024-12-21T03:00:00Z")));
+        assert!(berlin.is_daylight(at("2024-12-21T11:00:00Z")));
+        assert!(!berlin.is_daylight(at("2024-12-21T16:00:00Z")));
+
+        let tromso = Location {
+            latitude: 69.65,
+            longitude: 18.96,
+        };
+        assert!(
+            !tromso.is_daylight(at("2024-12-21T11:00:00Z")),
+            "should be dark in the polar night"
+        );
+        assert!(
+            tromso.is_daylight(at("2024-06-21T23:00:00Z")),
+            "should be light in the midnight sun"
+        );
+    }
+
+    #[test]
+    fn test_parse_location() {
+        assert_eq!(parse_location(None, None), Ok(None));
+        assert_eq!(
+            parse_location(Some("52.52".into()), Some(" 13.405".into())),
+            Ok(Some(Location {
+                latitude: 52.52,
+                longitude: 13.405
+            }))
+        );
+        assert_matches!(parse_location(Some("52.52".into()), None), Err(_));
+        assert_matches!(parse_location(Some("91".into()), Some("0".into())), Err(_));
+        assert_matches!(parse_location(Some("x".into()), Some("0".into())), Err(_));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::influx_gateway::ExcessStatus;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use mac_address::MacAddress;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::Path;
+
+// last heartbeat decision (replayed on startup)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PersistedState {
+    pub excess: ExcessStatus,
+    // woken macs with the time of waking
+    pub woken: Vec<(MacAddress, DateTime<Utc>)>,
+    pub saved_at: DateTime<Utc>,
+    // last known ips of macs (WAKE_LAST_KNOWN_IP, replayed regardless of age)
+    #[serde(default)]
+    pub last_known_ips: Vec<(MacAddress, IpAddr)>,
+}
+
+pub fn save_state(path: &Path, state: &PersistedState) -> Result<()> {
+    // replace atomically (no partial state after a crash)
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_string(state)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_state(path: &Path) -> Option<PersistedState> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Failed to read state file {:?}! {}", path, e);
+            return None;
+        }
+    };
+    match serde_json::from_str::<PersistedState>(&content) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            warn!("Invalid state file {:?}! {}", path, e);
+            None
+        }
+    }
+}
+
+pub fn load_state(path: &Path, max_age: Duration, now: DateTime<Utc>) -> Option<PersistedState> {
+    match read_state(path) {
+        Some(state) if state.saved_at < now - max_age => {
+            info!("ignoring stale state from {}", state.saved_at);
+            None
+        }
+        state => state,
+    }
+}
+
+pub fn load_last_known_ips(path: &Path) -> Vec<(MacAddress, IpAddr)> {
+    // powered-off macs may not be seen for longer than the state max age
+    read_state(path)
+        .map(|state| state.last_known_ips)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_state() {
+        let path =
+            std::env::temp_dir().join(format!("pv_informant_state_{}.json", std::process::id()));
+        let now = Utc::now();
+        let max_age = Duration::minutes(10);
+        assert!(
+            load_state(&path, max_age, now).is_none(),
+            "should ignore missing file"
+        );
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let state = PersistedState {
+            excess: ExcessStatus::Yes,
+            woken: vec![(mac, now - Duration::minutes(2))],
+            saved_at: now - Duration::minutes(5),
+            last_known_ips: vec![(mac, "192.168.1.23".parse().unwrap())],
+        };
+        save_state(&path, &state).unwrap();
+        assert_matches!(
+            load_state(&path, max_age, now),
+            Some(PersistedState { excess: ExcessStatus::Yes, woken, .. }) if woken == state.woken,
+            "should load recent state"
+        );
+        assert!(
+            load_state(&path, max_age, now + Duration::minutes(6)).is_none(),
+            "should expire stale state"
+        );
+        assert_eq!(
+            load_last_known_ips(&path),
+            state.last_known_ips,
+            "should load last known ips of stale state"
+        );
+        std::fs::write(
+            &path,
+            r#"{"excess": "Yes", "woken": [], "saved_at": "2022-01-10T12:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(
+            load_last_known_ips(&path).is_empty(),
+            "should load state files without last known ips"
+        );
+        std::fs::write(&path, "{").unwrap();
+        assert!(
+            load_state(&path, max_age, now).is_none(),
+            "should ignore invalid state"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use crate::influx_gateway::{
+    log_excess, log_liveness, log_workerstatus, log_workerstatus_batch, query_all_worker_status,
+    query_excess_report, query_history_interval, query_pv_excess, query_pv_excess_detail,
+    query_stale_macs, query_wake_counts, ExcessDetail, ExcessReport, ExcessStatus, QueryClient,
+    QueryLanguage, WakeCounts, WorkerLastStatus, WorkerStatus,
+};
+use crate::interval_handler::IntervalReq;
+use anyhow::Result;
+use chrono::Duration;
+use mac_address::MacAddress;
+use std::collections::HashMap;
+
+// persistence backend of worker status and pv data
+#[async_trait]
+pub trait StatusStore: std::fmt::Debug + Send + Sync {
+    async fn log_workerstatus(
+        &self,
+        mac: &MacAddress,
+        status: WorkerStatus,
+        wake: bool,
+    ) -> Result<()>;
+    async fn log_workerstatus_batch(
+        &self,
+        entries: &[(MacAddress, WorkerStatus, bool)],
+    ) -> Result<()> {
+        for (m, s, w) in entries {
+            self.log_workerstatus(m, s.clone(), *w).await?;
+        }
+        Ok(())
+    }
+    // the informant is alive in the given heartbeat cycle
+    async fn log_liveness(&self, measurement: &str, informant: &str, cycle: u64) -> Result<()>;
+    // the excess decision of a heartbeat (history of the excess annotation)
+    async fn log_excess(&self, excess: &ExcessStatus, informant: &str) -> Result<()>;
+    // macs (and their wake flag) which did not report recently
+    async fn stale_macs(&self, max_age: Option<Duration>) -> Result<Vec<(MacAddress, bool)>>;
+    // last report of each worker
+    async fn workers(&self) -> Result<Vec<WorkerLastStatus>>;
+    // reports per mac by wake intent within the window
+    async fn wake_counts(&self, window: Duration) -> Result<HashMap<String, WakeCounts>>;
+    async fn pv_excess(&self) -> Result<ExcessStatus>;
+    // excess with the inputs of the decision (if available)
+    async fn excess_report(&self) -> Result<ExcessReport> {
+        Ok(ExcessReport::from(self.pv_excess().await?))
+    }
+    async fn excess_detail(&self) -> Result<ExcessDetail> {
+        Ok(ExcessDetail::from(self.excess_report().await?))
+    }
+    async fn history_interval(&self, req: &IntervalReq) -> Result<String>;
+    // history is an influxdb json response (otherwise csv)
+    fn history_is_json(&self) -> bool;
+    // the backend is reachable
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<Q> StatusStore for Q
+where
+    Q: QueryClient + std::fmt::Debug + Send + Sync,
+{
+    async fn log_workerstatus(
+        &self,
+        mac: &MacAddress,
+        status: WorkerStatus,
+        wake: bool,
+    ) -> Result<()> {
+        Ok(log_workerstatus(mac, status, wake, self).await?)
+    }
+    async fn log_workerstatus_batch(
+        &self,
+        entries: &[(MacAddress, WorkerStatus, bool)],
+    ) -> Result<()> {
+        Ok(log_workerstatus_batch(entries, self).await?)
+    }
+    async fn log_liveness(&self, measurement: &str, informant: &str, cycle: u64) -> Result<()> {
+        Ok(log_liveness(measurement, informant, cycle, self).await?)
+    }
+    async fn log_excess(&self, excess: &ExcessStatus, informant: &str) -> Result<()> {
+        Ok(log_excess(excess, informant, self).await?)
+    }
+    async fn stale_macs(&self, max_age: Option<Duration>) -> Result<Vec<(MacAddress, bool)>> {
+        Ok(query_stale_macs(self, max_age).await?)
+    }
+    async fn workers(&self) -> Result<Vec<WorkerLastStatus>> {
+        Ok(query_all_worker_status(self).await?)
+    }
+    async fn wake_counts(&self, window: Duration) -> Result<HashMap<String, WakeCounts>> {
+        Ok(query_wake_counts(self, window).await?)
+    }
+    async fn pv_excess(&self) -> Result<ExcessStatus> {
+        Ok(query_pv_excess(self).await?)
+    }
+    async fn excess_report(&self) -> Result<ExcessReport> {
+        Ok(query_excess_report(self).await?)
+    }
+    async fn excess_detail(&self) -> Result<ExcessDetail> {
+        Ok(query_pv_excess_detail(self).await?)
+    }
+    async fn history_interval(&self, req: &IntervalReq) -> Result<String> {
+        Ok(query_history_interval(req, self).await?)
+    }
+    fn history_is_json(&self) -> bool {
+        self.query_language() == QueryLanguage::InfluxQL
+    }
+    async fn health_check(&self) -> Result<()> {
+        Ok(self.ping().await?)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::influx_gateway::WORKER_STALE_MINS;
+    use chrono::{DateTime, Utc};
+    use std::sync::Mutex;
+
+    #[derive(Debug)]
+    pub struct MemoryStore {
+        pub excess: ExcessStatus,
+        pub entries: Mutex<Vec<(MacAddress, i32, bool, DateTime<Utc>)>>,
+        // measurement, informant and cycle of liveness points
+        pub liveness: Mutex<Vec<(String, String, u64)>>,
+        // informant and excess of logged excess decisions
+        pub excess_log: Mutex<Vec<(String, u8)>>,
+    }
+
+    impl MemoryStore {
+        pub fn new(excess: ExcessStatus) -> Self {
+            MemoryStore {
+                excess,
+                entries: Mutex::new(vec![]),
+                liveness: Mutex::new(vec![]),
+                excess_log: Mutex::new(vec![]),
+            }
+        }
+        pub fn last_status(&self, mac: &MacAddress) -> Option<(i32, bool, DateTime<Utc>)> {
+            self.entries
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .find(|(m, ..)| m == mac)
+                .map(|(_, s, w, t)| (*s, *w, *t))
+        }
+        // time of the last report by the worker itself (not logged by the heartbeat)
+        pub fn last_seen(&self, mac: &MacAddress) -> Option<DateTime<Utc>> {
+            self.entries
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .find(|(m, s, ..)| m == mac && *s >= WorkerStatus::Inquisitive as i32)
+                .map(|(.., t)| *t)
+        }
+    }
+
+    #[async_trait]
+    impl StatusStore for MemoryStore {
+        async fn log_workerstatus(
+            &self,
+            mac: &MacAddress,
+            status: WorkerStatus,
+            wake: bool,
+        ) -> Result<()> {
+            self.entries
+                .lock()
+                .unwrap()
+                .push((*mac, status as i32, wake, Utc::now()));
+            Ok(())
+        }
+        async fn log_liveness(&self, measurement: &str, informant: &str, cycle: u64) -> Result<()> {
+            self.liveness.lock().unwrap().push((
+                measurement.to_string(),
+                informant.to_string(),
+                cycle,
+            ));
+            Ok(())
+        }
+        async fn log_excess(&self, excess: &ExcessStatus, informant: &str) -> Result<()> {
+            self.excess_log
+                .lock()
+                .unwrap()
+                .push((informant.to_string(), excess.clone() as u8));
+            Ok(())
+        }
+        async fn stale_macs(&self, max_age: Option<Duration>) -> Result<Vec<(MacAddress, bool)>> {
+            let now = Utc::now();
+            let mut macs: Vec<MacAddress> =
+                self.entries.lock().unwrap().iter().map(|e| e.0).collect();
+            macs.sort_by_key(|m| m.bytes());
+            macs.dedup();
+            Ok(macs
+                .into_iter()
+                .filter_map(|m| {
+                    let (status, wake, time) = self.last_status(&m)?;
+                    if let Some(age) = max_age {
+                        if self.last_seen(&m).map(|t| t < now - age).unwrap_or(true) {
+                            return None;
+                        }
+                    }
+                    let active = status >= WorkerStatus::Inquisitive as i32;
+                    let stale = time < now - Duration::minutes(WORKER_STALE_MINS);
+                    ((active && stale) || (!active && wake)).then_some((m, wake))
+                })
+                .collect())
+        }
+        async fn workers(&self) -> Result<Vec<WorkerLastStatus>> {
+            let mut macs: Vec<MacAddress> =
+                self.entries.lock().unwrap().iter().map(|e| e.0).collect();
+            macs.sort_by_key(|m| m.bytes());
+            macs.dedup();
+            Ok(macs
+                .into_iter()
+                .filter_map(|m| {
+                    let (status, wake, time) = self.last_status(&m)?;
+                    Some(WorkerLastStatus {
+                        mac: m.to_string(),
+                        status,
+                        wake,
+                        last_seen: time,
+                        wake_history: None,
+                    })
+                })
+                .collect())
+        }
+        async fn wake_counts(&self, window: Duration) -> Result<HashMap<String, WakeCounts>> {
+            let since = Utc::now() - window;
+            let mut counts: HashMap<String, WakeCounts> = HashMap::new();
+            for (m, _, wake, time) in self.entries.lock().unwrap().iter() {
+                if *time > since {
+                    let entry = counts.entry(m.to_string()).or_default();
+                    if *wake {
+                        entry.wake_true += 1;
+                    } else {
+                        entry.wake_false += 1;
+                    }
+                }
+            }
+            Ok(counts)
+        }
+        async fn pv_excess(&self) -> Result<ExcessStatus> {
+            Ok(self.excess.clone())
+        }
+        async fn history_interval(&self, _req: &IntervalReq) -> Result<String> {
+            Ok(r#"{"results": []}"#.into())
+        }
+        fn history_is_json(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        let sleeping: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let working: MacAddress = "11:22:33:44:55:77".parse().unwrap();
+        store
+            .log_workerstatus(&sleeping, WorkerStatus::Sleep, true)
+            .await
+            .unwrap();
+        store
+            .log_workerstatus(&working, WorkerStatus::Working, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.stale_macs(None).await.unwrap(),
+            vec![(sleeping, true)],
+            "should only report sleeping mac with wake as stale"
+        );
+        assert_eq!(
+            store.stale_macs(Some(Duration::days(30))).await.unwrap(),
+            vec![],
+            "should abandon a mac never seen with max age"
+        );
+        store.entries.lock().unwrap().insert(
+            0,
+            (
+                sleeping,
+                WorkerStatus::Working as i32,
+                true,
+                Utc::now() - Duration::days(1),
+            ),
+        );
+        assert_eq!(
+            store.stale_macs(Some(Duration::days(30))).await.unwrap(),
+            vec![(sleeping, true)],
+            "should keep a mac seen within max age"
+        );
+        assert_matches!(store.pv_excess().await, Ok(ExcessStatus::Yes));
+    }
+}
@@ -0,0 +1,17 @@
+use crate::context::Context;
+use crate::errors::ApiError;
+use crate::server::RequestHandler;
+use crate::wake_heartbeat::{manual_heartbeat, HeartbeatRes};
+use async_trait::async_trait;
+
+pub struct WakeRequestHandler {}
+
+#[async_trait]
+impl RequestHandler<(), HeartbeatRes> for WakeRequestHandler {
+    async fn handle(&self, _req: (), context: Context) -> Result<HeartbeatRes, ApiError> {
+        // run a heartbeat on demand (e.g. triggered by an inverter event)
+        let summary =
+            manual_heartbeat(&context, context.status_store.as_ref(), &context.net).await?;
+        Ok(HeartbeatRes::new(&summary, context.excess_json_lowercase))
+    }
+}
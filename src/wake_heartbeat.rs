@@ -1,41 +1,295 @@
 use crate::context::Context;
-use crate::influx_gateway::{log_workerstatus, query_pv_excess, WorkerStatus};
-use crate::influx_gateway::{query_stale_macs, ExcessStatus};
-use crate::neighbor::{macs_to_addrs, sleeping_macs, wake_macs};
+use crate::errors::{ApiError, GenericError};
+use crate::influx_gateway::{ExcessStatus, WorkerStatus};
+use crate::metrics;
+use crate::neighbor::{macs_to_addrs, sleeping_macs, MacIpMapping, NetworkGateway};
+use crate::shutdown::ShutdownSignal;
+use crate::status_store::StatusStore;
+use crate::wake_transport::{dispatch_wake, transport_for};
+use crate::wake_webhook::{post_wake_event, WakeEvent, WEBHOOK_TIMEOUT};
+use chrono::{DateTime, Duration, NaiveTime, Utc};
 use log::{error, info};
+use mac_address::MacAddress;
+use serde::Serialize;
 use std::collections::HashSet;
+use std::str::FromStr;
 
-async fn waker_heartbeat(context: Context) {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeartbeatMode {
+    // heartbeat every wake interval
+    Timer,
+    // heartbeat only on POST /wake
+    Manual,
+}
+
+impl FromStr for HeartbeatMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "timer" => Ok(HeartbeatMode::Timer),
+            "manual" => Ok(HeartbeatMode::Manual),
+            _ => Err(format!("Unknown heartbeat mode '{}'!", s)),
+        }
+    }
+}
+
+// error log coalescing identical messages within LOG_THROTTLE_SECONDS
+macro_rules! throttled_error {
+    ($context:expr, $($arg:tt)+) => {
+        if let Some(msg) = $context.throttle_log(format!($($arg)+)) {
+            error!("{}", msg);
+        }
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct HeartbeatSummary {
+    pub excess: ExcessStatus,
+    // stale macs with wake = true
+    pub candidates: usize,
+    // candidates which responded to ping
+    pub awake: usize,
+    pub woken: usize,
+    // macs woken by schedule (regardless of excess)
+    pub scheduled: usize,
+}
+
+impl HeartbeatSummary {
+    // excess is available but there is no sleeping worker to wake
+    pub fn excess_unused(&self) -> bool {
+        matches!(self.excess, ExcessStatus::Yes) && self.awake == self.candidates
+    }
+}
+
+// heartbeat summary of responses (POST /wake, /debug/snapshot)
+#[derive(Debug, Serialize)]
+pub struct HeartbeatRes {
+    pub excess: &'static str,
+    pub candidates: usize,
+    pub awake: usize,
+    pub woken: usize,
+    pub scheduled: usize,
+}
+
+impl HeartbeatRes {
+    pub fn new(summary: &HeartbeatSummary, lowercase: bool) -> Self {
+        HeartbeatRes {
+            excess: summary.excess.name(lowercase),
+            candidates: summary.candidates,
+            awake: summary.awake,
+            woken: summary.woken,
+            scheduled: summary.scheduled,
+        }
+    }
+}
+
+fn schedule_due(schedule: &[NaiveTime], now: DateTime<Utc>, window: Duration) -> bool {
+    // a scheduled time (of today or yesterday) passed since the previous heartbeat
+    let since = now - window;
+    schedule.iter().any(|t| {
+        [now.date_naive(), (now - Duration::days(1)).date_naive()]
+            .iter()
+            .map(|d| d.and_time(*t).and_utc())
+            .any(|at| since < at && at <= now)
+    })
+}
+
+async fn scheduled_wake<N>(context: &Context, net: &N) -> HashSet<MacAddress>
+where
+    N: NetworkGateway + Sync,
+{
+    let window = Duration::from_std(context.wake_interval).unwrap_or(Duration::zero());
+    if context.scheduled_wake_macs.is_empty()
+        || !schedule_due(&context.scheduled_wakes, Utc::now(), window)
+    {
+        return HashSet::new();
+    }
+    info!(
+        "scheduled wake of {} macs",
+        context.scheduled_wake_macs.len()
+    );
+    // wake without ping (ip-addresses only for the broadcast address)
+    let mac_map = macs_to_addrs(&context.scheduled_wake_macs, net, &context.arp_selection)
+        .await
+        .map(|mac_map| {
+            context.remember_ips(&mac_map);
+            context.with_last_known_ips(mac_map)
+        })
+        .unwrap_or_else(|e| {
+            throttled_error!(
+                context,
+                "Exception while IP-addr lookup of scheduled macs! {}",
+                e
+            );
+            context
+                .scheduled_wake_macs
+                .iter()
+                .map(|m| (m.clone(), None))
+                .collect()
+        });
+    dispatch_wake(
+        &context.scheduled_wake_macs,
+        &mac_map,
+        &context.wake_transports,
+        transport_for(net),
+    )
+    .await
+}
+
+async fn heartbeat_excess<S>(
+    context: &Context,
+    store: &S,
+    now: DateTime<Utc>,
+) -> anyhow::Result<ExcessStatus>
+where
+    S: StatusStore + ?Sized,
+{
+    if !context.excess_enabled {
+        // permit waking of stale sleeping macs
+        debug!("pv excess query disabled");
+        return Ok(ExcessStatus::Yes);
+    }
+    if let Some(location) = context.location.filter(|l| !l.is_daylight(now)) {
+        debug!(
+            "sun is down at {},{}, skipping pv excess query",
+            location.latitude, location.longitude
+        );
+        return Ok(ExcessStatus::No);
+    }
+    let report = store.excess_report().await?;
+    if context.log_excess_inputs {
+        info!("pv excess: {}", report);
+    } else {
+        info!("pv excess: {}", report.excess.clone() as u8);
+    }
+    Ok(report.excess)
+}
+
+#[cfg_attr(
+    feature = "otel",
+    tracing::instrument(
+        name = "waker_heartbeat",
+        skip_all,
+        fields(informant = %context.informant_id)
+    )
+)]
+async fn waker_heartbeat<S, N>(context: &Context, store: &S, net: &N) -> HeartbeatSummary
+where
+    S: StatusStore + ?Sized,
+    N: NetworkGateway + Sync,
+{
+    let pruned = context.prune_woken();
+    if pruned > 0 {
+        debug!("pruned {} woken macs", pruned);
+    }
+    if context.influx_circuit_open() {
+        // only re-check connectivity while the database is down
+        match store.health_check().await {
+            Ok(()) => {
+                info!("influxdb is healthy again, resuming heartbeat");
+                context.record_influx_health(true);
+            }
+            Err(e) => {
+                debug!("influxdb still unavailable, skipping heartbeat! {}", e);
+                return HeartbeatSummary {
+                    excess: ExcessStatus::No,
+                    candidates: 0,
+                    awake: 0,
+                    woken: 0,
+                    scheduled: 0,
+                };
+            }
+        }
+    }
     // gather stale macs (not inquisitive for 10m) or already stale
-    let stale_macs = query_stale_macs(&context.influx_client)
+    let mut influx_ok = true;
+    let stale_macs = store
+        .stale_macs(context.candidate_max_age)
         .await
         .unwrap_or_else(|e| {
-            error!("Stale macs query failed! {}", e);
+            throttled_error!(context, "Stale macs query failed! {}", e);
+            influx_ok = false;
             Vec::new()
         });
+    // decided by the excess query (not disabled or failed)
+    let mut excess_decided = context.excess_enabled;
+    let excess = match heartbeat_excess(context, store, Utc::now()).await {
+        Ok(excess) => context.smooth_excess(excess),
+        Err(e) => {
+            throttled_error!(context, "pv excess query failed! {}", e);
+            influx_ok = false;
+            excess_decided = false;
+            ExcessStatus::No
+        }
+    };
+    context.record_influx_health(influx_ok);
+    if context.influx_circuit_open() {
+        throttled_error!(
+            context,
+            "influxdb failed in {} consecutive heartbeats, pausing until healthy",
+            context.influx_circuit_threshold
+        );
+    }
+
+    // wake intent is meaningless without any excess (e.g. at night)
+    let suppress_wake = context.wake_intent_requires_excess && matches!(excess, ExcessStatus::No);
     let mut wake_candidates = HashSet::new();
     let mut logs = vec![];
     for (m, wake) in stale_macs {
-        if wake {
+        if wake && suppress_wake {
+            debug!("[{}] stale with wake but no excess", m);
+        } else if wake {
             // ping macs with wake = true
             wake_candidates.insert(m);
         } else {
-            // do not ping macs with wake = false 
+            // do not ping macs with wake = false
             logs.push((m, WorkerStatus::Sleep, false));
             debug!("[{}] stale but nowake", m);
         }
     }
-    let mac_mapping = macs_to_addrs(&wake_candidates).await;
-    let sleeping_macs = match &mac_mapping {
-        Ok(mac_map) => sleeping_macs(mac_map).await,
-        Err(e) => {
-            error!("Exception while IP-addr lookup of wake candidates! {}", e);
-            HashSet::new()
+    // bound the candidates per heartbeat (remainder in the next heartbeats)
+    let wake_candidates = context.candidate_batch(wake_candidates);
+    let (mac_mapping, sleeping_macs) = if context.trust_status_for_wake {
+        // trust the reported status: wake all candidates by broadcast without ping
+        let mac_map: MacIpMapping = wake_candidates.iter().map(|m| (m.clone(), None)).collect();
+        (Ok(mac_map), wake_candidates.clone())
+    } else {
+        let mac_mapping = macs_to_addrs(&wake_candidates, net, &context.arp_selection).await;
+        if let Ok(mac_map) = &mac_mapping {
+            context.remember_ips(mac_map);
         }
+        let sleeping_macs = match &mac_mapping {
+            Ok(mac_map) => {
+                sleeping_macs(mac_map, net, context.ping_limits, context.awake_tcp_port).await
+            }
+            Err(e) => {
+                throttled_error!(
+                    context,
+                    "Exception while IP-addr lookup of wake candidates! {}",
+                    e
+                );
+                HashSet::new()
+            }
+        };
+        (mac_mapping, sleeping_macs)
     };
+    // recently reporting macs are awake
+    let sleeping_macs: HashSet<MacAddress> = sleeping_macs
+        .into_iter()
+        .filter(|m| {
+            let active = context.recently_active(m);
+            if active {
+                debug!("[{}] recently active", m);
+            }
+            !active
+        })
+        .collect();
 
-    // log new workerstatus
-    for (m, s, w) in logs
+    let candidates = wake_candidates.len();
+    let awake = candidates - sleeping_macs.len();
+
+    // log new workerstatus (single write)
+    let entries: Vec<(MacAddress, WorkerStatus, bool)> = logs
         .into_iter()
         .chain(wake_candidates.into_iter().map(|mac| {
             (
@@ -48,42 +302,841 @@ async fn waker_heartbeat(context: Context) {
                 true,
             )
         }))
-    {
-        if let Err(e) = log_workerstatus(&m, s, w, &context.influx_client).await {
-            error!("Failed logging workerstatus! {}", e)
+        .collect();
+    match store.log_workerstatus_batch(&entries).await {
+        Err(e) => throttled_error!(context, "Failed logging workerstatus! {}", e),
+        // only statuses stored in influxdb
+        Ok(()) if context.metrics_max_macs > 0 => {
+            let mut gauges = metrics::WORKER_GAUGES.lock().unwrap();
+            for (mac, status, _) in &entries {
+                gauges.observe(mac, status.clone(), None, context.metrics_max_macs);
+            }
         }
+        Ok(()) => {}
     }
 
-    let excess = match query_pv_excess(&context.influx_client).await {
-        Ok(excess) => {
-            info!("pv excess: {}", excess.clone() as u8);
-            excess
-        }
-        Err(e) => {
-            error!("pv excess query failed! {}", e);
-            ExcessStatus::No
+    // wake asleep macs if excess = Yes
+    let woken_macs = match (excess.clone(), mac_mapping) {
+        (ExcessStatus::Yes, Ok(mac_map)) => {
+            let eligible_macs: HashSet<MacAddress> = sleeping_macs
+                .iter()
+                .filter(|m| {
+                    if context.in_wake_cooldown(m) {
+                        debug!("[{}] woken recently, still booting", m);
+                        return false;
+                    }
+                    let eligible = context.wake_eligible(m);
+                    if !eligible {
+                        info!("[{}] backing off from waking", m);
+                    }
+                    eligible
+                })
+                .cloned()
+                .collect();
+            // wake a slice of the eligible macs (if spread across heartbeats)
+            let eligible_macs = context.wake_slice(&eligible_macs);
+            context.record_wake_attempts(&eligible_macs);
+            dispatch_wake(
+                &eligible_macs,
+                &context.with_last_known_ips(mac_map),
+                &context.wake_transports,
+                transport_for(net),
+            )
+            .await
         }
+        _ => HashSet::new(),
     };
-
-    // wake asleep macs if excess = Yes
-    let woken_macs = match (excess, mac_mapping) {
-        (ExcessStatus::Yes, Ok(mac_map)) => match wake_macs(&sleeping_macs, &mac_map).await {
-            Ok(_) => sleeping_macs,
-            Err(e) => {
-                error!("Waking failed! {}", e);
-                HashSet::new()
+    let scheduled_macs = scheduled_wake(context, net).await;
+    if let Some(url) = &context.wake_webhook_url {
+        let macs: HashSet<MacAddress> = woken_macs.union(&scheduled_macs).cloned().collect();
+        if !macs.is_empty() {
+            let event = WakeEvent::new(
+                &macs,
+                excess.name(context.excess_json_lowercase),
+                Utc::now(),
+            );
+            if let Err(e) = post_wake_event(url, &event, WEBHOOK_TIMEOUT).await {
+                throttled_error!(context, "Wake webhook failed! {}", e);
             }
-        },
-        _ => HashSet::new(),
+        }
+    }
+    let summary = HeartbeatSummary {
+        excess,
+        candidates,
+        awake,
+        woken: woken_macs.len(),
+        scheduled: scheduled_macs.len(),
     };
-    context.just_woke(woken_macs)
+    if summary.excess_unused() {
+        metrics::EXCESS_UNUSED_HEARTBEATS.inc();
+        info!(
+            "excess available, all workers already active ({} awake)",
+            summary.awake
+        );
+    }
+    context.just_woke(woken_macs);
+    context.just_woke(scheduled_macs);
+    context.set_last_heartbeat(summary.clone());
+    context.persist_state();
+    if excess_decided {
+        // excess history of '"annotate_excess": true' in /interval requests
+        if let Err(e) = store
+            .log_excess(&summary.excess, &context.informant_id)
+            .await
+        {
+            throttled_error!(context, "Excess write failed! {}", e);
+        }
+    }
+    if context.write_liveness {
+        let cycle = context.next_heartbeat_cycle();
+        if let Err(e) = store
+            .log_liveness(&context.liveness_measurement, &context.informant_id, cycle)
+            .await
+        {
+            throttled_error!(context, "Liveness write failed! {}", e);
+        }
+    }
+    summary
 }
 
-pub async fn wake_heartbeat_loop(context: Context) -> Result<(), hyper::Error> {
+pub async fn manual_heartbeat<S, N>(
+    context: &Context,
+    store: &S,
+    net: &N,
+) -> Result<HeartbeatSummary, ApiError>
+where
+    S: StatusStore + ?Sized,
+    N: NetworkGateway + Sync,
+{
+    if !context.wake_interval_enabled {
+        return Err(api_err!(
+            hyper::StatusCode::FORBIDDEN,
+            "Waking is disabled!"
+        ));
+    }
+    info!("manual heartbeat");
+    Ok(waker_heartbeat(context, store, net).await)
+}
+
+pub async fn wake_heartbeat_loop(
+    context: Context,
+    shutdown: ShutdownSignal,
+) -> Result<(), hyper::Error> {
     let mut interval = tokio::time::interval(context.wake_interval);
-    while context.wake_interval_enabled {
-        interval.tick().await;
-        waker_heartbeat(context.clone()).await;
+    while context.wake_interval_enabled && context.heartbeat_mode == HeartbeatMode::Timer {
+        // finish a running heartbeat before shutting down
+        tokio::select! {
+            at = interval.tick() => context.record_tick(at),
+            _ = shutdown.clone() => {
+                info!("heartbeat stopped");
+                break;
+            }
+        }
+        waker_heartbeat(&context, context.status_store.as_ref(), &context.net).await;
     }
     Ok(())
 }
+
+pub fn spawn_isolated_heartbeat(
+    context: Context,
+    shutdown: ShutdownSignal,
+) -> impl std::future::Future<Output = Result<(), GenericError>> {
+    // own thread and single-threaded runtime (not starved by request handling)
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let spawned = std::thread::Builder::new()
+        .name("heartbeat".into())
+        .spawn(move || {
+            let res = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt
+                    .block_on(wake_heartbeat_loop(context, shutdown))
+                    .map_err(GenericError::from),
+                Err(e) => Err(GenericError::from(e)),
+            };
+            let _ = tx.send(res);
+        });
+    async move {
+        spawned?;
+        rx.await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::influx_gateway::test::InfluxClientMock;
+    use crate::neighbor::test::NetworkGatewayMock;
+    use crate::shutdown::shutdown_on;
+    use crate::solar::Location;
+    use crate::status_store::test::MemoryStore;
+    use crate::wake_webhook::test::webhook_server;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+
+    fn stale_macs_resp(entries: &[(&str, i32, bool)]) -> String {
+        let serie = r#"{
+            "name":"workerstatus",
+            "tags": { "mac": "MAC" },
+            "columns": ["time", "status", "wake"],
+            "values": [ VALUES ]
+        }"#;
+        r#"[{"series": [SERIES]}]"#.replace(
+            "SERIES",
+            &entries
+                .iter()
+                .map(|(m, s, w)| {
+                    serie.replace("MAC", m).replace(
+                        "VALUES",
+                        &format!("[\"{}\", {}, {}]", Utc::now().to_rfc3339(), s, w),
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(","),
+        )
+    }
+
+    fn mean_resp(value: f32) -> String {
+        format!(
+            r#"[{{"series": [{{"name": "pvstatus", "columns": ["mean", "count"], "values": [[{:.2}, 10]]}}]}}]"#,
+            value
+        )
+    }
+
+    fn heartbeat_client(
+        stale_macs: &[(&str, i32, bool)],
+        mean_current: f32,
+        mean_voltage: f32,
+    ) -> InfluxClientMock {
+        InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT last(\"status\") AS status,wake,time FROM workerstatus GROUP BY mac"
+                        .into(),
+                    stale_macs_resp(stale_macs),
+                ),
+                (
+                    "SELECT mean(\"pv_current\") AS mean, count(\"pv_current\") AS count FROM pvstatus WHERE time > now() - 30m"
+                        .into(),
+                    mean_resp(mean_current),
+                ),
+                (
+                    "SELECT mean(\"battery_voltage\") AS mean, count(\"battery_voltage\") AS count FROM pvstatus WHERE time > now() - 15m"
+                        .into(),
+                    mean_resp(mean_voltage),
+                ),
+                // write queries are matched by prefix
+                ("workerstatus".into(), "".into()),
+                ("excessstatus".into(), "".into()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_influx_circuit_breaker() {
+        let mac = "11:22:33:44:55:66";
+        let mut client = heartbeat_client(&[(mac, 0, true)], 30.0, 13.5);
+        let stale_query =
+            "SELECT last(\"status\") AS status,wake,time FROM workerstatus GROUP BY mac";
+        let stale_resp = client.answer_map[stale_query].clone();
+        client
+            .answer_map
+            .insert(stale_query.into(), "ERROR: connection refused".into());
+        client.ping_err = true;
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+        context.influx_circuit_threshold = 2;
+
+        for _ in 0..2 {
+            waker_heartbeat(&context, &client, &net).await;
+        }
+        assert!(
+            context.influx_circuit_open(),
+            "should open after consecutive failures"
+        );
+
+        // a recovered query is not run while the ping fails
+        client.answer_map.insert(stale_query.into(), stale_resp);
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(
+            summary.candidates, 0,
+            "should skip the heartbeat while open"
+        );
+        assert!(context.influx_circuit_open());
+
+        client.ping_err = false;
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(summary.candidates, 1, "should resume once healthy");
+        assert!(!context.influx_circuit_open(), "should close once healthy");
+    }
+
+    #[tokio::test]
+    async fn test_trust_status_for_wake() {
+        let macs = ["11:22:33:44:55:66", "11:22:33:44:55:77"];
+        let client = heartbeat_client(&[(macs[0], 0, true), (macs[1], 0, true)], 30.0, 13.5);
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+
+        waker_heartbeat(&context, &client, &net).await;
+
+        assert!(
+            net.pinged.lock().unwrap().is_empty(),
+            "should not ping wake candidates"
+        );
+        let woken: HashMap<MacAddress, _> = net.woken.lock().unwrap().iter().cloned().collect();
+        for m in macs {
+            let mac: MacAddress = m.parse().unwrap();
+            assert_matches!(
+                woken.get(&mac),
+                Some(None),
+                "should wake every candidate by broadcast"
+            );
+            assert!(context.woken_in_previous_heartbeat(&mac));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recently_active_not_woken() {
+        let macs = ["11:22:33:44:55:66", "11:22:33:44:55:77"];
+        let client = heartbeat_client(&[(macs[0], 0, true), (macs[1], 0, true)], 30.0, 13.5);
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+        let reported: MacAddress = macs[0].parse().unwrap();
+        context.mark_active(&reported);
+
+        let summary = waker_heartbeat(&context, &client, &net).await;
+
+        let woken: Vec<MacAddress> = net.woken.lock().unwrap().iter().map(|(m, _)| *m).collect();
+        assert_eq!(
+            woken,
+            vec![macs[1].parse().unwrap()],
+            "should not wake mac which just reported"
+        );
+        assert_eq!(summary.awake, 1);
+    }
+
+    #[tokio::test]
+    async fn test_wake_webhook() {
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        store
+            .log_workerstatus(&mac, WorkerStatus::Sleep, true)
+            .await
+            .unwrap();
+        let net = NetworkGatewayMock::default();
+        let (url, mut rx) = webhook_server();
+        let mut context = Context::load().unwrap();
+        context.wake_webhook_url = Some(url);
+
+        waker_heartbeat(&context, &store, &net).await;
+
+        let payload: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+        assert_eq!(payload["macs"], serde_json::json!(["11:22:33:44:55:66"]));
+        assert_eq!(payload["excess"], "Yes");
+        assert!(payload["timestamp"].is_string());
+
+        context.excess_json_lowercase = true;
+        context.reset_backoff(None);
+        context.post_wake_cooldown = Duration::zero();
+        waker_heartbeat(&context, &store, &net).await;
+        let payload: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+        assert_eq!(
+            payload["excess"], "yes",
+            "should follow EXCESS_JSON_LOWERCASE"
+        );
+
+        context.wake_webhook_url = Some("http://127.0.0.1:1/wake".into());
+        context.reset_backoff(None);
+        assert_eq!(
+            waker_heartbeat(&context, &store, &net).await.woken,
+            1,
+            "should not fail the heartbeat on webhook errors"
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn test_export_heartbeat_span() {
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        let net = NetworkGatewayMock::default();
+        let context = Context::load().unwrap();
+        let spans =
+            crate::otel::test::exported_spans(waker_heartbeat(&context, &store, &net)).await;
+        assert!(
+            spans.iter().any(|s| s.name == "waker_heartbeat"),
+            "should export a span of the heartbeat"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_heartbeat() {
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        store
+            .log_workerstatus(&mac, WorkerStatus::Sleep, true)
+            .await
+            .unwrap();
+        let net = NetworkGatewayMock::default();
+        let context = Context::load().unwrap();
+
+        let summary = waker_heartbeat(&context, &store, &net).await;
+
+        assert_eq!(summary.woken, 1, "should wake with any status store");
+        assert_eq!(*net.woken.lock().unwrap(), vec![(mac, None)]);
+        assert_matches!(
+            store.last_status(&mac),
+            Some((status, true, _)) if status == WorkerStatus::Sleep as i32,
+            "should log status to the status store"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abandon_ancient_candidate() {
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        store.entries.lock().unwrap().push((
+            mac,
+            WorkerStatus::Working as i32,
+            true,
+            Utc::now() - Duration::days(100),
+        ));
+        // logged by an earlier heartbeat
+        store
+            .log_workerstatus(&mac, WorkerStatus::Sleep, true)
+            .await
+            .unwrap();
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+        context.candidate_max_age = Some(Duration::days(30));
+
+        let summary = waker_heartbeat(&context, &store, &net).await;
+
+        assert_eq!(
+            summary.candidates, 0,
+            "should abandon the ancient candidate"
+        );
+        assert!(net.woken.lock().unwrap().is_empty());
+        assert_eq!(
+            store.entries.lock().unwrap().len(),
+            2,
+            "should not log it again"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wake_last_known_ip() {
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let ip: IpAddr = "192.168.5.23".parse().unwrap();
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        store
+            .log_workerstatus(&mac, WorkerStatus::Sleep, true)
+            .await
+            .unwrap();
+        // powered off: no arp entry
+        let net = NetworkGatewayMock::default();
+        let context = Context::load().unwrap();
+        context.remember_ip(mac, ip);
+        waker_heartbeat(&context, &store, &net).await;
+        assert_eq!(
+            *net.woken.lock().unwrap(),
+            vec![(mac, None)],
+            "should not remember ips unless configured"
+        );
+
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.wake_last_known_ip = true;
+        context.remember_ip(mac, ip);
+        waker_heartbeat(&context, &store, &net).await;
+        assert_eq!(
+            *net.woken.lock().unwrap(),
+            vec![(mac, Some(ip))],
+            "should wake via the last known ip (directed broadcast)"
+        );
+        assert!(
+            net.pinged.lock().unwrap().is_empty(),
+            "should not ping the last known ip"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_isolated_heartbeat() {
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::No));
+        context.trust_status_for_wake = true;
+        let heartbeat =
+            spawn_isolated_heartbeat(context.clone(), shutdown_on(futures::future::pending()));
+        // block the (single-threaded) test runtime like a saturated server pool
+        let start = std::time::Instant::now();
+        while context.last_heartbeat().is_none() && start.elapsed().as_secs() < 5 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(
+            context.last_heartbeat().is_some(),
+            "should run the heartbeat while the server runtime is blocked"
+        );
+        drop(heartbeat);
+    }
+
+    #[tokio::test]
+    async fn test_write_liveness() {
+        let store = MemoryStore::new(ExcessStatus::No);
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        waker_heartbeat(&context, &store, &net).await;
+        assert!(
+            store.liveness.lock().unwrap().is_empty(),
+            "should not write liveness by default"
+        );
+        context.write_liveness = true;
+        context.informant_id = "informant-1".into();
+        for _ in 0..3 {
+            waker_heartbeat(&context, &store, &net).await;
+        }
+        assert_eq!(
+            *store.liveness.lock().unwrap(),
+            [1, 2, 3]
+                .map(|c| (
+                    "informantliveness".to_string(),
+                    "informant-1".to_string(),
+                    c
+                ))
+                .to_vec(),
+            "should write liveness every cycle with an incrementing counter"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_excess() {
+        let store = MemoryStore::new(ExcessStatus::Maybe);
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.informant_id = "informant-1".into();
+        for _ in 0..2 {
+            waker_heartbeat(&context, &store, &net).await;
+        }
+        assert_eq!(
+            *store.excess_log.lock().unwrap(),
+            vec![
+                ("informant-1".to_string(), 1),
+                ("informant-1".to_string(), 1)
+            ],
+            "should write the excess decision every cycle"
+        );
+
+        context.excess_enabled = false;
+        waker_heartbeat(&context, &store, &net).await;
+        assert_eq!(
+            store.excess_log.lock().unwrap().len(),
+            2,
+            "should not write an excess without a decision"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_shutdown() {
+        let mut context = Context::load().unwrap();
+        context.status_store = std::sync::Arc::new(MemoryStore::new(ExcessStatus::No));
+        context.trust_status_for_wake = true;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let heartbeat = tokio::spawn(wake_heartbeat_loop(
+            context.clone(),
+            shutdown_on(async move {
+                stopped.await.ok();
+            }),
+        ));
+        while context.last_heartbeat().is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        stop.send(()).unwrap();
+        assert_matches!(
+            tokio::time::timeout(std::time::Duration::from_secs(5), heartbeat).await,
+            Ok(Ok(Ok(()))),
+            "should exit the heartbeat loop on shutdown"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_unused() {
+        let macs = ["11:22:33:44:55:66", "11:22:33:44:55:77"];
+        let client = heartbeat_client(&[(macs[0], 0, true), (macs[1], 0, true)], 30.0, 13.5);
+        let net = NetworkGatewayMock {
+            neigh_resp: "192.168.178.2 dev eth0 lladdr 11:22:33:44:55:66 REACHABLE\n\
+                192.168.178.3 dev eth0 lladdr 11:22:33:44:55:77 REACHABLE"
+                .into(),
+            ping_resp: HashMap::from([
+                ("192.168.178.2".parse().unwrap(), true),
+                ("192.168.178.3".parse().unwrap(), true),
+            ]),
+            ..Default::default()
+        };
+        let context = Context::load().unwrap();
+        let before = metrics::EXCESS_UNUSED_HEARTBEATS.get();
+
+        let summary = waker_heartbeat(&context, &client, &net).await;
+
+        assert_matches!(summary.excess, ExcessStatus::Yes);
+        assert_eq!(summary.candidates, 2);
+        assert_eq!(summary.awake, 2, "should count awake candidates");
+        assert_eq!(summary.woken, 0);
+        assert!(
+            summary.excess_unused(),
+            "should report unused excess if all workers are active"
+        );
+        assert!(
+            metrics::EXCESS_UNUSED_HEARTBEATS.get() > before,
+            "should count the heartbeat with unused excess"
+        );
+        assert!(net.woken.lock().unwrap().is_empty());
+        assert_matches!(context.last_heartbeat(), Some(s) if s.awake == 2);
+    }
+
+    #[tokio::test]
+    async fn test_excess_disabled() {
+        let mac = "11:22:33:44:55:66";
+        // no pvstatus queries answered
+        let client = InfluxClientMock {
+            answer_map: HashMap::from([
+                (
+                    "SELECT last(\"status\") AS status,wake,time FROM workerstatus GROUP BY mac"
+                        .into(),
+                    stale_macs_resp(&[(mac, 0, true)]),
+                ),
+                ("workerstatus".into(), "".into()),
+            ]),
+            ..Default::default()
+        };
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.excess_enabled = false;
+
+        let summary = waker_heartbeat(&context, &client, &net).await;
+
+        assert_eq!(
+            summary.woken, 1,
+            "should wake stale macs without excess query"
+        );
+        assert_eq!(
+            *net.woken.lock().unwrap(),
+            vec![(mac.parse().unwrap(), None)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excess_at_night() {
+        let client = heartbeat_client(&[], 30.0, 13.5);
+        let mut context = Context::load().unwrap();
+        context.location = Some(Location {
+            latitude: 52.52,
+            longitude: 13.405,
+        });
+        let night: DateTime<Utc> = "2024-06-21T23:00:00Z".parse().unwrap();
+        let day: DateTime<Utc> = "2024-06-21T12:00:00Z".parse().unwrap();
+
+        assert_matches!(
+            heartbeat_excess(&context, &client, night).await,
+            Ok(ExcessStatus::No),
+            "should not query the excess at night"
+        );
+        assert_matches!(
+            heartbeat_excess(&context, &InfluxClientMock::default(), night).await,
+            Ok(ExcessStatus::No),
+            "should not fail at night without a database"
+        );
+        assert_matches!(
+            heartbeat_excess(&context, &client, day).await,
+            Ok(ExcessStatus::Yes),
+            "should query the excess during the day"
+        );
+        context.location = None;
+        assert_matches!(
+            heartbeat_excess(&context, &client, night).await,
+            Ok(ExcessStatus::Yes),
+            "should query the excess without a location"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wake_spread() {
+        let macs = [
+            "11:22:33:44:55:66",
+            "11:22:33:44:55:77",
+            "11:22:33:44:55:88",
+        ];
+        let client = heartbeat_client(
+            &[(macs[0], 0, true), (macs[1], 0, true), (macs[2], 0, true)],
+            30.0,
+            13.5,
+        );
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+        context.wake_spread_cycles = 3;
+
+        for cycle in 1..=3 {
+            let summary = waker_heartbeat(&context, &client, &net).await;
+            assert_eq!(summary.woken, 1, "should wake one mac per heartbeat");
+            assert_eq!(net.woken.lock().unwrap().len(), cycle);
+        }
+        let woken: HashSet<MacAddress> =
+            net.woken.lock().unwrap().iter().map(|(m, _)| *m).collect();
+        assert_eq!(
+            woken.len(),
+            3,
+            "should wake every candidate within 3 heartbeats"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_wake_cooldown() {
+        let mac = "11:22:33:44:55:66";
+        let client = heartbeat_client(&[(mac, 0, true)], 30.0, 13.5);
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+        context.post_wake_cooldown = Duration::seconds(600);
+        context.just_woke(HashSet::from([mac.parse().unwrap()]));
+
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(summary.woken, 0, "should skip mac during cooldown");
+
+        context.post_wake_cooldown = Duration::zero();
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(summary.woken, 1, "should wake mac after cooldown");
+    }
+
+    #[tokio::test]
+    async fn test_manual_heartbeat() {
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        store
+            .log_workerstatus(&mac, WorkerStatus::Sleep, true)
+            .await
+            .unwrap();
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.heartbeat_mode = HeartbeatMode::Manual;
+
+        assert_matches!(
+            tokio::time::timeout(
+                std::time::Duration::from_secs(1),
+                wake_heartbeat_loop(context.clone(), shutdown_on(futures::future::pending()))
+            )
+            .await,
+            Ok(Ok(())),
+            "should not run the timer loop"
+        );
+        assert!(context.last_heartbeat().is_none(), "timer should not fire");
+
+        assert_matches!(
+            manual_heartbeat(&context, &store, &net).await,
+            Ok(summary) if summary.woken == 1,
+            "should run heartbeat on manual trigger"
+        );
+        assert!(context.last_heartbeat().is_some());
+
+        context.wake_interval_enabled = false;
+        assert_matches!(
+            manual_heartbeat(&context, &store, &net).await,
+            Err(e) if e.code == hyper::StatusCode::FORBIDDEN,
+            "should not trigger heartbeat if waking is disabled"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_candidates() {
+        let macs = [
+            "11:22:33:44:55:66",
+            "11:22:33:44:55:77",
+            "11:22:33:44:55:88",
+        ];
+        let client = heartbeat_client(
+            &[(macs[0], 0, true), (macs[1], 0, true), (macs[2], 0, true)],
+            30.0,
+            13.5,
+        );
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+        context.max_candidates = Some(2);
+
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(summary.candidates, 2, "should only process max candidates");
+        assert_eq!(summary.woken, 2);
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(summary.candidates, 2);
+        let woken: HashSet<MacAddress> =
+            net.woken.lock().unwrap().iter().map(|(m, _)| *m).collect();
+        assert!(
+            woken.contains(&macs[2].parse().unwrap()),
+            "should process the remainder in the next heartbeat"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wake_intent_requires_excess() {
+        let macs = ["11:22:33:44:55:66", "11:22:33:44:55:77"];
+        // night: no excess
+        let client = heartbeat_client(&[(macs[0], 0, true), (macs[1], 0, true)], 0.0, 12.0);
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.trust_status_for_wake = true;
+
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(summary.candidates, 2);
+
+        context.wake_intent_requires_excess = true;
+        let summary = waker_heartbeat(&context, &client, &net).await;
+        assert_eq!(
+            summary.candidates, 0,
+            "should suppress candidates without excess"
+        );
+        assert!(net.woken.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_schedule_due() {
+        let schedule = [
+            NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+            NaiveTime::from_hms_opt(23, 58, 0).unwrap(),
+        ];
+        let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+        let window = Duration::minutes(5);
+        assert!(schedule_due(&schedule, at("2022-01-10T02:03:00Z"), window));
+        assert!(schedule_due(&schedule, at("2022-01-10T02:00:00Z"), window));
+        assert!(!schedule_due(&schedule, at("2022-01-10T02:05:00Z"), window));
+        assert!(!schedule_due(&schedule, at("2022-01-10T01:59:00Z"), window));
+        assert!(
+            schedule_due(&schedule, at("2022-01-10T00:01:00Z"), window),
+            "should be due after midnight for a time of yesterday"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_wake() {
+        let scheduled: MacAddress = "11:22:33:44:55:99".parse().unwrap();
+        let client = heartbeat_client(&[], 0.0, 0.0);
+        let net = NetworkGatewayMock::default();
+        let mut context = Context::load().unwrap();
+        context.scheduled_wake_macs = HashSet::from([scheduled]);
+        context.scheduled_wakes = vec![Utc::now().time()];
+
+        let summary = waker_heartbeat(&context, &client, &net).await;
+
+        assert_matches!(summary.excess, ExcessStatus::No);
+        assert_eq!(summary.woken, 0);
+        assert_eq!(summary.scheduled, 1);
+        assert_eq!(
+            *net.woken.lock().unwrap(),
+            vec![(scheduled, None)],
+            "should wake scheduled macs without excess"
+        );
+        assert!(context.woken_in_previous_heartbeat(&scheduled));
+    }
+}
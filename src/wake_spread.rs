@@ -0,0 +1,115 @@
+use mac_address::MacAddress;
+use std::collections::HashSet;
+
+#[derive(Debug, Default)]
+pub struct WakeSpread {
+    // macs planned for waking in the next heartbeats
+    queue: Vec<MacAddress>,
+    // macs woken per heartbeat of the current plan
+    slice: usize,
+}
+
+impl WakeSpread {
+    pub fn next_slice(&mut self, macs: &HashSet<MacAddress>, cycles: usize) -> HashSet<MacAddress> {
+        // drop planned macs which are no longer wake candidates
+        self.queue.retain(|m| macs.contains(m));
+        if self.queue.is_empty() {
+            // plan waking of all macs across the next cycles
+            self.queue = macs.iter().cloned().collect();
+            self.queue.sort_by_key(|m| m.bytes());
+            self.slice = (self.queue.len() + cycles.max(1) - 1) / cycles.max(1);
+        }
+        let n = self.slice.min(self.queue.len());
+        self.queue.drain(..n).collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CandidateRotation {
+    // last processed mac (continue after it)
+    last: Option<MacAddress>,
+}
+
+impl CandidateRotation {
+    pub fn next_batch(&mut self, macs: &HashSet<MacAddress>, max: usize) -> HashSet<MacAddress> {
+        // round-robin across heartbeats (ordered by mac)
+        let mut sorted: Vec<MacAddress> = macs.iter().cloned().collect();
+        sorted.sort_by_key(|m| m.bytes());
+        let start = self
+            .last
+            .map(|l| {
+                sorted
+                    .iter()
+                    .position(|m| m.bytes() > l.bytes())
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0);
+        sorted.rotate_left(start);
+        sorted.truncate(max);
+        self.last = sorted.last().cloned().or(self.last);
+        sorted.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wake_spread() {
+        let macs: HashSet<MacAddress> = (1..=10u8)
+            .map(|i| MacAddress::new([0x12, 0x34, 0x56, 0x78, 0x9a, i]))
+            .collect();
+        let mut spread = WakeSpread::default();
+        let slices: Vec<HashSet<MacAddress>> =
+            (0..3).map(|_| spread.next_slice(&macs, 3)).collect();
+        assert_eq!(
+            slices.iter().map(|s| s.len()).collect::<Vec<usize>>(),
+            vec![4, 4, 2],
+            "should spread the wake across 3 cycles"
+        );
+        assert_eq!(
+            slices
+                .iter()
+                .flatten()
+                .cloned()
+                .collect::<HashSet<MacAddress>>(),
+            macs,
+            "should wake every mac once"
+        );
+        assert_eq!(
+            spread.next_slice(&macs, 3).len(),
+            4,
+            "should plan again after the last slice"
+        );
+        assert_eq!(
+            WakeSpread::default().next_slice(&macs, 1),
+            macs,
+            "should wake all at once without spread"
+        );
+    }
+
+    #[test]
+    fn test_candidate_rotation() {
+        let mac = |i: u8| MacAddress::new([0x12, 0x34, 0x56, 0x78, 0x9a, i]);
+        let macs: HashSet<MacAddress> = (1..=5).map(mac).collect();
+        let mut rotation = CandidateRotation::default();
+        let batch = |r: &mut CandidateRotation| {
+            let mut b: Vec<u8> = r
+                .next_batch(&macs, 2)
+                .iter()
+                .map(|m| m.bytes()[5])
+                .collect();
+            b.sort();
+            b
+        };
+        assert_eq!(batch(&mut rotation), vec![1, 2]);
+        assert_eq!(
+            batch(&mut rotation),
+            vec![3, 4],
+            "should carry the remainder"
+        );
+        assert_eq!(batch(&mut rotation), vec![1, 5], "should wrap around");
+        assert_eq!(rotation.next_batch(&macs, 10), macs);
+    }
+}
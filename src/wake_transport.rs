@@ -0,0 +1,216 @@
+use crate::neighbor::{MacIpMapping, NetworkGateway};
+use anyhow::{anyhow, Result};
+use mac_address::MacAddress;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+#[async_trait]
+pub trait WakeTransport {
+    async fn wake(&self, macs: &HashSet<MacAddress>, mac_mapping: &MacIpMapping) -> Result<()>;
+}
+
+// Wake-On-LAN magic packets sent by the network gateway
+pub struct MagicPacketTransport<'a, N> {
+    net: &'a N,
+}
+
+#[async_trait]
+impl<'a, N: NetworkGateway + Sync> WakeTransport for MagicPacketTransport<'a, N> {
+    async fn wake(&self, macs: &HashSet<MacAddress>, mac_mapping: &MacIpMapping) -> Result<()> {
+        self.net.wake(macs, mac_mapping).await
+    }
+}
+
+// HTTP GET request (e.g. smart plug), '{mac}' in the url is replaced by the mac
+pub struct HttpGetTransport {
+    url: String,
+}
+
+#[async_trait]
+impl WakeTransport for HttpGetTransport {
+    async fn wake(&self, macs: &HashSet<MacAddress>, _mac_mapping: &MacIpMapping) -> Result<()> {
+        let client = hyper::Client::new();
+        for m in macs {
+            let uri: hyper::Uri = self.url.replace("{mac}", &m.to_string()).parse()?;
+            let resp = client.get(uri.clone()).await?;
+            if !resp.status().is_success() {
+                return Err(anyhow!("'{}' responded with {}", uri, resp.status()));
+            }
+            info!("Waking {} with {}", m, uri);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    MagicPacket,
+    HttpGet(String),
+}
+
+static DEFAULT_TRANSPORT: TransportKind = TransportKind::MagicPacket;
+
+impl FromStr for TransportKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wol" => Ok(TransportKind::MagicPacket),
+            url if url.starts_with("http://") => Ok(TransportKind::HttpGet(url.into())),
+            _ => Err(format!("Unknown wake transport '{}'!", s)),
+        }
+    }
+}
+
+pub fn parse_transport_map(s: &str) -> Result<HashMap<MacAddress, TransportKind>, String> {
+    // mac=transport pairs separated by ',' (transport: 'wol' or 'http://...')
+    s.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (mac, transport) = pair
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| format!("Missing '=' in wake transport '{}'!", pair))?;
+            Ok((
+                mac.parse()
+                    .map_err(|e| format!("Invalid wake transport mac '{}'! {}", mac, e))?,
+                transport.parse()?,
+            ))
+        })
+        .collect()
+}
+
+pub fn transport_for<'a, N: NetworkGateway + Sync>(
+    net: &'a N,
+) -> impl Fn(&TransportKind) -> Box<dyn WakeTransport + Send + Sync + 'a> {
+    move |kind: &TransportKind| -> Box<dyn WakeTransport + Send + Sync + 'a> {
+        match kind {
+            TransportKind::MagicPacket => Box::new(MagicPacketTransport { net }),
+            TransportKind::HttpGet(url) => Box::new(HttpGetTransport { url: url.clone() }),
+        }
+    }
+}
+
+pub async fn dispatch_wake<'a, F>(
+    macs: &HashSet<MacAddress>,
+    mac_mapping: &MacIpMapping,
+    transports: &HashMap<MacAddress, TransportKind>,
+    transport_for: F,
+) -> HashSet<MacAddress>
+where
+    F: Fn(&TransportKind) -> Box<dyn WakeTransport + Send + Sync + 'a>,
+{
+    // wake each group of macs with their configured transport
+    let mut groups: HashMap<&TransportKind, HashSet<MacAddress>> = HashMap::new();
+    for m in macs {
+        groups
+            .entry(transports.get(m).unwrap_or(&DEFAULT_TRANSPORT))
+            .or_default()
+            .insert(m.clone());
+    }
+    let mut woken = HashSet::new();
+    for (kind, group) in groups {
+        match transport_for(kind).wake(&group, mac_mapping).await {
+            Ok(_) => woken.extend(group),
+            Err(e) => error!("Waking with {:?} failed! {}", kind, e),
+        }
+    }
+    woken
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct WakeTransportMock {
+        kind: TransportKind,
+        woken: Arc<Mutex<Vec<(TransportKind, MacAddress)>>>,
+    }
+
+    #[async_trait]
+    impl WakeTransport for WakeTransportMock {
+        async fn wake(
+            &self,
+            macs: &HashSet<MacAddress>,
+            _mac_mapping: &MacIpMapping,
+        ) -> Result<()> {
+            if let TransportKind::HttpGet(url) = &self.kind {
+                if url.contains("broken") {
+                    return Err(anyhow!("mocking failed wake!"));
+                }
+            }
+            let mut woken = self.woken.lock().unwrap();
+            woken.extend(macs.iter().map(|m| (self.kind.clone(), m.clone())));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_transport_map() {
+        let map = parse_transport_map(
+            "11:22:33:44:55:66=wol, 11:22:33:44:55:77=http://plug.local/on?mac={mac}",
+        )
+        .unwrap();
+        assert_eq!(
+            map[&"11:22:33:44:55:66".parse().unwrap()],
+            TransportKind::MagicPacket
+        );
+        assert_eq!(
+            map[&"11:22:33:44:55:77".parse().unwrap()],
+            TransportKind::HttpGet("http://plug.local/on?mac={mac}".into())
+        );
+        assert!(parse_transport_map("").unwrap().is_empty());
+        assert_matches!(parse_transport_map("11:22:33:44:55:66=mqtt"), Err(_));
+        assert_matches!(parse_transport_map("11:22:33:44:55:66"), Err(_));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_wake() {
+        let mac = |s: &str| s.parse::<MacAddress>().unwrap();
+        let plug = TransportKind::HttpGet("http://plug.local/on".into());
+        let broken = TransportKind::HttpGet("http://broken.local/on".into());
+        let transports = HashMap::from([
+            (mac("11:22:33:44:55:77"), plug.clone()),
+            (mac("11:22:33:44:55:88"), broken.clone()),
+        ]);
+        let macs: HashSet<MacAddress> = [
+            "11:22:33:44:55:66",
+            "11:22:33:44:55:77",
+            "11:22:33:44:55:88",
+        ]
+        .into_iter()
+        .map(mac)
+        .collect();
+        let woken_log = Arc::new(Mutex::new(Vec::new()));
+        let woken = dispatch_wake(
+            &macs,
+            &MacIpMapping::new(),
+            &transports,
+            |kind: &TransportKind| -> Box<dyn WakeTransport + Send + Sync> {
+                Box::new(WakeTransportMock {
+                    kind: kind.clone(),
+                    woken: woken_log.clone(),
+                })
+            },
+        )
+        .await;
+
+        let mut woken_log = woken_log.lock().unwrap().clone();
+        woken_log.sort_by_key(|(_, m)| m.to_string());
+        assert_eq!(
+            woken_log,
+            vec![
+                (TransportKind::MagicPacket, mac("11:22:33:44:55:66")),
+                (plug, mac("11:22:33:44:55:77")),
+            ],
+            "should wake each mac with its configured transport"
+        );
+        assert_eq!(
+            woken,
+            [mac("11:22:33:44:55:66"), mac("11:22:33:44:55:77")]
+                .into_iter()
+                .collect::<HashSet<MacAddress>>(),
+            "should only report macs of successful transports as woken"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request};
+use mac_address::MacAddress;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Duration;
+
+// do not delay the heartbeat by a slow webhook
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+pub struct WakeEvent {
+    pub macs: Vec<MacAddress>,
+    // excess status name (lowercase with EXCESS_JSON_LOWERCASE)
+    pub excess: &'static str,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl WakeEvent {
+    pub fn new(macs: &HashSet<MacAddress>, excess: &'static str, timestamp: DateTime<Utc>) -> Self {
+        let mut macs: Vec<MacAddress> = macs.iter().cloned().collect();
+        macs.sort_by_key(|m| m.bytes());
+        WakeEvent {
+            macs,
+            excess,
+            timestamp,
+        }
+    }
+}
+
+pub async fn post_wake_event(url: &str, event: &WakeEvent, timeout: Duration) -> Result<()> {
+    let req = Request::post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(event)?))?;
+    let resp = tokio::time::timeout(timeout, hyper::Client::new().request(req))
+        .await
+        .map_err(|_| anyhow!("'{}' timed out after {:?}", url, timeout))??;
+    if !resp.status().is_success() {
+        bail!("'{}' responded with {}", url, resp.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::influx_gateway::ExcessStatus;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    // local http server which forwards the received bodies
+    pub fn webhook_server() -> (String, UnboundedReceiver<String>) {
+        let (tx, rx) = unbounded_channel();
+        let make_svc = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        tx.send(String::from_utf8_lossy(&body).to_string()).ok();
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let url = format!("http://{}/wake", server.local_addr());
+        tokio::spawn(server);
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_post_wake_event() {
+        let (url, mut rx) = webhook_server();
+        let macs: HashSet<MacAddress> = ["11:22:33:44:55:77", "11:22:33:44:55:66"]
+            .iter()
+            .map(|m| m.parse().unwrap())
+            .collect();
+        let timestamp = DateTime::parse_from_rfc3339("2023-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let event = WakeEvent::new(&macs, ExcessStatus::Yes.name(false), timestamp);
+        post_wake_event(&url, &event, WEBHOOK_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            r#"{"macs":["11:22:33:44:55:66","11:22:33:44:55:77"],"excess":"Yes","timestamp":"2023-06-01T12:00:00Z"}"#
+        );
+        assert_matches!(
+            post_wake_event("http://127.0.0.1:1/wake", &event, WEBHOOK_TIMEOUT).await,
+            Err(_),
+            "should fail if the webhook is unreachable"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use crate::context::Context;
+use crate::errors::ApiError;
+use crate::influx_gateway::WorkerLastStatus;
+use crate::server::{query_param, RequestHandler};
+use crate::{api_baderr, fwd_err};
+use async_trait::async_trait;
+
+pub struct WorkersRequestHandler {}
+
+#[async_trait]
+impl RequestHandler<String, Vec<WorkerLastStatus>> for WorkersRequestHandler {
+    async fn handle(
+        &self,
+        query_str: String,
+        context: Context,
+    ) -> Result<Vec<WorkerLastStatus>, ApiError> {
+        let mut workers = context
+            .status_store
+            .workers()
+            .await
+            .map_err(|e| fwd_err!("Failed to query workers! {}", e))?;
+        // '?history=true'
+        if query_param(&query_str, "history").as_deref() == Some("true") {
+            if !context.status_store.history_is_json() {
+                return Err(api_baderr!("Wake history requires an InfluxQL history!"));
+            }
+            let counts = context
+                .status_store
+                .wake_counts(context.wake_history_window)
+                .await
+                .map_err(|e| fwd_err!("Failed to query wake history! {}", e))?;
+            for w in workers.iter_mut() {
+                w.wake_history = Some(counts.get(&w.mac).copied().unwrap_or_default());
+            }
+        }
+        Ok(workers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::influx_gateway::{ExcessStatus, WakeCounts, WorkerStatus};
+    use crate::status_store::test::MemoryStore;
+    use crate::status_store::StatusStore;
+    use mac_address::MacAddress;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_wake_history() {
+        let mac: MacAddress = "11:22:33:44:55:66".parse().unwrap();
+        let store = MemoryStore::new(ExcessStatus::Yes);
+        for (status, wake) in [
+            (WorkerStatus::Working, false),
+            (WorkerStatus::Sleep, true),
+            (WorkerStatus::Sleep, true),
+        ] {
+            store.log_workerstatus(&mac, status, wake).await.unwrap();
+        }
+        let mut context = Context::load().unwrap();
+        context.status_store = Arc::new(store);
+
+        let workers = WorkersRequestHandler {}
+            .handle("".into(), context.clone())
+            .await
+            .unwrap();
+        assert_eq!(workers.len(), 1);
+        assert_eq!(workers[0].mac, mac.to_string());
+        assert_eq!(workers[0].status, WorkerStatus::Sleep as i32);
+        assert!(
+            !serde_json::to_value(&workers).unwrap()[0]
+                .as_object()
+                .unwrap()
+                .contains_key("wake_history"),
+            "should omit the wake history by default"
+        );
+
+        let workers = WorkersRequestHandler {}
+            .handle("history=true".into(), context)
+            .await
+            .unwrap();
+        assert_eq!(
+            workers[0].wake_history,
+            Some(WakeCounts {
+                wake_true: 2,
+                wake_false: 1
+            }),
+            "should count the reports by wake intent"
+        );
+    }
+}